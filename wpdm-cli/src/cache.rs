@@ -0,0 +1,146 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::fmt::Write as FmtWrite;
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use wpdm_common::config::{self, load_wp_paths};
+
+use crate::current::load_current;
+use crate::error::print_json;
+
+#[derive(clap::Args)]
+pub struct CacheArgs {
+    #[command(subcommand)]
+    command: CacheCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum CacheCommand {
+    /// Print how many entries the cache holds and their size, by monitor size
+    Stats,
+}
+
+pub fn get_cache_name(path: &str, width: i32, height: i32) -> anyhow::Result<String> {
+    let digest = Sha256::digest(path);
+    let mut digest_str = String::new();
+    write!(&mut digest_str, "{}x{}_{:x}", width, height, digest)?;
+    let _ = digest_str.split_off(20);
+    digest_str.push_str(".bgra");
+    Ok(digest_str)
+}
+
+pub fn cache_path(cache_name: &str) -> anyhow::Result<PathBuf> {
+    Ok(config::config_dir().context("Cannot get config dir")?.join(cache_name))
+}
+
+pub fn cache_exists(cache_name: &str) -> bool {
+    let Some(dir) = config::config_dir() else {
+        return false;
+    };
+    std::fs::exists(dir.join(cache_name)).unwrap_or(false)
+}
+
+/// Writes the buffer next to the cache entry first and renames it into place, so an
+/// interrupted write never leaves a truncated entry that looks complete.
+pub fn write_cache(cache_path: &Path, buffer: &[u8]) -> anyhow::Result<()> {
+    write_cache_with(cache_path, |writer| Ok(writer.write_all(buffer)?))
+}
+
+/// Like `write_cache`, for entries written in pieces, such as the frames of an animation
+pub fn write_cache_with(cache_path: &Path, write: impl FnOnce(&mut dyn Write) -> anyhow::Result<()>) -> anyhow::Result<()> {
+    if let Some(dir) = cache_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let part_path = cache_path.with_extension("part");
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&part_path)?;
+
+    let mut writer = BufWriter::new(file);
+    write(&mut writer)?;
+    writer.flush()?;
+    std::fs::rename(&part_path, cache_path)?;
+    Ok(())
+}
+
+#[derive(serde::Serialize, Default)]
+struct SizeStats {
+    size: String,
+    entries: usize,
+    bytes: u64,
+}
+
+#[derive(serde::Serialize)]
+struct CacheStats {
+    dir: PathBuf,
+    entries: usize,
+    bytes: u64,
+    /// Entries on screen, as far as the daemon and the CLI have recorded
+    in_use: usize,
+    sizes: Vec<SizeStats>,
+}
+
+fn cache_stats() -> anyhow::Result<CacheStats> {
+    let dir = config::config_dir().context("Cannot get config dir")?;
+    let in_use = load_wp_paths()?
+        .into_values()
+        .map(PathBuf::from)
+        .chain(load_current()?.into_values().map(|recorded| recorded.cache))
+        .collect::<BTreeSet<_>>();
+
+    let mut stats = CacheStats { dir: dir.clone(), entries: 0, bytes: 0, in_use: 0, sizes: vec![] };
+    let mut sizes = BTreeMap::<String, SizeStats>::new();
+    if std::fs::exists(&dir)? {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.ends_with(".bgra") {
+                continue;
+            }
+            let bytes = entry.metadata()?.len();
+            let size = name.split_once('_').map(|(size, _)| size).unwrap_or("unknown").to_string();
+            let size_stats = sizes.entry(size.clone()).or_insert_with(|| SizeStats { size, ..Default::default() });
+            size_stats.entries += 1;
+            size_stats.bytes += bytes;
+            stats.entries += 1;
+            stats.bytes += bytes;
+            if in_use.contains(&entry.path()) {
+                stats.in_use += 1;
+            }
+        }
+    }
+    stats.sizes = sizes.into_values().collect();
+    Ok(stats)
+}
+
+fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} B", bytes) } else { format!("{:.1} {}", value, units[unit]) }
+}
+
+pub fn run(args: CacheArgs, json: bool) -> anyhow::Result<()> {
+    match args.command {
+        CacheCommand::Stats => {
+            let stats = cache_stats()?;
+            if json {
+                return print_json(&stats);
+            }
+            println!("{}", stats.dir.display());
+            println!("{} entries, {}, {} in use", stats.entries, format_bytes(stats.bytes), stats.in_use);
+            for size in stats.sizes.iter() {
+                println!("{}\t{}\t{}", size.size, size.entries, format_bytes(size.bytes));
+            }
+        },
+    }
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use std::path::Path;
+use anyhow::Context;
+use image::{DynamicImage, RgbaImage};
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+use rayon::iter::{IndexedParallelIterator, ParallelIterator};
+use rayon::slice::{ParallelSlice, ParallelSliceMut};
+
+// Pixels handed to a single transform call when converting in parallel
+const CHUNK_PIXELS: usize = 16 * 1024;
+
+/// Converts an image with an embedded ICC profile to sRGB. Images without a profile are taken
+/// to be sRGB already and are left untouched.
+pub fn convert_to_srgb(img: DynamicImage, icc: Option<&[u8]>) -> anyhow::Result<DynamicImage> {
+    let Some(icc) = icc else {
+        return Ok(img);
+    };
+    let src = ColorProfile::new_from_slice(icc)
+        .map_err(|e| anyhow::anyhow!("{:?}", e))
+        .context("Failed to read embedded ICC profile")?;
+    let dst = ColorProfile::new_srgb();
+
+    let (width, height) = (img.width(), img.height());
+    let mut rgba = vec![0; (width * height * 4) as usize];
+    match src.color_space {
+        DataColorSpace::Rgb => {
+            let img = img.into_rgba8();
+            transform(&src, Layout::Rgba, &dst, img.as_raw(), &mut rgba)?;
+        },
+        DataColorSpace::Gray => {
+            let img = img.into_luma_alpha8();
+            transform(&src, Layout::GrayAlpha, &dst, img.as_raw(), &mut rgba)?;
+        },
+        // Decoders already hand out CMYK and YCbCr images as RGB, so their profile no longer
+        // describes the pixels
+        _ => return Ok(img),
+    }
+
+    let img = RgbaImage::from_raw(width, height, rgba)
+        .context("Converted buffer has the wrong size")?;
+    Ok(DynamicImage::ImageRgba8(img))
+}
+
+/// Converts an sRGB RGBA buffer in place to the colour space of the given ICC profile.
+pub fn convert_from_srgb(rgba: &mut [u8], profile_path: &Path) -> anyhow::Result<()> {
+    let icc = std::fs::read(profile_path)
+        .with_context(|| format!("Failed to read {}", profile_path.display()))?;
+    let dst = ColorProfile::new_from_slice(&icc)
+        .map_err(|e| anyhow::anyhow!("{:?}", e))
+        .with_context(|| format!("Failed to parse ICC profile {}", profile_path.display()))?;
+    let src = ColorProfile::new_srgb();
+
+    let input = rgba.to_vec();
+    transform(&src, Layout::Rgba, &dst, &input, rgba)
+}
+
+fn transform(src: &ColorProfile, src_layout: Layout, dst: &ColorProfile, input: &[u8], output: &mut [u8]) -> anyhow::Result<()> {
+    let executor = src
+        .create_transform_8bit(src_layout, dst, Layout::Rgba, TransformOptions::default())
+        .map_err(|e| anyhow::anyhow!("{:?}", e))
+        .context("Failed to create colour transform")?;
+    let src_channels = match src_layout {
+        Layout::GrayAlpha => 2,
+        _ => 4,
+    };
+
+    input
+        .par_chunks(CHUNK_PIXELS * src_channels)
+        .zip(output.par_chunks_mut(CHUNK_PIXELS * 4))
+        .try_for_each(|(src, dst)| executor.transform(src, dst))
+        .map_err(|e| anyhow::anyhow!("{:?}", e))
+        .context("Failed to convert colours")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use wpdm_common::settings::LimitSettings;
+
+    use crate::prepare::{decode_image, PrepareOptions};
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+    }
+
+    fn first_pixel(name: &str) -> [u8; 3] {
+        let img = decode_image(&fixture(name).into(), &PrepareOptions::default(), &LimitSettings::default(), None).unwrap().into_rgb8();
+        img.get_pixel(0, 0).0
+    }
+
+    fn assert_close(actual: [u8; 3], expected: [u8; 3]) {
+        let close = actual.iter().zip(expected).all(|(a, e)| a.abs_diff(e) <= 3);
+        assert!(close, "expected {:?}, got {:?}", expected, actual);
+    }
+
+    // Every fixture stores rgb(128, 64, 32) in its own colour space. The expected sRGB values
+    // come from the published primaries of each space.
+
+    #[test]
+    fn untagged_image_is_left_as_srgb() {
+        assert_eq!(first_pixel("untagged.png"), [128, 64, 32]);
+    }
+
+    #[test]
+    fn display_p3_is_converted_to_srgb() {
+        assert_close(first_pixel("display-p3.png"), [138, 60, 21]);
+    }
+
+    #[test]
+    fn adobe_rgb_is_converted_to_srgb() {
+        assert_close(first_pixel("adobe-rgb.png"), [146, 62, 23]);
+    }
+}
@@ -0,0 +1,174 @@
+//! Images behind the wallpapers on screen. The daemon only knows the cache entries it shows,
+//! so the images are recorded here whenever the CLI sets something.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use wpdm_common::config::{self, get_wp_path, load_wp_paths};
+use wpdm_common::slideshow::WpdmSlideshowStatus;
+use wpdm_common::time_of_day::WpdmTimeOfDayStatus;
+use wpdm_common::{WpdmClient, WpdmTransition};
+
+use crate::error::{print_json, CliError};
+use crate::set::{self, Applied};
+
+#[derive(clap::Args)]
+pub struct RestoreArgs {
+    /// Only restore this monitor. Can be repeated
+    #[arg(short, long)]
+    monitor: Vec<String>,
+
+    /// Prepare the recorded images again instead of using their cache entries, e.g. after a
+    /// monitor changed size
+    #[arg(long)]
+    prepare: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct Recorded {
+    pub image: String,
+    pub cache: PathBuf,
+}
+
+pub fn current_path() -> Option<PathBuf> {
+    Some(config::config_dir()?.join("current.toml"))
+}
+
+pub fn load_current() -> anyhow::Result<BTreeMap<String, Recorded>> {
+    let Some(path) = current_path() else {
+        return Ok(BTreeMap::new());
+    };
+    if !std::fs::exists(&path)? {
+        return Ok(BTreeMap::new());
+    }
+    let contents = std::fs::read_to_string(&path)?;
+    Ok(toml::from_str(&contents)?)
+}
+
+pub fn save_current(applied: &[Applied]) -> anyhow::Result<()> {
+    let Some(path) = current_path() else {
+        return Ok(());
+    };
+    let mut current = load_current()?;
+    for applied in applied {
+        for monitor in applied.monitors.iter() {
+            current.insert(monitor.clone(), Recorded { image: applied.image.clone(), cache: applied.cache.clone() });
+        }
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, toml::to_string(&current)?)?;
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+struct MonitorWallpaper {
+    monitor: String,
+    /// None when something other than the CLI set the wallpaper
+    image: Option<String>,
+    cache: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct Current {
+    monitors: Vec<MonitorWallpaper>,
+    slideshow: Option<WpdmSlideshowStatus>,
+    time_of_day: Option<WpdmTimeOfDayStatus>,
+}
+
+/// Prints the wallpaper of every monitor, and the slideshow or time-of-day set changing it
+pub fn run(json: bool) -> anyhow::Result<()> {
+    let mut client = WpdmClient::new()?;
+    let monitors = client.get_monitors()?;
+    let shown = load_wp_paths()?;
+    let recorded = load_current()?;
+
+    let monitors = monitors.into_iter()
+        .map(|mon| {
+            let cache = get_wp_path(&shown, &mon.name);
+            // The daemon's own record wins, the image only counts while it still matches
+            let image = recorded.get(&mon.name)
+                .filter(|recorded| cache.as_ref().is_none_or(|cache| recorded.cache == Path::new(cache)))
+                .map(|recorded| recorded.image.clone());
+            let cache = cache.or_else(|| Some(recorded.get(&mon.name)?.cache.display().to_string()));
+            MonitorWallpaper { monitor: mon.name, image, cache }
+        })
+        .collect();
+    let current = Current {
+        monitors,
+        slideshow: client.get_slideshow()?,
+        time_of_day: client.get_time_of_day()?,
+    };
+
+    if json {
+        return print_json(&current);
+    }
+    for mon in current.monitors.iter() {
+        let wallpaper = match (&mon.image, &mon.cache) {
+            (Some(image), _) => image.clone(),
+            (None, Some(cache)) => format!("cache entry {}", cache),
+            (None, None) => "none".to_string(),
+        };
+        println!("{}\t{}", mon.monitor, wallpaper);
+    }
+    if let Some(slideshow) = &current.slideshow {
+        println!("Slideshow of {}", slideshow.slideshow.dir);
+    }
+    if let Some(time_of_day) = &current.time_of_day {
+        println!("Time-of-day set {}", time_of_day.time_of_day.path);
+    }
+    Ok(())
+}
+
+/// Sets the recorded wallpapers again, e.g. after `clear` or after something outside the CLI
+/// changed them. Uses the cache entries as they are, so the images aren't prepared again
+/// unless `--prepare` is given.
+pub fn run_restore(args: RestoreArgs, json: bool) -> anyhow::Result<()> {
+    let mut client = WpdmClient::new()?;
+    let monitors = client.get_monitors()?;
+    if let Some(name) = args.monitor.iter().find(|name| !monitors.iter().any(|mon| mon.name == **name)) {
+        return Err(CliError::UnknownMonitor(name.clone()).into());
+    }
+
+    let recorded = load_current()?;
+    let selected = monitors.iter().filter(|mon| args.monitor.is_empty() || args.monitor.contains(&mon.name));
+    if args.prepare {
+        let mut images = vec![];
+        for (mon, recorded) in selected.filter_map(|mon| Some((mon, recorded.get(&mon.name)?))) {
+            if !Path::new(&recorded.image).is_file() {
+                anyhow::bail!("The wallpaper of {} wasn't made from an image file, set it again", mon.name);
+            }
+            images.push(format!("{}={}", mon.name, recorded.image));
+        }
+        if images.is_empty() {
+            return Err(CliError::NoMatch("No wallpaper has been recorded for these monitors".to_string()).into());
+        }
+        return set::run_images(images, json);
+    }
+
+    let mut by_cache = BTreeMap::<PathBuf, (String, Vec<String>)>::new();
+    for mon in selected {
+        let Some(recorded) = recorded.get(&mon.name) else {
+            continue;
+        };
+        if !recorded.cache.is_file() {
+            anyhow::bail!("The wallpaper of {} is no longer cached, set {} again", mon.name, recorded.image);
+        }
+        by_cache.entry(recorded.cache.clone())
+            .or_insert_with(|| (recorded.image.clone(), vec![]))
+            .1.push(mon.name.clone());
+    }
+    if by_cache.is_empty() {
+        return Err(CliError::NoMatch("No wallpaper has been recorded for these monitors".to_string()).into());
+    }
+
+    let mut wallpapers = vec![];
+    let mut applied = vec![];
+    for (cache, (image, monitors)) in by_cache {
+        let wallpaper = set::wallpaper(&cache, monitors, WpdmTransition::default())?;
+        applied.push(Applied::new(image, &wallpaper));
+        wallpapers.push(wallpaper);
+    }
+    client.set_wallpapers(wallpapers)?;
+    set::report(&applied, None, json)
+}
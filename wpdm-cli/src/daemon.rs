@@ -0,0 +1,32 @@
+use std::time::Instant;
+use wpdm_common::WpdmClient;
+
+use crate::error::print_json;
+
+#[derive(clap::Args)]
+pub struct DaemonArgs {
+    #[command(subcommand)]
+    command: DaemonCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum DaemonCommand {
+    /// Check that the daemon is running, fails with exit code 3 when it doesn't answer
+    Ping,
+}
+
+pub fn run(args: DaemonArgs, json: bool) -> anyhow::Result<()> {
+    match args.command {
+        DaemonCommand::Ping => {
+            let mut client = WpdmClient::new()?;
+            let start = Instant::now();
+            let version = client.ping()?;
+            let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+            if json {
+                return print_json(&serde_json::json!({ "version": version, "latency_ms": latency_ms }));
+            }
+            println!("wpdm {} answered in {:.1} ms", version, latency_ms);
+        },
+    }
+    Ok(())
+}
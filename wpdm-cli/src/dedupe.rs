@@ -0,0 +1,314 @@
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use anyhow::Context;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use wpdm_common::settings::{load_settings, LimitSettings};
+
+use crate::error::print_json;
+use crate::preload::list_images;
+use crate::prepare::{open_image, render_source, PrepareOptions};
+use crate::source::Source;
+
+#[derive(clap::ValueEnum, Clone, Copy, Default)]
+pub enum HashKind {
+    /// Brighter or darker than the average, fast but fooled by contrast changes
+    Ahash,
+    /// Gradient between neighbouring pixels
+    Dhash,
+    /// Low frequencies of the DCT, the most robust to re-encoding and colour changes
+    #[default]
+    Phash,
+}
+
+#[derive(clap::Args)]
+pub struct DedupeArgs {
+    /// Directory to look for duplicates in
+    dir: PathBuf,
+
+    /// Include images in subdirectories
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Perceptual hash to compare
+    #[arg(long, value_enum, default_value_t)]
+    hash: HashKind,
+
+    /// Most bits out of 64 two hashes can differ by and still count as duplicates
+    #[arg(short, long, default_value_t = 6, value_parser = clap::value_parser!(u32).range(0..=32))]
+    distance: u32,
+
+    /// Move every image of a group but the one kept into this directory. The largest
+    /// resolution is kept, then the largest file
+    #[arg(long, value_name = "DIR")]
+    move_to: Option<PathBuf>,
+}
+
+struct Hashed {
+    path: PathBuf,
+    hash: u64,
+    width: u32,
+    height: u32,
+    file_size: u64,
+}
+
+/// Grey levels of the image squashed to `width`x`height`, decoded like any wallpaper
+fn grey_thumbnail(path: &Path, width: u32, height: u32, limits: &LimitSettings) -> anyhow::Result<((u32, u32), Vec<f64>)> {
+    let prepare = PrepareOptions::default();
+    let img = open_image(&Source::Path(path.to_path_buf()), &prepare, limits)?;
+    let (img_width, img_height) = img.dimensions();
+    let crop = (0.0, 0.0, img_width as f64, img_height as f64);
+    let buffer = render_source(&img, width, height, crop, &prepare, None)?;
+    let grey = buffer.chunks_exact(4)
+        .map(|pixel| 0.114 * pixel[0] as f64 + 0.587 * pixel[1] as f64 + 0.299 * pixel[2] as f64)
+        .collect();
+    Ok(((img_width, img_height), grey))
+}
+
+fn bits(values: impl Iterator<Item = bool>) -> u64 {
+    values.take(64).fold(0, |hash, bit| (hash << 1) | bit as u64)
+}
+
+fn ahash(grey: &[f64]) -> u64 {
+    let mean = grey.iter().sum::<f64>() / grey.len() as f64;
+    bits(grey.iter().map(|value| *value > mean))
+}
+
+/// `grey` is 9x8, each bit compares a pixel with the one to its right
+fn dhash(grey: &[f64]) -> u64 {
+    bits(grey.chunks_exact(9).flat_map(|row| row.windows(2).map(|pair| pair[0] < pair[1])))
+}
+
+/// `grey` is 32x32. The 8x8 lowest DCT frequencies are compared with their median, leaving
+/// out the DC term, which only holds the average brightness.
+fn phash(grey: &[f64]) -> u64 {
+    const SIZE: usize = 32;
+    const LOW: usize = 8;
+    let cos = (0..LOW)
+        .map(|freq| (0..SIZE)
+            .map(|x| ((2 * x + 1) as f64 * freq as f64 * PI / (2 * SIZE) as f64).cos())
+            .collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    let mut coefficients = Vec::with_capacity(LOW * LOW);
+    for v in 0..LOW {
+        for u in 0..LOW {
+            let mut sum = 0.0;
+            for y in 0..SIZE {
+                let row = &grey[y * SIZE..(y + 1) * SIZE];
+                let row_sum = row.iter().zip(cos[u].iter()).map(|(value, cos)| value * cos).sum::<f64>();
+                sum += row_sum * cos[v][y];
+            }
+            coefficients.push(sum);
+        }
+    }
+
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    bits(coefficients.iter().map(|coefficient| *coefficient > median))
+}
+
+fn hash_image(path: &Path, kind: HashKind, limits: &LimitSettings) -> anyhow::Result<Hashed> {
+    let (width, height) = match kind {
+        HashKind::Ahash => (8, 8),
+        HashKind::Dhash => (9, 8),
+        HashKind::Phash => (32, 32),
+    };
+    let ((img_width, img_height), grey) = grey_thumbnail(path, width, height, limits)?;
+    let hash = match kind {
+        HashKind::Ahash => ahash(&grey),
+        HashKind::Dhash => dhash(&grey),
+        HashKind::Phash => phash(&grey),
+    };
+    Ok(Hashed {
+        path: path.to_path_buf(),
+        hash,
+        width: img_width,
+        height: img_height,
+        file_size: path.metadata()?.len(),
+    })
+}
+
+/// Groups of duplicates, each with the image to keep first. Every other image in a group is
+/// at most `distance` from the kept one, so a chain of small changes doesn't pull in images
+/// far from it. Images without any duplicate are left out.
+fn group(hashed: &[Hashed], distance: u32) -> Vec<Vec<usize>> {
+    let keep_order = |idx: &usize| {
+        let image = &hashed[*idx];
+        (std::cmp::Reverse(image.width as u64 * image.height as u64), std::cmp::Reverse(image.file_size), image.path.clone())
+    };
+    let mut order = (0..hashed.len()).collect::<Vec<_>>();
+    order.sort_by_cached_key(keep_order);
+
+    let mut grouped = vec![false; hashed.len()];
+    let mut groups = vec![];
+    for (pos, &keep) in order.iter().enumerate() {
+        if grouped[keep] {
+            continue;
+        }
+        let group = std::iter::once(keep)
+            .chain(order[pos + 1..].iter().copied()
+                .filter(|&idx| !grouped[idx] && (hashed[keep].hash ^ hashed[idx].hash).count_ones() <= distance))
+            .collect::<Vec<_>>();
+        if group.len() > 1 {
+            for &idx in &group {
+                grouped[idx] = true;
+            }
+            groups.push(group);
+        }
+    }
+    groups.sort_by(|a, b| hashed[a[0]].path.cmp(&hashed[b[0]].path));
+    groups
+}
+
+/// Moves the file into `dir`, numbering it when the name is taken
+fn move_aside(path: &Path, dir: &Path) -> anyhow::Result<PathBuf> {
+    let name = path.file_name().context("Image has no file name")?;
+    let mut target = dir.join(name);
+    let mut n = 1;
+    while target.exists() {
+        let stem = path.file_stem().unwrap_or(name).to_string_lossy();
+        let ext = path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+        target = dir.join(format!("{}-{}{}", stem, n, ext));
+        n += 1;
+    }
+    if std::fs::rename(path, &target).is_err() {
+        // Different filesystem
+        std::fs::copy(path, &target)
+            .with_context(|| format!("Cannot move {} to {}", path.display(), dir.display()))?;
+        std::fs::remove_file(path)?;
+    }
+    Ok(target)
+}
+
+pub fn run(args: DedupeArgs, json: bool) -> anyhow::Result<()> {
+    let settings = load_settings()?;
+    let mut images = list_images(&args.dir, args.recursive)?;
+    if let Some(dir) = &args.move_to {
+        std::fs::create_dir_all(dir)?;
+        // Don't hash what was moved by an earlier run
+        let dir = dir.canonicalize()?;
+        images.retain(|path| path.canonicalize().is_ok_and(|path| !path.starts_with(&dir)));
+    }
+
+    let total = images.len();
+    let done = AtomicUsize::new(0);
+    let hashed = images.par_iter()
+        .filter_map(|path| {
+            let result = hash_image(path, args.hash, &settings.limits);
+            let n = done.fetch_add(1, Ordering::Relaxed) + 1;
+            if let Err(err) = &result {
+                eprintln!("[{}/{}] failed {}: {}", n, total, path.display(), err);
+            }
+            result.ok()
+        })
+        .collect::<Vec<_>>();
+
+    let groups = group(&hashed, args.distance);
+    let duplicates = groups.iter().map(|group| group.len() - 1).sum::<usize>();
+    if json {
+        let groups = groups.iter()
+            .map(|members| {
+                let keep = &hashed[members[0]];
+                members.iter()
+                    .map(|member| {
+                        let image = &hashed[*member];
+                        serde_json::json!({
+                            "path": image.path,
+                            "width": image.width,
+                            "height": image.height,
+                            "file_size": image.file_size,
+                            "distance": (image.hash ^ keep.hash).count_ones(),
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        print_json(&serde_json::json!({
+            "groups": groups,
+            "duplicates": duplicates,
+            "moved": args.move_to.is_some(),
+        }))?;
+    } else {
+        for (idx, members) in groups.iter().enumerate() {
+            if idx > 0 {
+                println!();
+            }
+            let keep = &hashed[members[0]];
+            for (n, member) in members.iter().enumerate() {
+                let image = &hashed[*member];
+                let note = if n == 0 {
+                    "keep".to_string()
+                } else {
+                    format!("distance {}", (image.hash ^ keep.hash).count_ones())
+                };
+                println!("{}\t{}x{}\t{}\t{}", image.path.display(), image.width, image.height, image.file_size, note);
+            }
+        }
+    }
+
+    if let Some(dir) = &args.move_to {
+        for member in groups.iter().flat_map(|group| &group[1..]) {
+            move_aside(&hashed[*member].path, dir)?;
+        }
+        if !json {
+            eprintln!("Moved {} duplicates in {} groups to {}", duplicates, groups.len(), dir.display());
+        }
+    } else if !json {
+        eprintln!("Found {} duplicates in {} groups", duplicates, groups.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `width`x`height` grey levels from `f(x, y)`
+    fn grey(width: usize, height: usize, f: impl Fn(usize, usize) -> f64) -> Vec<f64> {
+        (0..height).flat_map(|y| (0..width).map(move |x| (x, y))).map(|(x, y)| f(x, y)).collect()
+    }
+
+    fn hashed(name: &str, hash: u64, width: u32) -> Hashed {
+        Hashed { path: PathBuf::from(name), hash, width, height: width, file_size: 1 }
+    }
+
+    #[test]
+    fn ahash_marks_pixels_brighter_than_the_mean() {
+        assert_eq!(ahash(&grey(8, 8, |x, _| if x < 4 { 10.0 } else { 200.0 })), 0x0f0f_0f0f_0f0f_0f0f);
+    }
+
+    #[test]
+    fn dhash_marks_pixels_darker_than_their_right_neighbour() {
+        assert_eq!(dhash(&grey(9, 8, |x, _| x as f64)), u64::MAX);
+        assert_eq!(dhash(&grey(9, 8, |x, _| -(x as f64))), 0);
+    }
+
+    #[test]
+    fn phash_ignores_brightness_and_contrast() {
+        let image = grey(32, 32, |x, y| ((x * 7 + y * 3) % 32) as f64 * 4.0);
+        let brighter = image.iter().map(|value| value * 1.5 + 40.0).collect::<Vec<_>>();
+        let mirrored = grey(32, 32, |x, y| (((31 - x) * 7 + y * 3) % 32) as f64 * 4.0);
+        assert_eq!(phash(&image), phash(&brighter));
+        assert!((phash(&image) ^ phash(&mirrored)).count_ones() > 6);
+    }
+
+    #[test]
+    fn groups_keep_the_largest_image_first() {
+        let images = [hashed("small", 0b1011, 100), hashed("large", 0b1001, 200), hashed("other", !0b1011, 300)];
+        assert_eq!(group(&images, 1), [vec![1, 0]]);
+        assert!(group(&images, 0).is_empty());
+    }
+
+    #[test]
+    fn groups_only_take_images_near_the_kept_one() {
+        // b is near both, but a and c are too far apart to be duplicates
+        let images = [hashed("a", 0b0000, 300), hashed("b", 0b0001, 200), hashed("c", 0b0011, 100)];
+        assert_eq!(group(&images, 1), [vec![0, 1]]);
+
+        // c is kept before b but can't take it, b is already a duplicate of a
+        let images = [hashed("a", 0b0000, 300), hashed("b", 0b0001, 100), hashed("c", 0b0011, 200)];
+        assert_eq!(group(&images, 1), [vec![0, 1]]);
+    }
+}
@@ -0,0 +1,221 @@
+use clap::{Arg, ArgAction, ArgMatches, Args, Command, FromArgMatches};
+use image::RgbaImage;
+use rayon::iter::ParallelIterator;
+use rayon::slice::ParallelSliceMut;
+
+use crate::generate::{parse_color, Color};
+
+#[derive(Clone, Debug)]
+pub enum Effect {
+    Blur(f32),
+    Dim(f32),
+    Brightness(f32),
+    Contrast(f32),
+    Saturation(f32),
+    Grayscale,
+    Invert,
+    Tint(Color),
+}
+
+/// Adjustments made after resizing, kept in the order they were given on the command line.
+/// Clap's derive loses the order between different arguments, so the arguments are declared
+/// by hand and sorted by their position.
+#[derive(Clone, Default)]
+pub struct Effects(pub Vec<Effect>);
+
+const IDS: [&str; 8] = ["blur", "dim", "brightness", "contrast", "saturation", "grayscale", "invert", "tint"];
+
+fn parse_positive(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(value) if value > 0.0 && value.is_finite() => Ok(value),
+        _ => Err(format!("Expected a positive number, got {}", s)),
+    }
+}
+
+fn parse_factor(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(value) if value >= 0.0 && value.is_finite() => Ok(value),
+        _ => Err(format!("Expected a factor of 0 or more, got {}", s)),
+    }
+}
+
+fn parse_fraction(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(value) if (0.0..=1.0).contains(&value) => Ok(value),
+        _ => Err(format!("Expected a number between 0 and 1, got {}", s)),
+    }
+}
+
+impl Args for Effects {
+    fn augment_args(cmd: Command) -> Command {
+        let valued = |id: &'static str, value_name: &'static str, help: &'static str| Arg::new(id)
+            .long(id)
+            .value_name(value_name)
+            .help(help)
+            .action(ArgAction::Append);
+        let flag = |id: &'static str, help: &'static str, effect: Effect| Arg::new(id)
+            .long(id)
+            .help(help)
+            .action(ArgAction::Append)
+            .num_args(0)
+            .default_missing_value("")
+            .value_parser(move |_: &str| Ok::<_, String>(effect.clone()));
+
+        cmd.next_help_heading("Effects, applied after resizing in the order given")
+            .arg(valued("blur", "RADIUS", "Gaussian blur, radius in monitor pixels")
+                .value_parser(|s: &str| parse_positive(s).map(Effect::Blur)))
+            .arg(valued("dim", "AMOUNT", "Darken towards black, from 0 to 1")
+                .value_parser(|s: &str| parse_fraction(s).map(Effect::Dim)))
+            .arg(valued("brightness", "FACTOR", "Multiply brightness, 1 keeps the image unchanged")
+                .value_parser(|s: &str| parse_factor(s).map(Effect::Brightness)))
+            .arg(valued("contrast", "FACTOR", "Scale contrast around mid grey, 1 keeps the image unchanged")
+                .value_parser(|s: &str| parse_factor(s).map(Effect::Contrast)))
+            .arg(valued("saturation", "FACTOR", "Scale saturation, 0 is grey and 1 keeps the image unchanged")
+                .value_parser(|s: &str| parse_factor(s).map(Effect::Saturation)))
+            .arg(flag("grayscale", "Remove all colour", Effect::Grayscale))
+            .arg(flag("invert", "Invert the colours", Effect::Invert))
+            .arg(valued("tint", "COLOR", "Multiply by a colour, its alpha sets the strength")
+                .value_parser(|s: &str| parse_color(s).map(Effect::Tint)))
+    }
+
+    fn augment_args_for_update(cmd: Command) -> Command {
+        Self::augment_args(cmd)
+    }
+}
+
+impl FromArgMatches for Effects {
+    fn from_arg_matches(matches: &ArgMatches) -> Result<Self, clap::Error> {
+        let mut effects = vec![];
+        for id in IDS {
+            let (Some(values), Some(indices)) = (matches.get_many::<Effect>(id), matches.indices_of(id)) else {
+                continue;
+            };
+            effects.extend(indices.zip(values.cloned()));
+        }
+        effects.sort_by_key(|(index, _)| *index);
+        Ok(Effects(effects.into_iter().map(|(_, effect)| effect).collect()))
+    }
+
+    fn update_from_arg_matches(&mut self, matches: &ArgMatches) -> Result<(), clap::Error> {
+        *self = Self::from_arg_matches(matches)?;
+        Ok(())
+    }
+}
+
+impl Effects {
+    /// Empty when there are no effects, so plain images keep their cache key
+    pub fn cache_key(&self) -> String {
+        if self.0.is_empty() {
+            return String::new();
+        }
+        let effects = self.0.iter()
+            .map(Effect::cache_key)
+            .collect::<Vec<_>>();
+        format!("#effects={}", effects.join(","))
+    }
+
+    pub fn apply(&self, img: RgbaImage) -> RgbaImage {
+        self.0.iter().fold(img, |img, effect| effect.apply(img))
+    }
+}
+
+impl Effect {
+    fn cache_key(&self) -> String {
+        match self {
+            Effect::Blur(radius) => format!("blur:{}", radius),
+            Effect::Dim(amount) => format!("dim:{}", amount),
+            Effect::Brightness(factor) => format!("brightness:{}", factor),
+            Effect::Contrast(factor) => format!("contrast:{}", factor),
+            Effect::Saturation(factor) => format!("saturation:{}", factor),
+            Effect::Grayscale => "grayscale".to_string(),
+            Effect::Invert => "invert".to_string(),
+            Effect::Tint([r, g, b, a]) => format!("tint:#{:02x}{:02x}{:02x}{:02x}", r, g, b, a),
+        }
+    }
+
+    fn apply(&self, mut img: RgbaImage) -> RgbaImage {
+        if let Effect::Blur(radius) = self {
+            return image::imageops::fast_blur(&img, *radius);
+        }
+        img.par_chunks_exact_mut(4)
+            .for_each(|pixel| {
+                let rgb = [pixel[0], pixel[1], pixel[2]].map(|c| c as f32 / 255.0);
+                let rgb = self.apply_rgb(rgb);
+                for (channel, value) in pixel.iter_mut().zip(rgb) {
+                    *channel = (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+                }
+            });
+        img
+    }
+
+    fn apply_rgb(&self, rgb: [f32; 3]) -> [f32; 3] {
+        let luma = rgb[0] * 0.2126 + rgb[1] * 0.7152 + rgb[2] * 0.0722;
+        match *self {
+            Effect::Blur(_) => rgb,
+            Effect::Dim(amount) => rgb.map(|c| c * (1.0 - amount)),
+            Effect::Brightness(factor) => rgb.map(|c| c * factor),
+            Effect::Contrast(factor) => rgb.map(|c| (c - 0.5) * factor + 0.5),
+            Effect::Saturation(factor) => rgb.map(|c| luma + (c - luma) * factor),
+            Effect::Grayscale => [luma; 3],
+            Effect::Invert => rgb.map(|c| 1.0 - c),
+            Effect::Tint([r, g, b, a]) => {
+                let strength = a as f32 / 255.0;
+                let tint = [r, g, b].map(|c| c as f32 / 255.0);
+                [0, 1, 2].map(|i| rgb[i] + (rgb[i] * tint[i] - rgb[i]) * strength)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        effects: Effects,
+    }
+
+    fn parse(args: &[&str]) -> Result<String, clap::Error> {
+        let cli = Cli::try_parse_from(std::iter::once("wpdm-cli").chain(args.iter().copied()))?;
+        Ok(cli.effects.cache_key())
+    }
+
+    #[test]
+    fn effects_keep_the_command_line_order() {
+        assert_eq!(parse(&["--invert", "--blur", "2"]).unwrap(), "#effects=invert,blur:2");
+        assert_eq!(parse(&["--blur", "2", "--invert"]).unwrap(), "#effects=blur:2,invert");
+        assert_eq!(parse(&[]).unwrap(), "");
+    }
+
+    #[test]
+    fn repeated_effects_apply_each_time() {
+        assert_eq!(
+            parse(&["--dim", "0.5", "--grayscale", "--dim", "0.25", "--tint", "#ff8000"]).unwrap(),
+            "#effects=dim:0.5,grayscale,dim:0.25,tint:#ff8000ff"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        for args in [
+            ["--blur", "0"], ["--blur", "inf"], ["--dim", "1.5"], ["--dim", "-0.1"],
+            ["--brightness", "-1"], ["--contrast", "NaN"], ["--saturation", "x"], ["--tint", "#ggg"],
+        ] {
+            assert!(parse(&args).is_err(), "{:?} parsed", args);
+        }
+        assert!(parse(&["--invert=1"]).is_err());
+    }
+
+    #[test]
+    fn effects_change_pixels_in_order() {
+        let img = RgbaImage::from_pixel(1, 1, image::Rgba([200, 100, 0, 255]));
+        let inverted_then_dimmed = Effects(vec![Effect::Invert, Effect::Dim(0.5)]).apply(img.clone());
+        let dimmed_then_inverted = Effects(vec![Effect::Dim(0.5), Effect::Invert]).apply(img);
+        assert_eq!(inverted_then_dimmed.get_pixel(0, 0).0, [28, 78, 128, 255]);
+        assert_eq!(dimmed_then_inverted.get_pixel(0, 0).0, [155, 205, 255, 255]);
+    }
+}
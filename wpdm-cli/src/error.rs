@@ -0,0 +1,117 @@
+use std::process::ExitCode;
+use wpdm_common::DaemonUnreachable;
+
+/// Shown under `--help`. Scripts rely on these, so codes are only ever added, never changed.
+pub const EXIT_CODES: &str = "\
+Exit codes:
+  0  Success
+  1  Any other error
+  2  Invalid arguments
+  3  The daemon did not respond, it is most likely not running
+  4  Unknown monitor
+  5  A file or directory could not be opened
+  6  An image could not be decoded
+  7  No image matched, e.g. an empty directory or library query";
+
+/// Failures scripts need to tell apart. Attached as context, so they can be found however
+/// deep in the chain they end up.
+#[derive(thiserror::Error, Debug)]
+pub enum CliError {
+    #[error("Unknown monitor {0}")]
+    UnknownMonitor(String),
+    #[error("Failed to decode {0}")]
+    Decode(String),
+    #[error("{0}")]
+    NoMatch(String),
+    /// Arguments clap can't check itself, like the set options after --
+    #[error("{0}")]
+    Usage(String),
+}
+
+#[derive(Clone, Copy)]
+enum ErrorKind {
+    Other = 1,
+    Usage = 2,
+    DaemonUnreachable = 3,
+    UnknownMonitor = 4,
+    CannotOpen = 5,
+    Decode = 6,
+    NoMatch = 7,
+}
+
+impl ErrorKind {
+    fn of(err: &anyhow::Error) -> Self {
+        let cli_error = err.downcast_ref::<CliError>();
+        if err.downcast_ref::<clap::Error>().is_some() || matches!(cli_error, Some(CliError::Usage(_))) {
+            return ErrorKind::Usage;
+        }
+        if err.downcast_ref::<DaemonUnreachable>().is_some() {
+            return ErrorKind::DaemonUnreachable;
+        }
+        if let Some(CliError::UnknownMonitor(_)) = cli_error {
+            return ErrorKind::UnknownMonitor;
+        }
+        if let Some(CliError::NoMatch(_)) = cli_error {
+            return ErrorKind::NoMatch;
+        }
+        // Checked before decoding, an image that went missing is reported as missing
+        let cannot_open = err.chain()
+            .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+            .any(|io| matches!(io.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied));
+        if cannot_open {
+            return ErrorKind::CannotOpen;
+        }
+        if let Some(CliError::Decode(_)) = cli_error {
+            return ErrorKind::Decode;
+        }
+        ErrorKind::Other
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ErrorKind::Other => "other",
+            ErrorKind::Usage => "usage",
+            ErrorKind::DaemonUnreachable => "daemon_unreachable",
+            ErrorKind::UnknownMonitor => "unknown_monitor",
+            ErrorKind::CannotOpen => "cannot_open",
+            ErrorKind::Decode => "decode",
+            ErrorKind::NoMatch => "no_match",
+        }
+    }
+}
+
+/// Prints the error on stderr, as JSON with `--json`, and returns its exit code
+pub fn report(err: &anyhow::Error, json: bool) -> ExitCode {
+    let kind = ErrorKind::of(err);
+    if json {
+        let message = match err.downcast_ref::<clap::Error>() {
+            // Just the problem, without the usage and the hint to try --help
+            Some(err) => err.to_string()
+                .lines()
+                .take_while(|line| !line.is_empty())
+                .map(str::trim)
+                .collect::<Vec<_>>()
+                .join(" ")
+                .trim_start_matches("error: ")
+                .to_string(),
+            None => format!("{:#}", err),
+        };
+        let error = serde_json::json!({
+            "error": {
+                "kind": kind.name(),
+                "code": kind as u8,
+                "message": message,
+            }
+        });
+        eprintln!("{}", error);
+    } else {
+        eprintln!("Error: {:#}", err);
+    }
+    ExitCode::from(kind as u8)
+}
+
+/// Prints one line of JSON on stdout
+pub fn print_json(value: &impl serde::Serialize) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string(value)?);
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use std::path::{Path, PathBuf};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use wpdm_common::settings::LimitSettings;
+use wpdm_common::WpdmMonitor;
+
+use crate::prepare::{image_dimensions, PrepareOptions};
+use crate::preload::list_images;
+use crate::source::Source;
+
+/// Cropping away part of the image is worse than upscaling it by the same factor
+const ASPECT_WEIGHT: f64 = 2.0;
+/// Extra resolution only costs decoding time
+const DOWNSCALE_WEIGHT: f64 = 0.1;
+
+pub struct Candidate {
+    pub path: PathBuf,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Images in `dir` with their size after `prepare` turns them, leaving out any smaller than
+/// `min_resolution` either way round. Files that fail to open are skipped with a warning.
+pub fn candidates(dir: &Path, min_resolution: Option<(i32, i32)>, prepare: &PrepareOptions, limits: &LimitSettings) -> anyhow::Result<Vec<Candidate>> {
+    let (min_short, min_long) = min_resolution
+        .map(|(width, height)| (width.min(height) as u32, width.max(height) as u32))
+        .unwrap_or((0, 0));
+    let mut candidates = list_images(dir, false)?
+        .into_par_iter()
+        .filter_map(|path| {
+            let path = path.canonicalize().ok()?;
+            match image_dimensions(&Source::Path(path.clone()), prepare, limits) {
+                Ok((width, height)) => Some(Candidate { path, width, height }),
+                Err(err) => {
+                    eprintln!("skipped {}: {}", path.display(), err);
+                    None
+                },
+            }
+        })
+        .filter(|candidate| {
+            candidate.width.min(candidate.height) >= min_short && candidate.width.max(candidate.height) >= min_long
+        })
+        .collect::<Vec<_>>();
+    // Equal scores go to the first by name
+    candidates.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(candidates)
+}
+
+/// Lower is better. A mismatched aspect ratio costs the most since the image gets cropped to
+/// cover the monitor, then upscaling, then a little for every doubling beyond the monitor.
+fn score(candidate: &Candidate, monitor: &WpdmMonitor) -> f64 {
+    let (width, height) = (candidate.width.max(1) as f64, candidate.height.max(1) as f64);
+    let (mon_width, mon_height) = (monitor.width.max(1) as f64, monitor.height.max(1) as f64);
+    let aspect = ((width / height) / (mon_width / mon_height)).ln().abs();
+    let scale = (mon_width / width).max(mon_height / height);
+    let resolution = if scale > 1.0 { scale.ln() } else { DOWNSCALE_WEIGHT * (1.0 / scale).ln() };
+    ASPECT_WEIGHT * aspect + resolution
+}
+
+/// Candidate that suits the monitor best, by aspect ratio then resolution
+pub fn best_fit<'a>(candidates: &'a [Candidate], monitor: &WpdmMonitor) -> Option<&'a Candidate> {
+    candidates.iter()
+        .min_by(|a, b| score(a, monitor).total_cmp(&score(b, monitor)))
+}
@@ -0,0 +1,231 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use anyhow::Context;
+use image::{DynamicImage, ImageBuffer, ImageFormat, RgbaImage};
+use resvg::{tiny_skia, usvg};
+use wpdm_common::settings::LimitSettings;
+use zenavif::{DecoderConfig, ManagedAvifDecoder, Unstoppable};
+use zenpixels::{ChannelLayout, ChannelType};
+
+use crate::limits::check_limits;
+
+/// How an image file gets decoded
+pub enum Format {
+    /// Anything the image crate reads itself
+    Image(ImageFormat),
+    Avif,
+    JpegXl,
+    Svg,
+}
+
+const JXL_CODESTREAM: &[u8] = &[0xff, 0x0a];
+const JXL_CONTAINER: &[u8] = &[0, 0, 0, 0x0c, b'J', b'X', b'L', b' ', 0x0d, 0x0a, 0x87, 0x0a];
+
+/// Comma separated list of the formats `detect_format` accepts, for error messages
+pub fn supported_formats() -> String {
+    let mut names = ImageFormat::all()
+        .filter(|format| format.reading_enabled() && *format != ImageFormat::Avif)
+        .filter_map(|format| format.extensions_str().first())
+        .map(|ext| ext.to_uppercase())
+        .collect::<Vec<_>>();
+    names.extend(["AVIF", "JXL", "SVG"].map(String::from));
+    names.join(", ")
+}
+
+/// Extensions of every format we can decode
+pub fn supported_extensions() -> Vec<String> {
+    let mut extensions = ImageFormat::all()
+        .filter(|format| format.reading_enabled())
+        .flat_map(|format| format.extensions_str())
+        .map(|ext| ext.to_string())
+        .collect::<Vec<_>>();
+    extensions.extend(["avif", "jxl", "svg", "svgz"].map(String::from));
+    extensions.sort();
+    extensions.dedup();
+    extensions
+}
+
+/// Whether the file name looks like an image we can decode, without opening it
+pub fn is_supported_path(path: &Path) -> bool {
+    let ext = path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+    match ext.as_deref() {
+        Some("avif" | "jxl" | "svg" | "svgz") => true,
+        _ => ImageFormat::from_path(path).is_ok_and(|format| format.reading_enabled()),
+    }
+}
+
+/// Figures out the format from the file contents, falling back to the extension of `path`
+pub fn detect_format(data: &[u8], path: &Path) -> anyhow::Result<Format> {
+    let head = &data[..data.len().min(512)];
+
+    if head.starts_with(JXL_CODESTREAM) || head.starts_with(JXL_CONTAINER) {
+        return Ok(Format::JpegXl);
+    }
+    if head.get(4..8) == Some(b"ftyp") && matches!(head.get(8..12), Some(b"heic" | b"heix" | b"heim" | b"heis" | b"mif1")) {
+        anyhow::bail!(
+            "{} is a HEIC image, which is not supported. Convert it to AVIF or JPEG first. Supported formats: {}",
+            path.display(), supported_formats()
+        );
+    }
+    // Magic bytes win over the SVG sniff, a PNG or JPEG can carry "<svg" in its metadata
+    let mut format = image::guess_format(head).ok();
+    if format.is_none() {
+        let is_svg_ext = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("svg") || ext.eq_ignore_ascii_case("svgz"));
+        if is_svg_ext || String::from_utf8_lossy(head).contains("<svg") {
+            return Ok(Format::Svg);
+        }
+        format = ImageFormat::from_path(path).ok();
+    }
+    match format {
+        Some(ImageFormat::Avif) => Ok(Format::Avif),
+        Some(format) if format.reading_enabled() => Ok(Format::Image(format)),
+        _ => anyhow::bail!(
+            "Unsupported image format for {}. Supported formats: {}",
+            path.display(), supported_formats()
+        ),
+    }
+}
+
+fn avif_decoder(data: &[u8], limits: &LimitSettings) -> anyhow::Result<ManagedAvifDecoder> {
+    let max_pixels = limits.max_width as u64 * limits.max_height as u64;
+    let config = DecoderConfig::new().frame_size_limit(max_pixels.min(u32::MAX as u64) as u32);
+    ManagedAvifDecoder::new(data, &config).map_err(|err| anyhow::anyhow!("{}", err))
+}
+
+pub fn avif_dimensions(data: &[u8], limits: &LimitSettings) -> anyhow::Result<(u32, u32)> {
+    let info = avif_decoder(data, limits)?.probe_info().map_err(|err| anyhow::anyhow!("{}", err))?;
+    Ok((info.width, info.height))
+}
+
+/// Decodes an AVIF image, returning it with its embedded ICC profile
+pub fn decode_avif(data: &[u8], path: &Path, limits: &LimitSettings) -> anyhow::Result<(DynamicImage, Option<Vec<u8>>)> {
+    let mut decoder = avif_decoder(data, limits)?;
+    let info = decoder.probe_info().map_err(|err| anyhow::anyhow!("{}", err))?;
+    let bytes_per_pixel = if info.bit_depth > 8 { 8 } else { 4 };
+    check_limits(path, info.width, info.height, info.width as u64 * info.height as u64 * bytes_per_pixel, limits)?;
+
+    let buffer = decoder.decode(&Unstoppable).map_err(|err| anyhow::anyhow!("{}", err))?;
+    let icc = buffer.color_context()
+        .and_then(|color| color.icc.as_deref())
+        .map(<[u8]>::to_vec);
+
+    let (width, height) = (buffer.width(), buffer.height());
+    let descriptor = buffer.descriptor();
+    let bytes = buffer.copy_to_contiguous_bytes();
+    let to_u16 = |bytes: Vec<u8>| bytes.chunks_exact(2)
+        .map(|b| u16::from_ne_bytes([b[0], b[1]]))
+        .collect::<Vec<_>>();
+
+    let img = match (descriptor.channel_type(), descriptor.layout()) {
+        (ChannelType::U8, ChannelLayout::Gray) => ImageBuffer::from_raw(width, height, bytes).map(DynamicImage::ImageLuma8),
+        (ChannelType::U8, ChannelLayout::GrayAlpha) => ImageBuffer::from_raw(width, height, bytes).map(DynamicImage::ImageLumaA8),
+        (ChannelType::U8, ChannelLayout::Rgb) => ImageBuffer::from_raw(width, height, bytes).map(DynamicImage::ImageRgb8),
+        (ChannelType::U8, ChannelLayout::Rgba) => ImageBuffer::from_raw(width, height, bytes).map(DynamicImage::ImageRgba8),
+        (ChannelType::U16, ChannelLayout::Gray) => ImageBuffer::from_raw(width, height, to_u16(bytes)).map(DynamicImage::ImageLuma16),
+        (ChannelType::U16, ChannelLayout::GrayAlpha) => ImageBuffer::from_raw(width, height, to_u16(bytes)).map(DynamicImage::ImageLumaA16),
+        (ChannelType::U16, ChannelLayout::Rgb) => ImageBuffer::from_raw(width, height, to_u16(bytes)).map(DynamicImage::ImageRgb16),
+        (ChannelType::U16, ChannelLayout::Rgba) => ImageBuffer::from_raw(width, height, to_u16(bytes)).map(DynamicImage::ImageRgba16),
+        (channel_type, layout) => anyhow::bail!("Unsupported AVIF pixel format {:?} {:?}", channel_type, layout),
+    };
+    let img = img.context("AVIF decoder returned a truncated buffer")?;
+    Ok((img, icc))
+}
+
+fn scaled_jpeg_decoder<R: Read>(reader: R, reduction: u32) -> anyhow::Result<(jpeg_decoder::Decoder<R>, (u16, u16))> {
+    let mut decoder = jpeg_decoder::Decoder::new(reader);
+    decoder.read_info()?;
+    let info = decoder.info().context("JPEG has no header")?;
+    let reduction = reduction as u16;
+    let size = decoder.scale(info.width.div_ceil(reduction), info.height.div_ceil(reduction))?;
+    Ok((decoder, size))
+}
+
+/// Dimensions `decode_scaled_jpeg` will return
+pub fn scaled_jpeg_dimensions(reader: impl Read, reduction: u32) -> anyhow::Result<(u32, u32)> {
+    let (_, (width, height)) = scaled_jpeg_decoder(reader, reduction)?;
+    Ok((width as u32, height as u32))
+}
+
+/// Decodes a JPEG at 1/`reduction` of its size. The decoder skips the fine detail, so the full
+/// size image never has to fit in memory.
+pub fn decode_scaled_jpeg(reader: impl Read, reduction: u32) -> anyhow::Result<DynamicImage> {
+    let (mut decoder, (width, height)) = scaled_jpeg_decoder(reader, reduction)?;
+    let pixels = decoder.decode()?;
+    let (width, height) = (width as u32, height as u32);
+    let img = match decoder.info().map(|info| info.pixel_format) {
+        Some(jpeg_decoder::PixelFormat::L8) => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+        Some(jpeg_decoder::PixelFormat::RGB24) => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8),
+        format => anyhow::bail!("Cannot decode {:?} JPEGs at a reduced size", format),
+    };
+    img.context("JPEG decoder returned a truncated buffer")
+}
+
+/// Parsed SVG, kept as vectors so it can be rendered at whatever size the monitor needs
+pub struct Svg {
+    tree: Box<usvg::Tree>,
+    path: PathBuf,
+    /// Checked against every render, the size depends on the monitor and crop
+    limits: LimitSettings,
+}
+
+impl Svg {
+    /// Relative references in the document are resolved next to `path`
+    pub fn parse(data: &[u8], path: &Path, limits: &LimitSettings) -> anyhow::Result<Self> {
+        let mut options = usvg::Options {
+            resources_dir: path.parent().map(Path::to_path_buf),
+            ..Default::default()
+        };
+        options.fontdb_mut().load_system_fonts();
+        let tree = usvg::Tree::from_data(data, &options)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        Ok(Svg { tree: Box::new(tree), path: path.to_path_buf(), limits: limits.clone() })
+    }
+
+    /// Intrinsic size, from the width/height or viewBox of the document
+    pub fn dimensions(&self) -> (u32, u32) {
+        let size = self.tree.size().to_int_size();
+        (size.width(), size.height())
+    }
+
+    /// Renders the image at `scale` times its intrinsic size, within the limits
+    pub fn rasterize(&self, scale: f32) -> anyhow::Result<DynamicImage> {
+        let size = self.tree.size().to_int_size()
+            .scale_by(scale)
+            .context("SVG is too large to render")?;
+        let (width, height) = (size.width(), size.height());
+        check_limits(&self.path, width, height, width as u64 * height as u64 * 4, &self.limits)?;
+        let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
+            .context("SVG is too large to render")?;
+        let transform = tiny_skia::Transform::from_scale(
+            size.width() as f32 / self.tree.size().width(),
+            size.height() as f32 / self.tree.size().height(),
+        );
+        resvg::render(&self.tree, transform, &mut pixmap.as_mut());
+
+        let data = pixmap.pixels()
+            .iter()
+            .flat_map(|pixel| {
+                let color = pixel.demultiply();
+                [color.red(), color.green(), color.blue(), color.alpha()]
+            })
+            .collect();
+        let img = RgbaImage::from_raw(size.width(), size.height(), data).context("Failed to render SVG")?;
+        Ok(DynamicImage::ImageRgba8(img))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn magic_bytes_win_over_svg_sniff() {
+        let png = [b"\x89PNG\r\n\x1a\n".as_slice(), b"tEXt<svg xmlns=".as_slice()].concat();
+        assert!(matches!(detect_format(&png, Path::new("a.svg")), Ok(Format::Image(ImageFormat::Png))));
+        assert!(matches!(detect_format(b"<?xml?><svg>", Path::new("a")), Ok(Format::Svg)));
+        assert!(matches!(detect_format(b"text", Path::new("a.svg")), Ok(Format::Svg)));
+        assert!(detect_format(b"text", Path::new("a")).is_err());
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::BTreeMap;
+use rayon::iter::{IndexedParallelIterator, ParallelIterator};
+use rayon::slice::ParallelSliceMut;
+use wpdm_common::{WpdmClient, WpdmMessage, WpdmTransition};
+
+use crate::cache::{cache_exists, cache_path, get_cache_name, write_cache};
+use crate::error::{print_json, CliError};
+use crate::set::{report, wallpaper, Applied};
+
+#[derive(clap::Args)]
+pub struct ColorArgs {
+    /// Colour as #rgb, #rrggbb or #rrggbbaa
+    #[arg(value_parser = parse_color)]
+    color: Color,
+
+    /// Only change this monitor. Can be repeated
+    #[arg(short, long)]
+    monitor: Vec<String>,
+}
+
+#[derive(clap::Args)]
+pub struct GradientArgs {
+    /// Colour stops, spread evenly from start to end
+    #[arg(value_parser = parse_color, num_args = 2.., required = true)]
+    colors: Vec<Color>,
+
+    /// Spread the colours outwards from the centre instead of along a line
+    #[arg(long)]
+    radial: bool,
+
+    /// Direction of a linear gradient in degrees, 0 goes upwards and 90 to the right
+    #[arg(long, default_value_t = 180.0, conflicts_with = "radial")]
+    angle: f32,
+
+    /// Only change this monitor. Can be repeated
+    #[arg(short, long)]
+    monitor: Vec<String>,
+}
+
+#[derive(clap::Args)]
+pub struct ClearArgs {
+    /// Only clear this monitor. Can be repeated
+    #[arg(short, long)]
+    monitor: Vec<String>,
+}
+
+pub type Color = [u8; 4];
+
+pub fn parse_color(s: &str) -> Result<Color, String> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid colour {}", s));
+    }
+    let digits = match hex.len() {
+        3 => hex.chars().flat_map(|c| [c, c]).collect::<String>(),
+        6 | 8 => hex.to_string(),
+        _ => return Err(format!("Expected #rgb, #rrggbb or #rrggbbaa, got {}", s)),
+    };
+    let mut color = [0, 0, 0, 255];
+    for (i, channel) in color.iter_mut().enumerate().take(digits.len() / 2) {
+        *channel = u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16)
+            .map_err(|_| format!("Invalid colour {}", s))?;
+    }
+    Ok(color)
+}
+
+pub enum Fill {
+    Solid(Color),
+    Linear { colors: Vec<Color>, angle: f32 },
+    Radial { colors: Vec<Color> },
+}
+
+impl Fill {
+    pub fn cache_key(&self) -> String {
+        let hex = |colors: &[Color]| colors.iter()
+            .map(|[r, g, b, a]| format!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, a))
+            .collect::<Vec<_>>()
+            .join(",");
+        match self {
+            Fill::Solid(color) => format!("color:{}", hex(&[*color])),
+            Fill::Linear { colors, angle } => format!("linear:{}:{}", angle, hex(colors)),
+            Fill::Radial { colors } => format!("radial:{}", hex(colors)),
+        }
+    }
+
+    /// Renders the fill as a BGRA buffer
+    pub fn render(&self, width: u32, height: u32) -> Vec<u8> {
+        let (fwidth, fheight) = (width as f32, height as f32);
+        let (cx, cy) = (fwidth / 2.0, fheight / 2.0);
+        let mut buffer = vec![0; (width * height * 4) as usize];
+
+        // Position along the gradient, 0.0 at the first colour and 1.0 at the last
+        let position = |x: f32, y: f32| -> f32 {
+            match self {
+                Fill::Solid(_) => 0.0,
+                Fill::Linear { angle, .. } => {
+                    // Same gradient line as CSS, long enough for the corners to reach the end colours
+                    let (dx, dy) = (angle.to_radians().sin(), -angle.to_radians().cos());
+                    let length = (fwidth * dx).abs() + (fheight * dy).abs();
+                    ((x - cx) * dx + (y - cy) * dy) / length + 0.5
+                },
+                Fill::Radial { .. } => {
+                    ((x - cx).hypot(y - cy)) / cx.hypot(cy)
+                },
+            }
+        };
+
+        buffer
+            .par_chunks_exact_mut(4)
+            .enumerate()
+            .for_each(|(i, pixel)| {
+                let x = (i as u32 % width) as f32 + 0.5;
+                let y = (i as u32 / width) as f32 + 0.5;
+                let [r, g, b, a] = match self {
+                    Fill::Solid(color) => *color,
+                    Fill::Linear { colors, .. } | Fill::Radial { colors } => {
+                        interpolate(colors, position(x, y))
+                    },
+                };
+                pixel.copy_from_slice(&[b, g, r, a]);
+            });
+        buffer
+    }
+}
+
+fn interpolate(colors: &[Color], t: f32) -> Color {
+    let segments = (colors.len() - 1) as f32;
+    let t = t.clamp(0.0, 1.0) * segments;
+    let idx = (t.floor() as usize).min(colors.len() - 2);
+    let frac = t - idx as f32;
+    let (from, to) = (colors[idx], colors[idx + 1]);
+    std::array::from_fn(|c| {
+        (from[c] as f32 + (to[c] as f32 - from[c] as f32) * frac).round() as u8
+    })
+}
+
+pub fn run_color(args: ColorArgs, json: bool) -> anyhow::Result<()> {
+    apply_fill(Fill::Solid(args.color), &args.monitor, json)
+}
+
+pub fn run_gradient(args: GradientArgs, json: bool) -> anyhow::Result<()> {
+    let fill = if args.radial {
+        Fill::Radial { colors: args.colors }
+    } else {
+        Fill::Linear { colors: args.colors, angle: args.angle }
+    };
+    apply_fill(fill, &args.monitor, json)
+}
+
+/// Shows black until something else is set. Any slideshow or time-of-day set is stopped, it
+/// would put a wallpaper back on its next change. The wallpaper before isn't forgotten, so
+/// `restore` can bring it back.
+pub fn run_clear(args: ClearArgs, json: bool) -> anyhow::Result<()> {
+    let mut client = WpdmClient::new()?;
+    client.send(WpdmMessage::SlideshowStop)?;
+    client.send(WpdmMessage::TimeOfDayStop)?;
+    let applied = fill_monitors(&mut client, &Fill::Solid([0, 0, 0, 255]), &args.monitor)?;
+    if json {
+        let monitors = applied.iter()
+            .flat_map(|applied| applied.monitors.iter())
+            .collect::<Vec<_>>();
+        print_json(&serde_json::json!({ "cleared": monitors }))?;
+    }
+    Ok(())
+}
+
+fn apply_fill(fill: Fill, filter: &[String], json: bool) -> anyhow::Result<()> {
+    let mut client = WpdmClient::new()?;
+    let applied = fill_monitors(&mut client, &fill, filter)?;
+    report(&applied, None, json)
+}
+
+fn fill_monitors(client: &mut WpdmClient, fill: &Fill, filter: &[String]) -> anyhow::Result<Vec<Applied>> {
+    let monitors = client.get_monitors()?;
+
+    if let Some(name) = filter.iter().find(|name| !monitors.iter().any(|mon| mon.name == **name)) {
+        return Err(CliError::UnknownMonitor(name.clone()).into());
+    }
+
+    let mut sizes = BTreeMap::<(i32, i32), Vec<String>>::new();
+    for mon in monitors {
+        if filter.is_empty() || filter.contains(&mon.name) {
+            sizes.entry((mon.width, mon.height)).or_default().push(mon.name);
+        }
+    }
+
+    let cache_key = fill.cache_key();
+    let mut wallpapers = vec![];
+    let mut applied = vec![];
+    for ((width, height), monitors) in sizes {
+        let cache_name = get_cache_name(&cache_key, width, height)?;
+        let cache_path = cache_path(&cache_name)?;
+        if !cache_exists(&cache_name) {
+            write_cache(&cache_path, &fill.render(width as u32, height as u32))?;
+        }
+        let wallpaper = wallpaper(&cache_path, monitors, WpdmTransition::default())?;
+        applied.push(Applied::new(cache_key.clone(), &wallpaper));
+        wallpapers.push(wallpaper);
+    }
+
+    client.set_wallpapers(wallpapers)?;
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_color;
+
+    #[test]
+    fn parses_short_long_and_alpha_forms() {
+        assert_eq!(parse_color("#abc"), Ok([0xaa, 0xbb, 0xcc, 255]));
+        assert_eq!(parse_color("102030"), Ok([0x10, 0x20, 0x30, 255]));
+        assert_eq!(parse_color("#10203040"), Ok([0x10, 0x20, 0x30, 0x40]));
+    }
+
+    #[test]
+    fn rejects_bad_digits_and_lengths() {
+        assert!(parse_color("#a\u{20ac}bb").is_err());
+        assert!(parse_color("#\u{e9}\u{e9}\u{e9}").is_err());
+        assert!(parse_color("#ggg").is_err());
+        assert!(parse_color("#abcd").is_err());
+    }
+}
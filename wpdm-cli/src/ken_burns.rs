@@ -0,0 +1,101 @@
+use wpdm_common::anim::KenBurnsHeader;
+
+type Point = (f32, f32);
+
+/// Slow pan and zoom over a still image, played by the daemon
+#[derive(clap::Args, Clone, Default)]
+#[command(next_help_heading = "Ken Burns")]
+pub struct KenBurnsOptions {
+    /// Drift and zoom slowly over the image instead of showing it still
+    #[arg(long)]
+    pub ken_burns: bool,
+
+    /// Seconds to go from the start of the motion to the end, it then plays back in reverse
+    #[arg(long, default_value_t = 30.0, value_parser = parse_duration, requires = "ken_burns")]
+    pub duration: f32,
+
+    /// Zoom at the start and end, 1 shows the whole image, e.g. 1:1.3
+    #[arg(long, default_value = "1:1.2", value_parser = parse_zoom, value_name = "FROM:TO", requires = "ken_burns")]
+    pub zoom: (f32, f32),
+
+    /// Direction of the pan, one of center, left, right, up, down, or X,Y:X,Y for positions
+    /// from 0 to 1, e.g. 0,0:1,1 from the top left to the bottom right
+    #[arg(long, default_value = "center", value_parser = parse_pan, requires = "ken_burns")]
+    pub pan: (Point, Point),
+
+    /// Frames rendered per second. Each one is drawn on the CPU, so keep it low
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..=30), requires = "ken_burns")]
+    pub fps: u32,
+}
+
+fn parse_duration(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(secs) if secs > 0.0 && secs.is_finite() => Ok(secs),
+        _ => Err(format!("Duration must be a positive number of seconds, got {}", s)),
+    }
+}
+
+fn parse_zoom(s: &str) -> Result<(f32, f32), String> {
+    let err = || format!("Zoom must be FROM:TO, both between 1 and 4, got {}", s);
+    let (from, to) = s.split_once(':').ok_or_else(err)?;
+    let zoom = |s: &str| match s.parse::<f32>() {
+        Ok(zoom) if (1.0..=4.0).contains(&zoom) => Ok(zoom),
+        _ => Err(err()),
+    };
+    Ok((zoom(from)?, zoom(to)?))
+}
+
+fn parse_pan(s: &str) -> Result<(Point, Point), String> {
+    match s {
+        "center" => return Ok(((0.5, 0.5), (0.5, 0.5))),
+        "left" => return Ok(((1.0, 0.5), (0.0, 0.5))),
+        "right" => return Ok(((0.0, 0.5), (1.0, 0.5))),
+        "up" => return Ok(((0.5, 1.0), (0.5, 0.0))),
+        "down" => return Ok(((0.5, 0.0), (0.5, 1.0))),
+        _ => {},
+    }
+    let err = || format!("Pan must be center, left, right, up, down or X,Y:X,Y, got {}", s);
+    let point = |s: &str| {
+        let (x, y) = s.split_once(',').ok_or_else(err)?;
+        let coord = |s: &str| match s.parse::<f32>() {
+            Ok(coord) if (0.0..=1.0).contains(&coord) => Ok(coord),
+            _ => Err(err()),
+        };
+        Ok::<_, String>((coord(x)?, coord(y)?))
+    };
+    let (from, to) = s.split_once(':').ok_or_else(err)?;
+    Ok((point(from)?, point(to)?))
+}
+
+impl KenBurnsOptions {
+    pub fn cache_key(&self) -> String {
+        if !self.ken_burns {
+            return String::new();
+        }
+        let ((from_x, from_y), (to_x, to_y)) = self.pan;
+        format!(
+            "#kenburns={}s,{}fps,{}:{},{},{}:{},{}",
+            self.duration, self.fps, self.zoom.0, self.zoom.1, from_x, from_y, to_x, to_y
+        )
+    }
+
+    /// Size of the source for a `width`x`height` monitor, enough for the closest zoom
+    pub fn source_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let zoom = self.zoom.0.max(self.zoom.1);
+        ((width as f32 * zoom).round() as u32, (height as f32 * zoom).round() as u32)
+    }
+
+    pub fn header(&self, width: u32, height: u32) -> KenBurnsHeader {
+        let (src_width, src_height) = self.source_size(width, height);
+        KenBurnsHeader {
+            width,
+            height,
+            src_width,
+            src_height,
+            duration_ms: (self.duration * 1000.0).round() as u32,
+            fps: self.fps,
+            zoom: self.zoom,
+            pan: self.pan,
+        }
+    }
+}
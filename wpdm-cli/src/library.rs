@@ -0,0 +1,340 @@
+use std::path::{Path, PathBuf};
+use anyhow::Context;
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use wpdm_common::config;
+use wpdm_common::slideshow::unix_now;
+
+use crate::error::{print_json, CliError};
+use crate::preload::list_images;
+use crate::set::{self, SetOptions};
+
+#[derive(clap::Args)]
+pub struct LibraryArgs {
+    #[command(subcommand)]
+    command: LibraryCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum LibraryCommand {
+    /// Add the images in a directory, images already in the library keep their tags
+    Add(AddArgs),
+    /// Forget images, the files are left alone
+    Remove {
+        #[arg(required = true)]
+        images: Vec<PathBuf>,
+    },
+    /// Forget images whose files are gone
+    Prune,
+    /// Add tags to an image
+    Tag {
+        image: PathBuf,
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    /// Remove tags from an image
+    Untag {
+        image: PathBuf,
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    /// Rate an image from 1 to 5, 0 clears the rating
+    Rate {
+        image: PathBuf,
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=5))]
+        rating: u8,
+    },
+    /// Mark an image as a favourite
+    Favourite {
+        image: PathBuf,
+        /// Unmark it instead
+        #[arg(long)]
+        remove: bool,
+    },
+    /// Print the images matching a query, one per line
+    List(Query),
+    /// Print every tag with the number of images that have it
+    Tags,
+}
+
+#[derive(clap::Args)]
+struct AddArgs {
+    dir: PathBuf,
+
+    /// Include images in subdirectories
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Tag every image found. Can be repeated
+    #[arg(short, long)]
+    tag: Vec<String>,
+}
+
+/// Selects images from the library, every condition given has to match
+#[derive(clap::Args)]
+pub struct Query {
+    /// Only images with this tag. Can be repeated, images need all of them
+    #[arg(short, long)]
+    tag: Vec<String>,
+
+    /// Only images rated at least this
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=5))]
+    min_rating: Option<u8>,
+
+    /// Only favourites
+    #[arg(short, long)]
+    favourite: bool,
+}
+
+#[derive(clap::Args)]
+pub struct RandomArgs {
+    #[command(flatten)]
+    query: Query,
+
+    /// Print the image instead of setting it
+    #[arg(long)]
+    print: bool,
+
+    #[command(flatten)]
+    options: SetOptions,
+}
+
+pub fn library_path() -> anyhow::Result<PathBuf> {
+    Ok(config::config_dir().context("Cannot get config dir")?.join("library.sqlite"))
+}
+
+fn open_library() -> anyhow::Result<Connection> {
+    let path = library_path()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let conn = Connection::open(&path)
+        .with_context(|| format!("Cannot open library {}", path.display()))?;
+    conn.execute_batch("
+        PRAGMA foreign_keys = ON;
+        CREATE TABLE IF NOT EXISTS images (
+            id INTEGER PRIMARY KEY,
+            path TEXT NOT NULL UNIQUE,
+            added INTEGER NOT NULL,
+            favourite INTEGER NOT NULL DEFAULT 0,
+            rating INTEGER
+        );
+        CREATE TABLE IF NOT EXISTS tags (
+            image_id INTEGER NOT NULL REFERENCES images(id) ON DELETE CASCADE,
+            tag TEXT NOT NULL,
+            PRIMARY KEY (image_id, tag)
+        );
+        CREATE INDEX IF NOT EXISTS tags_by_tag ON tags(tag);
+    ")?;
+    Ok(conn)
+}
+
+/// Tags match without regard to case
+fn normalize_tag(tag: &str) -> String {
+    tag.trim().to_lowercase()
+}
+
+fn path_str(path: &Path) -> anyhow::Result<&str> {
+    path.to_str().context("Failed to get string")
+}
+
+fn image_id(conn: &Connection, image: &Path) -> anyhow::Result<i64> {
+    let path = image.canonicalize()
+        .with_context(|| format!("Cannot open {}", image.display()))?;
+    conn.query_row("SELECT id FROM images WHERE path = ?1", [path_str(&path)?], |row| row.get(0))
+        .optional()?
+        .with_context(|| format!("{} is not in the library, add its directory with library add", path.display()))
+}
+
+impl Query {
+    /// WHERE clause and its parameters
+    fn sql(&self) -> (String, Vec<Value>) {
+        let mut conditions = vec![];
+        let mut values = vec![];
+        for tag in self.tag.iter() {
+            conditions.push("id IN (SELECT image_id FROM tags WHERE tag = ?)");
+            values.push(Value::Text(normalize_tag(tag)));
+        }
+        if let Some(rating) = self.min_rating {
+            conditions.push("rating >= ?");
+            values.push(Value::Integer(rating as i64));
+        }
+        if self.favourite {
+            conditions.push("favourite = 1");
+        }
+        if conditions.is_empty() {
+            conditions.push("1");
+        }
+        (conditions.join(" AND "), values)
+    }
+
+    fn paths(&self, conn: &Connection) -> anyhow::Result<Vec<PathBuf>> {
+        let (condition, values) = self.sql();
+        let mut stmt = conn.prepare(&format!("SELECT path FROM images WHERE {} ORDER BY path", condition))?;
+        let paths = stmt.query_map(params_from_iter(values), |row| row.get::<_, String>(0))?
+            .map(|path| Ok(PathBuf::from(path?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(paths)
+    }
+}
+
+/// An image as stored in the library, printed by `--json`
+#[derive(serde::Serialize)]
+struct Entry {
+    path: PathBuf,
+    favourite: bool,
+    rating: Option<u8>,
+    tags: Vec<String>,
+}
+
+fn entry(conn: &Connection, id: i64) -> anyhow::Result<Entry> {
+    let (path, favourite, rating) = conn.query_row(
+        "SELECT path, favourite, rating FROM images WHERE id = ?1",
+        [id],
+        |row| Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    let mut stmt = conn.prepare("SELECT tag FROM tags WHERE image_id = ?1 ORDER BY tag")?;
+    let tags = stmt.query_map([id], |row| row.get(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Entry { path: PathBuf::from(path), favourite, rating, tags })
+}
+
+/// Prints the image as it is after a change, only with `--json`
+fn print_entry(conn: &Connection, id: i64, json: bool) -> anyhow::Result<()> {
+    if json {
+        print_json(&entry(conn, id)?)?;
+    }
+    Ok(())
+}
+
+pub fn run(args: LibraryArgs, json: bool) -> anyhow::Result<()> {
+    let mut conn = open_library()?;
+    match args.command {
+        LibraryCommand::Add(add) => {
+            let dir = add.dir.canonicalize()
+                .with_context(|| format!("Cannot open {}", add.dir.display()))?;
+            let images = list_images(&dir, add.recursive)?;
+
+            let tx = conn.transaction()?;
+            let mut added = 0;
+            for path in images.iter() {
+                let path = path_str(path)?;
+                added += tx.execute("INSERT OR IGNORE INTO images (path, added) VALUES (?1, ?2)", params![path, unix_now() as i64])?;
+                for tag in add.tag.iter() {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO tags (image_id, tag) SELECT id, ?2 FROM images WHERE path = ?1",
+                        params![path, normalize_tag(tag)],
+                    )?;
+                }
+            }
+            tx.commit()?;
+            if json {
+                print_json(&serde_json::json!({ "added": added, "existing": images.len() - added }))?;
+            } else {
+                eprintln!("Added {} images, {} were already in the library", added, images.len() - added);
+            }
+        },
+        LibraryCommand::Remove { images } => {
+            let mut removed = vec![];
+            for image in images {
+                let id = image_id(&conn, &image)?;
+                removed.push(entry(&conn, id)?);
+                conn.execute("DELETE FROM images WHERE id = ?1", [id])?;
+            }
+            if json {
+                print_json(&serde_json::json!({ "removed": removed }))?;
+            }
+        },
+        LibraryCommand::Prune => {
+            let all = Query { tag: vec![], min_rating: None, favourite: false }.paths(&conn)?;
+            let tx = conn.transaction()?;
+            let mut removed = 0;
+            for path in all.iter().filter(|path| !path.is_file()) {
+                removed += tx.execute("DELETE FROM images WHERE path = ?1", [path_str(path)?])?;
+            }
+            tx.commit()?;
+            if json {
+                print_json(&serde_json::json!({ "removed": removed }))?;
+            } else {
+                eprintln!("Removed {} missing images", removed);
+            }
+        },
+        LibraryCommand::Tag { image, tags } => {
+            let id = image_id(&conn, &image)?;
+            for tag in tags {
+                conn.execute("INSERT OR IGNORE INTO tags (image_id, tag) VALUES (?1, ?2)", params![id, normalize_tag(&tag)])?;
+            }
+            print_entry(&conn, id, json)?;
+        },
+        LibraryCommand::Untag { image, tags } => {
+            let id = image_id(&conn, &image)?;
+            for tag in tags {
+                conn.execute("DELETE FROM tags WHERE image_id = ?1 AND tag = ?2", params![id, normalize_tag(&tag)])?;
+            }
+            print_entry(&conn, id, json)?;
+        },
+        LibraryCommand::Rate { image, rating } => {
+            let id = image_id(&conn, &image)?;
+            let rating = (rating > 0).then_some(rating);
+            conn.execute("UPDATE images SET rating = ?2 WHERE id = ?1", params![id, rating])?;
+            print_entry(&conn, id, json)?;
+        },
+        LibraryCommand::Favourite { image, remove } => {
+            let id = image_id(&conn, &image)?;
+            conn.execute("UPDATE images SET favourite = ?2 WHERE id = ?1", params![id, !remove])?;
+            print_entry(&conn, id, json)?;
+        },
+        LibraryCommand::List(query) => {
+            if json {
+                let (condition, values) = query.sql();
+                let mut stmt = conn.prepare(&format!("SELECT id FROM images WHERE {} ORDER BY path", condition))?;
+                let ids = stmt.query_map(params_from_iter(values), |row| row.get(0))?
+                    .collect::<Result<Vec<i64>, _>>()?;
+                let entries = ids.into_iter()
+                    .map(|id| entry(&conn, id))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                return print_json(&entries);
+            }
+            for path in query.paths(&conn)? {
+                println!("{}", path.display());
+            }
+        },
+        LibraryCommand::Tags => {
+            let mut stmt = conn.prepare("SELECT tag, count(*) FROM tags GROUP BY tag ORDER BY tag")?;
+            let tags = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)))?
+                .collect::<Result<Vec<_>, _>>()?;
+            if json {
+                let tags = tags.iter()
+                    .map(|(tag, count)| serde_json::json!({ "tag": tag, "count": count }))
+                    .collect::<Vec<_>>();
+                return print_json(&tags);
+            }
+            for (tag, count) in tags {
+                println!("{}\t{}", tag, count);
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Sets, or prints, a random library image matching the query. Files that have gone missing
+/// since they were added are passed over.
+pub fn run_random(args: RandomArgs, json: bool) -> anyhow::Result<()> {
+    let conn = open_library()?;
+    let paths = args.query.paths(&conn)?
+        .into_iter()
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    let Some(path) = paths.get(fastrand::usize(..paths.len().max(1))) else {
+        return Err(CliError::NoMatch("No images in the library match".to_string()).into());
+    };
+
+    if args.print {
+        if json {
+            return print_json(&serde_json::json!({ "image": path }));
+        }
+        println!("{}", path.display());
+        return Ok(());
+    }
+    set::set_wallpaper(path_str(path)?, &args.options, json)
+}
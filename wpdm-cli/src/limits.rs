@@ -0,0 +1,47 @@
+use std::path::Path;
+use wpdm_common::settings::{settings_path, LimitSettings};
+
+const MIB: u64 = 1024 * 1024;
+
+/// The same limits for the decoders of the image crate, which also cover their own allocations
+pub fn decoder_limits(limits: &LimitSettings) -> image::Limits {
+    let mut decoder_limits = image::Limits::default();
+    decoder_limits.max_image_width = Some(limits.max_width);
+    decoder_limits.max_image_height = Some(limits.max_height);
+    decoder_limits.max_alloc = Some(limits.max_memory_bytes());
+    decoder_limits
+}
+
+fn fits(width: u32, height: u32, bytes: u64, limits: &LimitSettings) -> bool {
+    width <= limits.max_width && height <= limits.max_height && bytes <= limits.max_memory_bytes()
+}
+
+/// Fails when decoding a `width`x`height` image into `bytes` would go over the limits,
+/// naming the setting to raise.
+pub fn check_limits(path: &Path, width: u32, height: u32, bytes: u64, limits: &LimitSettings) -> anyhow::Result<()> {
+    if fits(width, height, bytes, limits) {
+        return Ok(());
+    }
+    let settings = settings_path()
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|| "config.toml".to_string());
+    if width > limits.max_width || height > limits.max_height {
+        anyhow::bail!(
+            "{} is {}x{}, over the {}x{} limit. Raise max_width and max_height under [limits] in {} to open it",
+            path.display(), width, height, limits.max_width, limits.max_height, settings
+        );
+    }
+    anyhow::bail!(
+        "{} needs {} MiB to decode, over the {} MiB limit. Raise max_memory under [limits] in {} to open it",
+        path.display(), bytes.div_ceil(MIB), limits.max_memory, settings
+    )
+}
+
+/// Smallest JPEG scale-down, as a divisor the decoder supports, that brings the image within
+/// the limits
+pub fn jpeg_reduction(width: u32, height: u32, bytes_per_pixel: u64, limits: &LimitSettings) -> Option<u32> {
+    [2, 4, 8].into_iter().find(|reduction| {
+        let (width, height) = (width.div_ceil(*reduction), height.div_ceil(*reduction));
+        fits(width, height, width as u64 * height as u64 * bytes_per_pixel, limits)
+    })
+}
@@ -1,158 +1,165 @@
-use std::fs::OpenOptions;
-use std::io::{BufWriter, Cursor, Write};
-use std::{collections::HashMap, path::Path};
-use anyhow::Context;
-use clap::Parser;
-use fast_image_resize::{images::Image, ResizeOptions, Resizer};
-use fast_image_resize::{IntoImageView};
-use image::codecs::png::PngEncoder;
-use image::{DynamicImage, ImageEncoder, ImageReader};
-use gcd::Gcd;
-use rayon::iter::ParallelIterator;
-use rayon::slice::ParallelSliceMut;
-use sha2::{Digest, Sha256};
-use wpdm_common::config;
-use std::fmt::Write as FmtWrite;
-
+mod cache;
+mod color;
+mod current;
+mod daemon;
+mod dedupe;
+mod effects;
+mod error;
+mod fit;
+mod formats;
+mod generate;
+mod ken_burns;
+mod library;
+mod limits;
+mod monitors;
+mod palette;
+mod pick;
+mod prepare;
+mod preload;
+mod preview;
+mod set;
+mod slideshow;
+mod source;
+mod span;
+mod term;
+mod time_of_day;
+mod watch;
+
+use std::process::ExitCode;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+
+/// Sets and manages wallpapers shown by the wpdm daemon
 #[derive(Parser)]
+#[command(after_long_help = error::EXIT_CODES)]
 struct Args {
+    /// Print results as JSON on stdout, and errors as JSON on stderr
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Same as `set IMAGE_PATH`, kept for older scripts
     #[arg(short, long)]
-    image_path: String,
-}
+    image_path: Option<String>,
 
-fn get_cache_name(path: &str, width: i32, height: i32) -> anyhow::Result<String> {
-    let digest = Sha256::digest(path);
-    let mut digest_str = String::new();
-    write!(&mut digest_str, "{}x{}_{:x}", width, height, digest)?;
-    let _ = digest_str.split_off(20);
-    digest_str.push_str(".bgra");
-    Ok(digest_str)
-}
+    #[command(flatten, next_help_heading = "Options for -i")]
+    set_options: set::SetOptions,
 
-fn cache_exists(cache_name: &str) -> bool {
-    let Some(dir) = config::config_dir() else {
-        return false;
-    };
-    std::fs::exists(dir.join(cache_name)).unwrap_or(false)
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-
-fn build_bgra_buffer(img: &DynamicImage, width: u32, height: u32, cache_path: &Path) -> anyhow::Result<()> {
-    let mut dst_image = Image::new(width, height, img.pixel_type().context("Image does not have pixel type")?);
-    let mut resizer = Resizer::new();
-    let (left, top, rwidth, rheight) = get_crop_params(width, height, img.width(), img.height());
-
-    resizer.resize(img, &mut dst_image, &ResizeOptions::new().crop(left as f64, top as f64, rwidth as f64, rheight as f64))?;
-
-
-    let mut result_buf = BufWriter::new(Vec::new());
-    PngEncoder::new(&mut result_buf)
-        .write_image(
-            dst_image.buffer(),
-            width,
-            height,
-            img.color().into(),
-        )
-        .unwrap();
-    let png_image = result_buf.into_inner()?;
-    let png_image = Cursor::new(png_image);
-    let image = image::ImageReader::new(png_image);
-    let dimage = image.with_guessed_format()?.decode()?;
-    let mut image_vec = dimage.into_rgba8().into_vec();
-    image_vec
-        .par_chunks_exact_mut(4)
-        .for_each(|buff| {
-            let r = buff[0];
-            let g = buff[1];
-            let b = buff[2];
-            let a = buff[3];
-            buff.copy_from_slice(&[b, g, r, a]);
-        });
-
-    let mut file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(cache_path)?;
-
-
-    let _ = file.write(&image_vec)?;
-
-    Ok(())
+#[derive(Subcommand)]
+enum Command {
+    /// Set wallpapers, either one image for every monitor or MONITOR=PATH pairs
+    Set(set::SetArgs),
+    /// Use a solid colour as the wallpaper
+    Color(generate::ColorArgs),
+    /// Use a linear or radial gradient as the wallpaper
+    Gradient(generate::GradientArgs),
+    /// Build cache entries for every image in a directory
+    Preload(preload::PreloadArgs),
+    /// Print the dominant colours of an image, for theming
+    Palette(palette::PaletteArgs),
+    /// Write a PNG of how an image would look on every monitor, without setting it
+    Preview(preview::PreviewArgs),
+    /// Browse a directory in the terminal with thumbnails, and set the image picked
+    Pick(pick::PickArgs),
+    /// Cycle through a directory of images on a timer, run by the daemon
+    Slideshow(slideshow::SlideshowArgs),
+    /// Change the wallpaper by the time of day, at clock times or sunrise and sunset
+    TimeOfDay(time_of_day::TimeOfDayArgs),
+    /// Index wallpapers with tags, favourites and ratings
+    Library(library::LibraryArgs),
+    /// Set a random image from the library
+    Random(library::RandomArgs),
+    /// Find re-encoded and resized copies of the same image by perceptual hash
+    Dedupe(dedupe::DedupeArgs),
+    /// List the monitors known to the daemon
+    Monitors,
+    /// Show the wallpaper of every monitor, and any slideshow or time-of-day set
+    Current,
+    /// Show black instead of a wallpaper
+    Clear(generate::ClearArgs),
+    /// Set the wallpapers recorded by `current` again, e.g. after clear
+    Restore(current::RestoreArgs),
+    /// Inspect the cache of prepared wallpapers
+    Cache(cache::CacheArgs),
+    /// Talk to the daemon itself
+    Daemon(daemon::DaemonArgs),
 }
 
+fn main() -> ExitCode {
+    // Logs stay off stdout, which is kept for results
+    tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => return report_usage(err),
+    };
+    let json = args.json;
 
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => error::report(&err, json),
+    }
+}
 
-fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt().init();
-    let args = Args::parse();
-    let mut client = wpdm_common::WpdmClient::new()?;
-    let image_path = Path::new(&args.image_path).canonicalize()?;
-    let image_path_str = image_path.to_str().context("Failed to get string")?;
-
-    let monitors = client.get_monitors()?;
-    let sizes = monitors.into_iter()
-        .fold(HashMap::<(i32, i32), Vec<String>>::new(), |mut init, nxt| {
-        if let Some(monitors) = init.get_mut(&(nxt.width, nxt.height)) {
-            monitors.push(nxt.name);
-        } else {
-            init.insert((nxt.width, nxt.height), vec![nxt.name]);
-        }
-        init
-    });
-
-    let mut img = None;
-    for ((width, height), monitors) in sizes {
-        let cache_name = get_cache_name(image_path_str, width, height)?;
-        let cache_path = config::config_dir().context("Cannot get config dir")?.join(&cache_name);
-        let cache_exists = cache_exists(&cache_name);
-
-        if !cache_exists && let Some(imgg) = img.as_ref() {
-            build_bgra_buffer(imgg, width as u32, height as u32, &cache_path)?;
-        } else if !cache_exists {
-            let imgg = ImageReader::open(&image_path)?.with_guessed_format()?.decode()?;
-            build_bgra_buffer(&imgg, width as u32, height as u32, &cache_path)?;
-            img = Some(imgg);
+/// Like `Args::try_parse`, but the options of `wpdm-cli -i` can't be mixed with a subcommand.
+/// clap's `args_conflicts_with_subcommands` would also refuse `--json` before the subcommand.
+fn parse_args() -> Result<Args, clap::Error> {
+    let mut cmd = Args::command();
+    let matches = cmd.try_get_matches_from_mut(std::env::args_os())?;
+    if let Some((name, _)) = matches.subcommand() {
+        let top_level = matches.ids()
+            .filter(|id| id.as_str() != "json")
+            .find(|id| matches.value_source(id.as_str()) == Some(ValueSource::CommandLine));
+        if let Some(id) = top_level {
+            return Err(cmd.error(
+                clap::error::ErrorKind::ArgumentConflict,
+                format!("--{} cannot be used with the subcommand '{}'", id.as_str().replace('_', "-"), name),
+            ));
         }
-
-        let path = cache_path.to_path_buf().canonicalize()?;
-
-        let str_path = path.to_str().context("Cannot convert path to string")?.to_string();
-        client.set_wallpaper(str_path, monitors)?;
     }
-
-    Ok(())
+    Args::from_arg_matches(&matches)
 }
 
-fn get_crop_params(mon_width: u32, mon_height: u32, img_width: u32, img_height: u32) -> (u32, u32, u32, u32) {
-        let gcd = mon_width.gcd(mon_height);
-        let mon_ar_width = mon_width / gcd;
-        let mon_ar_height = mon_height / gcd;
-
-        let gcd = img_width.gcd(img_height);
-        let img_ar_width = img_width / gcd;
-        let img_ar_height = img_height / gcd;
-
-        let is_wide = img_ar_width * mon_ar_height >= mon_ar_width * img_ar_height;
-
-        let ar_equals = (mon_ar_width == img_ar_width) && (mon_ar_height == img_ar_height);
-
-        if is_wide && !ar_equals {
-            let width = (img_height * mon_ar_width) / mon_ar_height;
-            let height = img_height;
-
-            let x = img_width / 2 - width / 2;
-            let y = 0;
-            return (x, y, width, height);
-        } else if !ar_equals {
-            let width = img_width;
-            let height = (img_width * mon_ar_height) / mon_ar_width;
+/// Usage errors come out as JSON too when `--json` was given before any `--`. Help and
+/// version are printed by clap as usual.
+fn report_usage(err: clap::Error) -> ExitCode {
+    let json = std::env::args_os()
+        .skip(1)
+        .take_while(|arg| arg != "--")
+        .any(|arg| arg == "--json");
+    if !json || !err.use_stderr() {
+        err.exit();
+    }
+    error::report(&err.into(), json)
+}
 
-            let x = 0;
-            let y = img_height / 2 - height / 2;
-            return (x, y, width, height)
+fn run(args: Args) -> anyhow::Result<()> {
+    let json = args.json;
+    match (args.command, args.image_path) {
+        (Some(Command::Set(set_args)), _) => set::run(set_args, json),
+        (Some(Command::Color(color_args)), _) => generate::run_color(color_args, json),
+        (Some(Command::Gradient(gradient_args)), _) => generate::run_gradient(gradient_args, json),
+        (Some(Command::Preload(preload_args)), _) => preload::run(preload_args, json),
+        (Some(Command::Palette(palette_args)), _) => palette::run(palette_args, json),
+        (Some(Command::Preview(preview_args)), _) => preview::run(preview_args, json),
+        (Some(Command::Pick(pick_args)), _) => pick::run(pick_args, json),
+        (Some(Command::Slideshow(slideshow_args)), _) => slideshow::run(slideshow_args, json),
+        (Some(Command::TimeOfDay(time_of_day_args)), _) => time_of_day::run(time_of_day_args, json),
+        (Some(Command::Library(library_args)), _) => library::run(library_args, json),
+        (Some(Command::Random(random_args)), _) => library::run_random(random_args, json),
+        (Some(Command::Dedupe(dedupe_args)), _) => dedupe::run(dedupe_args, json),
+        (Some(Command::Monitors), _) => monitors::run(json),
+        (Some(Command::Current), _) => current::run(json),
+        (Some(Command::Clear(clear_args)), _) => generate::run_clear(clear_args, json),
+        (Some(Command::Restore(restore_args)), _) => current::run_restore(restore_args, json),
+        (Some(Command::Cache(cache_args)), _) => cache::run(cache_args, json),
+        (Some(Command::Daemon(daemon_args)), _) => daemon::run(daemon_args, json),
+        (None, Some(image_path)) => set::set_wallpaper(&image_path, &args.set_options, json),
+        (None, None) => {
+            Args::command().print_help()?;
+            Ok(())
         }
-
-        (0, 0, img_width, img_height)
+    }
 }
-
@@ -0,0 +1,17 @@
+use wpdm_common::WpdmClient;
+
+use crate::error::print_json;
+
+/// Prints the outputs the daemon knows, with their logical size and position
+pub fn run(json: bool) -> anyhow::Result<()> {
+    let mut client = WpdmClient::new()?;
+    let monitors = client.get_monitors()?;
+    if json {
+        return print_json(&monitors);
+    }
+    for mon in monitors {
+        let transform = format!("{:?}", mon.transform).to_lowercase();
+        println!("{}\t{}x{}\t{:+}{:+}\t{}", mon.name, mon.width, mon.height, mon.x, mon.y, transform);
+    }
+    Ok(())
+}
@@ -0,0 +1,303 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use anyhow::Context;
+use memmap2::Mmap;
+use wpdm_common::anim::{AnimHeader, KenBurnsHeader};
+use wpdm_common::settings::load_settings;
+
+use crate::error::CliError;
+use crate::prepare::{open_image, render_source, PrepareOptions};
+use crate::source::Source;
+
+/// Longest side the image is prepared at for `palette`, plenty to find the dominant colours
+const SAMPLE_SIZE: u32 = 512;
+/// Pixels looked at, larger buffers are sampled evenly
+const MAX_SAMPLES: usize = 100_000;
+
+#[derive(Clone, Debug)]
+pub enum PaletteFormat {
+    Hex,
+    Json,
+    /// File where `{color0}`, `{color0.rgb}`, `{background}` and `{foreground}` are filled in
+    Template(PathBuf),
+}
+
+pub fn parse_format(s: &str) -> Result<PaletteFormat, String> {
+    match s {
+        "hex" => Ok(PaletteFormat::Hex),
+        "json" => Ok(PaletteFormat::Json),
+        _ if Path::new(s).is_file() => Ok(PaletteFormat::Template(PathBuf::from(s))),
+        _ => Err(format!("Expected hex, json or a template file, got {}", s)),
+    }
+}
+
+#[derive(clap::Args)]
+pub struct PaletteArgs {
+    /// Image to take the colours from, - reads it from stdin
+    image: String,
+
+    /// Number of colours to extract
+    #[arg(short = 'n', long, default_value_t = 8, value_parser = clap::value_parser!(u8).range(1..=64))]
+    colors: u8,
+
+    /// hex for one colour per line, json, or a template file where {color0}, {color0.rgb},
+    /// {background} and {foreground} are replaced
+    #[arg(short, long, default_value = "hex", value_parser = parse_format)]
+    format: PaletteFormat,
+
+    /// Write the palette to this file instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    #[command(flatten)]
+    prepare: PrepareOptions,
+}
+
+#[derive(serde::Serialize, Clone, Copy, Debug)]
+pub struct PaletteColor {
+    pub rgb: [u8; 3],
+    /// Fraction of the pixels closest to this colour
+    pub share: f32,
+}
+
+impl PaletteColor {
+    fn hex(&self) -> String {
+        let [r, g, b] = self.rgb;
+        format!("#{:02x}{:02x}{:02x}", r, g, b)
+    }
+
+    fn luminance(&self) -> f32 {
+        let [r, g, b] = self.rgb.map(|c| c as f32);
+        0.2126 * r + 0.7152 * g + 0.0722 * b
+    }
+}
+
+pub fn run(args: PaletteArgs, json: bool) -> anyhow::Result<()> {
+    let settings = load_settings()?;
+    let source = if args.image == "-" {
+        Source::read_stdin(None, &settings.limits)?
+    } else {
+        Source::Path(Path::new(&args.image).canonicalize()
+            .with_context(|| format!("Cannot open {}", args.image))?)
+    };
+    let img = open_image(&source, &args.prepare, &settings.limits)
+        .context(CliError::Decode(source.name().display().to_string()))?;
+
+    // Prepared the same way as for a monitor, so effects change the palette too
+    let (width, height) = img.dimensions();
+    let scale = (SAMPLE_SIZE as f64 / width.max(height) as f64).min(1.0);
+    let (sample_width, sample_height) = (
+        ((width as f64 * scale).round() as u32).max(1),
+        ((height as f64 * scale).round() as u32).max(1),
+    );
+    let crop = (0.0, 0.0, width as f64, height as f64);
+    let buffer = render_source(&img, sample_width, sample_height, crop, &args.prepare, None)?;
+
+    let palette = extract_palette(&[&buffer], args.colors as usize);
+    // JSON output wins over --format, so every command prints the same kind of result
+    let text = if json {
+        serde_json::to_string(&palette_json(&palette))? + "\n"
+    } else {
+        format_palette(&palette, &args.format)?
+    };
+    match args.output {
+        Some(path) => std::fs::write(&path, text)
+            .with_context(|| format!("Cannot write {}", path.display()))?,
+        None => std::io::stdout().write_all(text.as_bytes())?,
+    }
+    Ok(())
+}
+
+/// Palette of the cache entries that were just set, taken together. The entries are mapped
+/// rather than read, so only the frame the palette comes from is loaded from an animation.
+pub fn cache_palette(cache_paths: &[PathBuf], colors: usize) -> anyhow::Result<Vec<PaletteColor>> {
+    let entries = cache_paths.iter()
+        .map(|path| map_entry(path).with_context(|| format!("Cannot read {}", path.display())))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let buffers = entries.iter()
+        .map(|data| cache_pixels(data))
+        .collect::<Vec<_>>();
+    Ok(extract_palette(&buffers, colors))
+}
+
+pub fn print_cache_palette(cache_paths: &[PathBuf], colors: usize, format: &PaletteFormat) -> anyhow::Result<()> {
+    let palette = cache_palette(cache_paths, colors)?;
+    std::io::stdout().write_all(format_palette(&palette, format)?.as_bytes())?;
+    Ok(())
+}
+
+fn map_entry(path: &Path) -> anyhow::Result<Mmap> {
+    let file = File::open(path)?;
+    // Cache entries are only ever replaced by writing a new file, never changed in place
+    Ok(unsafe { Mmap::map(&file)? })
+}
+
+/// Pixels on screen when the entry is shown, the first frame of animations and the whole
+/// source of Ken Burns wallpapers
+fn cache_pixels(data: &[u8]) -> &[u8] {
+    if KenBurnsHeader::parse(data).is_some() {
+        return &data[KenBurnsHeader::LEN..];
+    }
+    if let Some(header) = AnimHeader::parse(data) {
+        return &data[header.len()..header.len() + header.frame_len()];
+    }
+    data
+}
+
+/// Dominant colours of BGRA buffers by median cut, most common first
+pub fn extract_palette(buffers: &[&[u8]], colors: usize) -> Vec<PaletteColor> {
+    let total = buffers.iter().map(|buffer| buffer.len() / 4).sum::<usize>();
+    let step = total.div_ceil(MAX_SAMPLES).max(1);
+    let pixels = buffers.iter()
+        .flat_map(|buffer| buffer.chunks_exact(4))
+        .step_by(step)
+        .map(|pixel| [pixel[2], pixel[1], pixel[0]])
+        .collect::<Vec<_>>();
+    let sampled = pixels.len().max(1) as f32;
+
+    let mut boxes = vec![pixels];
+    while boxes.len() < colors {
+        // Split the box with the widest spread of any channel at its median
+        let widest = boxes.iter()
+            .enumerate()
+            .filter(|(_, pixels)| pixels.len() > 1)
+            .map(|(idx, pixels)| {
+                let (channel, range) = (0..3)
+                    .map(|channel| {
+                        let min = pixels.iter().map(|pixel| pixel[channel]).min().unwrap_or(0);
+                        let max = pixels.iter().map(|pixel| pixel[channel]).max().unwrap_or(0);
+                        (channel, max - min)
+                    })
+                    .max_by_key(|(_, range)| *range)
+                    .unwrap();
+                (idx, channel, range)
+            })
+            .filter(|(_, _, range)| *range > 0)
+            .max_by_key(|(_, _, range)| *range);
+        let Some((idx, channel, _)) = widest else {
+            break;
+        };
+        let mut pixels = boxes.swap_remove(idx);
+        pixels.sort_unstable_by_key(|pixel| pixel[channel]);
+        // Pixels level with the median stay on one side, or a colour covering the median
+        // would be averaged with its neighbours
+        let (mid, median) = (pixels.len() / 2, pixels[pixels.len() / 2][channel]);
+        let below = pixels.partition_point(|pixel| pixel[channel] < median);
+        let through = pixels.partition_point(|pixel| pixel[channel] <= median);
+        let split = if below > 0 && (through == pixels.len() || mid - below <= through - mid) { below } else { through };
+        let upper = pixels.split_off(split);
+        boxes.push(pixels);
+        boxes.push(upper);
+    }
+
+    let mut palette = boxes.into_iter()
+        .filter(|pixels| !pixels.is_empty())
+        .map(|pixels| {
+            let mut sum = [0u64; 3];
+            for pixel in pixels.iter() {
+                for channel in 0..3 {
+                    sum[channel] += pixel[channel] as u64;
+                }
+            }
+            let count = pixels.len() as u64;
+            PaletteColor {
+                rgb: sum.map(|sum| (sum as f64 / count as f64).round() as u8),
+                share: pixels.len() as f32 / sampled,
+            }
+        })
+        .collect::<Vec<_>>();
+    palette.sort_by(|a, b| b.share.total_cmp(&a.share));
+    palette
+}
+
+/// Colours with their hex code, as printed by `--format json`
+pub fn palette_json(palette: &[PaletteColor]) -> serde_json::Value {
+    #[derive(serde::Serialize)]
+    struct JsonColor {
+        hex: String,
+        #[serde(flatten)]
+        color: PaletteColor,
+    }
+    let colors = palette.iter()
+        .map(|color| JsonColor { hex: color.hex(), color: *color })
+        .collect::<Vec<_>>();
+    serde_json::to_value(colors).unwrap_or_default()
+}
+
+fn format_palette(palette: &[PaletteColor], format: &PaletteFormat) -> anyhow::Result<String> {
+    match format {
+        PaletteFormat::Hex => Ok(palette.iter().map(|color| color.hex() + "\n").collect()),
+        PaletteFormat::Json => Ok(serde_json::to_string_pretty(&palette_json(palette))? + "\n"),
+        PaletteFormat::Template(path) => {
+            let mut text = std::fs::read_to_string(path)
+                .with_context(|| format!("Cannot read template {}", path.display()))?;
+            let by_luminance = |a: &&PaletteColor, b: &&PaletteColor| a.luminance().total_cmp(&b.luminance());
+            if let Some(background) = palette.iter().min_by(by_luminance) {
+                text = text.replace("{background}", &background.hex());
+            }
+            if let Some(foreground) = palette.iter().max_by(by_luminance) {
+                text = text.replace("{foreground}", &foreground.hex());
+            }
+            for (idx, color) in palette.iter().enumerate() {
+                let [r, g, b] = color.rgb;
+                text = text.replace(&format!("{{color{}}}", idx), &color.hex());
+                text = text.replace(&format!("{{color{}.rgb}}", idx), &format!("{},{},{}", r, g, b));
+            }
+            Ok(text)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// BGRA buffer with `count` pixels of each RGB colour
+    fn buffer(colors: &[([u8; 3], usize)]) -> Vec<u8> {
+        colors.iter()
+            .flat_map(|&([r, g, b], count)| std::iter::repeat_n([b, g, r, 255], count))
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn palette_is_most_common_colour_first() {
+        let red_and_blue = buffer(&[([255, 0, 0], 30), ([0, 0, 255], 10)]);
+        let palette = extract_palette(&[&red_and_blue], 2);
+        assert_eq!(palette.iter().map(|color| color.rgb).collect::<Vec<_>>(), [[255, 0, 0], [0, 0, 255]]);
+        assert_eq!(palette.iter().map(|color| color.share).collect::<Vec<_>>(), [0.75, 0.25]);
+    }
+
+    #[test]
+    fn palette_takes_buffers_together() {
+        let (red, blue) = (buffer(&[([255, 0, 0], 10)]), buffer(&[([0, 0, 255], 30)]));
+        let palette = extract_palette(&[&red, &blue], 2);
+        assert_eq!(palette.iter().map(|color| color.rgb).collect::<Vec<_>>(), [[0, 0, 255], [255, 0, 0]]);
+    }
+
+    #[test]
+    fn palette_stops_at_the_colours_there_are() {
+        let grey = buffer(&[([128, 128, 128], 50)]);
+        let palette = extract_palette(&[&grey], 8);
+        assert_eq!(palette.len(), 1);
+        assert_eq!(palette[0].rgb, [128, 128, 128]);
+        assert!(extract_palette(&[], 8).is_empty());
+    }
+
+    #[test]
+    fn formats_hex_and_templates() {
+        let palette = [
+            PaletteColor { rgb: [0x20, 0x40, 0x60], share: 0.5 },
+            PaletteColor { rgb: [0xf0, 0xe0, 0xd0], share: 0.3 },
+            PaletteColor { rgb: [0x01, 0x02, 0x03], share: 0.2 },
+        ];
+        assert_eq!(format_palette(&palette, &PaletteFormat::Hex).unwrap(), "#204060\n#f0e0d0\n#010203\n");
+
+        let template = std::env::temp_dir().join(format!("wpdm-palette-{}.txt", std::process::id()));
+        std::fs::write(&template, "bg={background} fg={foreground} c1={color1} c0={color0.rgb} c9={color9}").unwrap();
+        let text = format_palette(&palette, &PaletteFormat::Template(template.clone()));
+        std::fs::remove_file(&template).unwrap();
+        assert_eq!(text.unwrap(), "bg=#010203 fg=#f0e0d0 c1=#f0e0d0 c0=32,64,96 c9={color9}");
+    }
+}
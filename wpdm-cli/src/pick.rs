@@ -0,0 +1,319 @@
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use anyhow::Context;
+use image::{imageops, RgbaImage};
+use wpdm_common::settings::{load_settings, Settings};
+use wpdm_common::{WpdmClient, WpdmMonitor};
+
+use crate::error::{print_json, CliError};
+use crate::prepare::{get_crop_params, image_dimensions, open_thumbnail, render_source, SourceImage};
+use crate::preload::list_images;
+use crate::set::{self, image_jobs, SetOptions};
+use crate::source::Source;
+use crate::term::{self, Graphics, Key, Terminal};
+
+#[derive(clap::Args)]
+pub struct PickArgs {
+    /// Directory to browse
+    dir: PathBuf,
+
+    /// Include images in subdirectories
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// How to draw images, detected from the terminal by default
+    #[arg(long, value_enum)]
+    graphics: Option<Graphics>,
+
+    #[command(flatten)]
+    options: SetOptions,
+}
+
+/// The selected image, decoded once for every redraw at about the size it is drawn
+struct Selected {
+    idx: usize,
+    fit: (u32, u32),
+    img: anyhow::Result<SourceImage>,
+}
+
+struct Picker {
+    images: Vec<PathBuf>,
+    names: Vec<String>,
+    monitors: Vec<WpdmMonitor>,
+    settings: Settings,
+    options: SetOptions,
+    graphics: Graphics,
+    cursor: usize,
+    scroll: usize,
+    selected: Option<Selected>,
+    status: String,
+}
+
+/// Renders `img` cropped to `crop` at `width`x`height`, as RGBA
+fn thumbnail(img: &SourceImage, width: u32, height: u32, crop: (f64, f64, f64, f64), options: &SetOptions) -> anyhow::Result<RgbaImage> {
+    let mut buffer = render_source(img, width.max(1), height.max(1), crop, &options.prepare, None)?;
+    for pixel in buffer.chunks_exact_mut(4) {
+        pixel.swap(0, 2);
+    }
+    RgbaImage::from_raw(width.max(1), height.max(1), buffer).context("Thumbnail has the wrong size")
+}
+
+/// Cursor moved by `by` rows, staying on one of `len` images
+fn moved(cursor: usize, by: isize, len: usize) -> usize {
+    cursor.saturating_add_signed(by).min(len - 1)
+}
+
+/// First row of the list to show, scrolling as little as keeps the cursor among `rows`
+fn scroll_to(cursor: usize, scroll: usize, rows: usize) -> usize {
+    if cursor < scroll {
+        cursor
+    } else if cursor >= scroll + rows {
+        cursor + 1 - rows
+    } else {
+        scroll
+    }
+}
+
+impl Picker {
+    fn select(&mut self, fit: (u32, u32)) {
+        if self.selected.as_ref().is_some_and(|selected| selected.idx == self.cursor && selected.fit == fit) {
+            return;
+        }
+        let source = Source::Path(self.images[self.cursor].clone());
+        let img = open_thumbnail(&source, fit, &self.options.prepare, &self.settings.limits);
+        self.selected = Some(Selected { idx: self.cursor, fit, img });
+    }
+
+    fn move_cursor(&mut self, by: isize) {
+        self.cursor = moved(self.cursor, by, self.images.len());
+    }
+
+    /// The whole image fitted into `width`x`height`
+    fn image_view(&self, img: &SourceImage, width: u32, height: u32) -> anyhow::Result<RgbaImage> {
+        let (img_width, img_height) = img.dimensions();
+        let scale = (width as f64 / img_width as f64).min(height as f64 / img_height as f64);
+        let (fit_width, fit_height) = ((img_width as f64 * scale) as u32, (img_height as f64 * scale) as u32);
+        thumbnail(img, fit_width, fit_height, (0.0, 0.0, img_width as f64, img_height as f64), &self.options)
+    }
+
+    /// What every monitor would show, laid out by their logical positions and scaled to fit
+    /// `width`x`height`. Returns the labels to print over each monitor too.
+    fn monitors_view(&self, img: &SourceImage, width: u32, height: u32) -> anyhow::Result<(RgbaImage, Vec<(String, u32)>)> {
+        let min_x = self.monitors.iter().map(|mon| mon.x).min().unwrap_or(0);
+        let min_y = self.monitors.iter().map(|mon| mon.y).min().unwrap_or(0);
+        let layout_width = self.monitors.iter().map(|mon| mon.x - min_x + mon.width).max().unwrap_or(1);
+        let layout_height = self.monitors.iter().map(|mon| mon.y - min_y + mon.height).max().unwrap_or(1);
+        let scale = (width as f64 / layout_width as f64).min(height as f64 / layout_height as f64);
+
+        let source = Source::Path(self.images[self.cursor].clone());
+        let jobs = image_jobs(&source, self.monitors.clone(), self.options.span_bezel(), &self.options.prepare, &self.settings)?;
+        let (img_width, img_height) = img.dimensions();
+        // Span crops are made for the whole image, the thumbnail can be smaller
+        let (scale_x, scale_y) = match self.options.span_bezel() {
+            Some(_) => {
+                let (full_width, full_height) = image_dimensions(&source, &self.options.prepare, &self.settings.limits)?;
+                (img_width as f64 / full_width as f64, img_height as f64 / full_height as f64)
+            },
+            None => (1.0, 1.0),
+        };
+        let mut canvas = RgbaImage::new((layout_width as f64 * scale) as u32, (layout_height as f64 * scale) as u32);
+        let mut labels = vec![];
+        for job in jobs {
+            let crop = match job.crop {
+                Some((left, top, rwidth, rheight)) => (left * scale_x, top * scale_y, rwidth * scale_x, rheight * scale_y),
+                None => {
+                    let (left, top, rwidth, rheight) = get_crop_params(job.width as u32, job.height as u32, img_width, img_height);
+                    (left as f64, top as f64, rwidth as f64, rheight as f64)
+                },
+            };
+            let tile_width = (job.width as f64 * scale) as u32;
+            let tile_height = (job.height as f64 * scale) as u32;
+            let tile = thumbnail(img, tile_width, tile_height, crop, &self.options)?;
+            for mon in self.monitors.iter().filter(|mon| job.monitors.contains(&mon.name)) {
+                let x = ((mon.x - min_x) as f64 * scale) as u32;
+                let y = ((mon.y - min_y) as f64 * scale) as u32;
+                imageops::replace(&mut canvas, &tile, x as i64, y as i64);
+                labels.push((mon.name.clone(), x));
+            }
+        }
+        labels.sort_by_key(|(_, x)| *x);
+        Ok((canvas, labels))
+    }
+
+    fn draw(&mut self, terminal: &Terminal) -> anyhow::Result<()> {
+        let (cols, rows, cell_width, cell_height) = terminal.size();
+        let (cell_width, cell_height) = match self.graphics {
+            Graphics::Text => (1, 2),
+            _ => (cell_width, cell_height),
+        };
+        let list_rows = rows as usize - 1;
+        self.scroll = scroll_to(self.cursor, self.scroll, list_rows);
+
+        let mut out = BufWriter::new(std::io::stdout().lock());
+        term::clear(&mut out, self.graphics)?;
+        let list_width = (cols / 3).clamp(12, 40);
+        for (row, idx) in (self.scroll..self.images.len()).take(list_rows).enumerate() {
+            term::move_to(&mut out, 0, row as u16)?;
+            let name = self.names[idx].chars().take(list_width as usize - 1).collect::<String>();
+            if idx == self.cursor {
+                write!(out, "\x1b[7m{:<width$}\x1b[0m", name, width = list_width as usize - 1)?;
+            } else {
+                write!(out, "{}", name)?;
+            }
+        }
+
+        // The image on top, the monitors below with a row of names between them
+        let view_col = list_width + 1;
+        let view_cols = cols.saturating_sub(view_col) as u32;
+        let image_rows = (rows as u32 - 2) / 2;
+        let monitor_rows = rows as u32 - 2 - image_rows;
+        self.select((view_cols * cell_width, image_rows.max(monitor_rows) * cell_height));
+        let selected = self.selected.as_ref().unwrap();
+        let mut info = self.names[self.cursor].clone();
+        match &selected.img {
+            Ok(img) => {
+                let (img_width, img_height) = img.dimensions();
+                info = format!("{}  {}x{}", info, img_width, img_height);
+                let view = self.image_view(img, view_cols * cell_width, image_rows * cell_height)?;
+                term::draw_image(&mut out, &view, view_col, 0, self.graphics)?;
+                let (monitors, labels) = self.monitors_view(img, view_cols * cell_width, monitor_rows * cell_height)?;
+                for (name, x) in labels {
+                    term::move_to(&mut out, view_col + (x / cell_width) as u16, image_rows as u16)?;
+                    write!(out, "\x1b[1m{}\x1b[0m", name)?;
+                }
+                term::draw_image(&mut out, &monitors, view_col, image_rows as u16 + 1, self.graphics)?;
+            },
+            Err(err) => {
+                term::move_to(&mut out, view_col, 0)?;
+                write!(out, "Failed to decode: {}", err)?;
+            },
+        }
+
+        term::move_to(&mut out, 0, rows - 1)?;
+        let help = "j/k move  enter set  q quit";
+        let status = if self.status.is_empty() { info } else { self.status.clone() };
+        let line = format!("{}/{}  {}  |  {}", self.cursor + 1, self.images.len(), status, help);
+        write!(out, "\x1b[7m{:<width$}\x1b[0m", line.chars().take(cols as usize).collect::<String>(), width = cols as usize)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Browses a directory in the terminal, previewing each image whole and as every monitor
+/// would crop it. Enter sets the image and keeps the picker open, to compare a few.
+pub fn run(args: PickArgs, json: bool) -> anyhow::Result<()> {
+    // It would be printed over the picker
+    if args.options.prints_palette() {
+        return Err(CliError::Usage("--palette cannot be used with pick".to_string()).into());
+    }
+    let dir = args.dir.canonicalize()
+        .with_context(|| format!("Cannot open {}", args.dir.display()))?;
+    let images = list_images(&dir, args.recursive)?;
+    if images.is_empty() {
+        return Err(CliError::NoMatch(format!("No images in {}", dir.display())).into());
+    }
+    let names = images.iter()
+        .map(|path| path.strip_prefix(&dir).unwrap_or(path).display().to_string())
+        .collect();
+    let monitors = WpdmClient::new()?.get_monitors()?;
+    let settings = load_settings()?;
+
+    let terminal = Terminal::enter()?;
+    let graphics = args.graphics.unwrap_or_else(|| terminal.detect_graphics());
+    let mut picker = Picker {
+        images,
+        names,
+        monitors,
+        settings,
+        options: args.options,
+        graphics,
+        cursor: 0,
+        scroll: 0,
+        selected: None,
+        status: String::new(),
+    };
+
+    let mut applied = None;
+    loop {
+        picker.draw(&terminal)?;
+        let page = terminal.size().1 as isize - 1;
+        picker.status.clear();
+        match terminal.read_key()? {
+            Key::Up => picker.move_cursor(-1),
+            Key::Down => picker.move_cursor(1),
+            Key::PageUp => picker.move_cursor(-page),
+            Key::PageDown => picker.move_cursor(page),
+            Key::Home => picker.cursor = 0,
+            Key::End => picker.cursor = picker.images.len() - 1,
+            Key::Enter => {
+                let path = picker.images[picker.cursor].clone();
+                let result = path.to_str()
+                    .context("Failed to get string")
+                    .and_then(|path| set::set_wallpaper(path, &picker.options, false));
+                picker.status = match result {
+                    Ok(()) => {
+                        applied = Some(path);
+                        format!("Set {}", picker.names[picker.cursor])
+                    },
+                    Err(err) => format!("Failed: {:#}", err),
+                };
+            },
+            Key::Quit => break,
+            Key::Other => {},
+        }
+    }
+
+    let mut stdout = std::io::stdout();
+    term::clear(&mut stdout, graphics)?;
+    drop(terminal);
+    if json {
+        print_json(&serde_json::json!({ "image": applied }))?;
+    } else if let Some(path) = applied {
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use image::{DynamicImage, Rgba};
+
+    use super::*;
+
+    #[test]
+    fn cursor_stays_on_the_list() {
+        assert_eq!(moved(0, -1, 5), 0);
+        assert_eq!(moved(3, 10, 5), 4);
+        assert_eq!(moved(3, -2, 5), 1);
+    }
+
+    #[test]
+    fn list_scrolls_just_enough_to_show_the_cursor() {
+        assert_eq!(scroll_to(2, 0, 10), 0);
+        assert_eq!(scroll_to(12, 0, 10), 3);
+        assert_eq!(scroll_to(4, 8, 10), 4);
+        assert_eq!(scroll_to(17, 8, 10), 8);
+    }
+
+    #[test]
+    fn thumbnails_are_rgba_crops() {
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            options: SetOptions,
+        }
+        let options = Cli::parse_from(["pick"]).options;
+        // Red on the left quarter, blue on the rest
+        let img = RgbaImage::from_fn(16, 4, |x, _| if x < 4 { Rgba([255, 0, 0, 255]) } else { Rgba([0, 0, 255, 255]) });
+        let img = SourceImage::Bitmap(DynamicImage::ImageRgba8(img));
+
+        let right = thumbnail(&img, 2, 2, (10.0, 0.0, 4.0, 4.0), &options).unwrap();
+        assert_eq!(right.dimensions(), (2, 2));
+        assert!(right.pixels().all(|pixel| pixel.0 == [0, 0, 255, 255]));
+        // Never empty, even when the view has no room
+        let left = thumbnail(&img, 0, 0, (0.0, 0.0, 2.0, 2.0), &options).unwrap();
+        assert_eq!(left.dimensions(), (1, 1));
+        assert_eq!(left.get_pixel(0, 0).0, [255, 0, 0, 255]);
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use anyhow::Context;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use wpdm_common::settings::{load_settings, LimitSettings};
+
+use crate::cache::{cache_exists, cache_path, get_cache_name};
+use crate::formats::is_supported_path;
+use crate::prepare::{build_bgra_buffer, open_image, profile_cache_key, PrepareOptions};
+use crate::error::{print_json, CliError};
+use crate::source::Source;
+
+#[derive(clap::Args)]
+pub struct PreloadArgs {
+    /// Directory containing the images to cache
+    dir: PathBuf,
+
+    /// Size to build cache entries for, e.g. 1920x1080. Can be repeated. When omitted, the
+    /// sizes of the monitors connected to the running daemon are used.
+    #[arg(short, long, value_parser = parse_size)]
+    size: Vec<(i32, i32)>,
+
+    #[command(flatten)]
+    prepare: PrepareOptions,
+}
+
+pub fn parse_size(s: &str) -> Result<(i32, i32), String> {
+    let (width, height) = s.split_once('x')
+        .ok_or_else(|| format!("Expected WIDTHxHEIGHT, got {}", s))?;
+    let width = width.parse::<i32>().map_err(|e| e.to_string())?;
+    let height = height.parse::<i32>().map_err(|e| e.to_string())?;
+    if width <= 0 || height <= 0 {
+        return Err(format!("Size must be positive, got {}", s));
+    }
+    Ok((width, height))
+}
+
+/// Images in `dir` we can decode, sorted
+pub fn list_images(dir: &Path, recursive: bool) -> anyhow::Result<Vec<PathBuf>> {
+    let mut images = vec![];
+    scan_dir(dir, recursive, &mut images)?;
+    images.sort();
+    Ok(images)
+}
+
+fn scan_dir(dir: &Path, recursive: bool, images: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Cannot read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() && recursive {
+            scan_dir(&path, recursive, images)?;
+        } else if path.is_file() && is_supported_path(&path) {
+            images.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Size and colour profile of a monitor to build entries for
+type Target = (i32, i32, Option<PathBuf>);
+
+/// Returns whether any cache entry had to be built for this image.
+fn preload_image(path: &Path, targets: &[Target], prepare: &PrepareOptions, limits: &LimitSettings) -> anyhow::Result<bool> {
+    let path = path.canonicalize()?;
+    let source = Source::Path(path);
+    let cache_key = prepare.cache_key(&source.cache_key()?);
+
+    let mut missing = vec![];
+    for (width, height, profile) in targets {
+        let cache_name = get_cache_name(&profile_cache_key(&cache_key, profile.as_deref()), *width, *height)?;
+        if !cache_exists(&cache_name) {
+            missing.push((*width, *height, profile.as_deref(), cache_path(&cache_name)?));
+        }
+    }
+
+    if missing.is_empty() {
+        return Ok(false);
+    }
+
+    let img = open_image(&source, prepare, limits)
+        .context(CliError::Decode(source.name().display().to_string()))?;
+    for (width, height, profile, cache_path) in missing {
+        build_bgra_buffer(&img, width as u32, height as u32, prepare, profile, &cache_path)?;
+    }
+    Ok(true)
+}
+
+#[derive(serde::Serialize, Default)]
+struct PreloadSummary {
+    cached: Vec<PathBuf>,
+    skipped: Vec<PathBuf>,
+    failed: Vec<PreloadFailure>,
+}
+
+#[derive(serde::Serialize)]
+struct PreloadFailure {
+    path: PathBuf,
+    error: String,
+}
+
+pub fn run(args: PreloadArgs, json: bool) -> anyhow::Result<()> {
+    let settings = load_settings()?;
+    let color = settings.color;
+    let targets = if args.size.is_empty() {
+        let mut client = wpdm_common::WpdmClient::new()?;
+        client.get_monitors()
+            .context("No sizes given and the daemon could not be queried, pass --size instead")?
+            .into_iter()
+            .map(|mon| (mon.width, mon.height, color.monitor_profile(&mon.name).map(Path::to_path_buf)))
+            .collect::<BTreeSet<_>>()
+    } else {
+        args.size.into_iter()
+            .map(|(width, height)| (width, height, color.profile.clone()))
+            .collect()
+    };
+    let targets = targets.into_iter().collect::<Vec<_>>();
+
+    let images = list_images(&args.dir, false)?;
+    let total = images.len();
+    let done = AtomicUsize::new(0);
+
+    // Entries that already exist are skipped, so an interrupted preload can just be re-run
+    let results = images.par_iter()
+        .map(|path| {
+            let result = preload_image(path, &targets, &args.prepare, &settings.limits);
+            let n = done.fetch_add(1, Ordering::Relaxed) + 1;
+            if !json {
+                match &result {
+                    Ok(true) => eprintln!("[{}/{}] cached {}", n, total, path.display()),
+                    Ok(false) => eprintln!("[{}/{}] skipped {}", n, total, path.display()),
+                    Err(err) => eprintln!("[{}/{}] failed {}: {}", n, total, path.display(), err),
+                }
+            }
+            (path, result)
+        })
+        .collect::<Vec<_>>();
+
+    let mut summary = PreloadSummary::default();
+    for (path, result) in results {
+        match result {
+            Ok(true) => summary.cached.push(path.clone()),
+            Ok(false) => summary.skipped.push(path.clone()),
+            Err(err) => summary.failed.push(PreloadFailure { path: path.clone(), error: format!("{:#}", err) }),
+        }
+    }
+    if json {
+        print_json(&summary)?;
+    }
+
+    if !summary.failed.is_empty() {
+        anyhow::bail!("Failed to cache {} of {} images", summary.failed.len(), total);
+    }
+    Ok(())
+}
@@ -0,0 +1,568 @@
+use std::io::{BufRead, BufWriter, Cursor, Read, Seek};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use anyhow::Context;
+use fast_image_resize::{images::Image, FilterType, ResizeAlg, ResizeOptions, Resizer};
+use fast_image_resize::IntoImageView;
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::{PngDecoder, PngEncoder};
+use image::codecs::webp::WebPDecoder;
+use image::metadata::Orientation;
+use image::{AnimationDecoder, DynamicImage, Frames, ImageDecoder, ImageEncoder, ImageFormat, ImageReader};
+use gcd::Gcd;
+use jxl_oxide::integration::JxlDecoder;
+use rayon::iter::ParallelIterator;
+use rayon::slice::ParallelSliceMut;
+use wpdm_common::anim::AnimHeader;
+use wpdm_common::settings::LimitSettings;
+
+use crate::cache::{write_cache, write_cache_with};
+use crate::color::{convert_from_srgb, convert_to_srgb};
+use crate::effects::Effects;
+use crate::ken_burns::KenBurnsOptions;
+use crate::formats::{
+    avif_dimensions, decode_avif, decode_scaled_jpeg, detect_format, scaled_jpeg_dimensions, Format, Svg,
+};
+use crate::limits::{check_limits, decoder_limits, jpeg_reduction};
+use crate::source::{Source, SourceReader};
+
+/// Animations with more frames than this, or whose frames would take more bytes at the
+/// monitor size, are shown as their first frame. Every frame of the cache entry is kept at
+/// full size, which would otherwise fill the disk for long animations on large monitors.
+const MAX_ANIMATION_FRAMES: usize = 1000;
+const MAX_ANIMATION_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Version of the prepared pixels, part of every cache key. Bumped whenever the same source
+/// and options start producing different pixels, so stale cache entries are not reused.
+const CACHE_VERSION: u32 = 4;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Flip {
+    #[value(name = "h")]
+    Horizontal,
+    #[value(name = "v")]
+    Vertical,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    CatmullRom,
+    #[default]
+    Lanczos3,
+}
+
+impl Filter {
+    fn algorithm(self) -> ResizeAlg {
+        match self {
+            Filter::Nearest => ResizeAlg::Nearest,
+            Filter::Bilinear => ResizeAlg::Convolution(FilterType::Bilinear),
+            Filter::CatmullRom => ResizeAlg::Convolution(FilterType::CatmullRom),
+            Filter::Lanczos3 => ResizeAlg::Convolution(FilterType::Lanczos3),
+        }
+    }
+}
+
+/// Changes made to the image while it is turned into a monitor buffer
+#[derive(clap::Args, Clone, Default)]
+pub struct PrepareOptions {
+    /// Rotate the image clockwise by 90, 180 or 270 degrees, after its EXIF orientation
+    #[arg(long, value_parser = parse_rotate)]
+    pub rotate: Option<u32>,
+
+    /// Mirror the image horizontally (h) or vertically (v), after rotating it
+    #[arg(long, value_enum)]
+    pub flip: Option<Flip>,
+
+    /// Resampling filter. Nearest keeps pixel art sharp
+    #[arg(long, value_enum, default_value_t)]
+    pub filter: Filter,
+
+    /// Sharpen with an unsharp mask of this radius after resizing, e.g. 0.8
+    #[arg(long, value_parser = parse_sharpen)]
+    pub sharpen: Option<f32>,
+
+    #[command(flatten)]
+    pub effects: Effects,
+
+    #[command(flatten)]
+    pub ken_burns: KenBurnsOptions,
+}
+
+fn parse_sharpen(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(radius) if radius > 0.0 && radius.is_finite() => Ok(radius),
+        _ => Err(format!("Sharpen radius must be a positive number, got {}", s)),
+    }
+}
+
+fn parse_rotate(s: &str) -> Result<u32, String> {
+    match s {
+        "90" | "180" | "270" => Ok(s.parse().unwrap()),
+        _ => Err(format!("Rotation must be 90, 180 or 270, got {}", s)),
+    }
+}
+
+impl PrepareOptions {
+    /// Cache key of the prepared image: the source key, the cache version and every option
+    /// that changes the pixels.
+    pub fn cache_key(&self, key: &str) -> String {
+        let mut key = format!("{}#v={}", key, CACHE_VERSION);
+        if let Some(rotate) = self.rotate {
+            key.push_str(&format!("#rotate={}", rotate));
+        }
+        if let Some(flip) = self.flip {
+            key.push_str(&format!("#flip={:?}", flip));
+        }
+        if self.filter != Filter::default() {
+            key.push_str(&format!("#filter={:?}", self.filter));
+        }
+        if let Some(radius) = self.sharpen {
+            key.push_str(&format!("#sharpen={}", radius));
+        }
+        key.push_str(&self.effects.cache_key());
+        key.push_str(&self.ken_burns.cache_key());
+        key
+    }
+
+    pub fn apply(&self, img: DynamicImage) -> DynamicImage {
+        let img = match self.rotate {
+            Some(90) => img.rotate90(),
+            Some(180) => img.rotate180(),
+            Some(270) => img.rotate270(),
+            _ => img,
+        };
+        match self.flip {
+            Some(Flip::Horizontal) => img.fliph(),
+            Some(Flip::Vertical) => img.flipv(),
+            None => img,
+        }
+    }
+
+    /// Dimensions of an upright image once the options are applied
+    fn orient(&self, (width, height): (u32, u32)) -> (u32, u32) {
+        match self.rotate {
+            Some(90) | Some(270) => (height, width),
+            _ => (width, height),
+        }
+    }
+}
+
+/// Frame of an animation, shown for `delay_ms`
+pub struct Frame {
+    pub image: DynamicImage,
+    pub delay_ms: u32,
+}
+
+/// Decoded image, or a vector image that is only rendered once the target size is known
+pub enum SourceImage {
+    Bitmap(DynamicImage),
+    Vector(Svg, PrepareOptions),
+    /// Always has more than one frame, all the same size
+    Animation(Vec<Frame>),
+}
+
+impl SourceImage {
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            SourceImage::Bitmap(img) => (img.width(), img.height()),
+            SourceImage::Vector(svg, options) => options.orient(svg.dimensions()),
+            SourceImage::Animation(frames) => (frames[0].image.width(), frames[0].image.height()),
+        }
+    }
+}
+
+/// Like `decode_image`, but leaves SVGs to be rendered at the size of each monitor, and keeps
+/// every frame of animated GIFs, APNGs and WebPs.
+pub fn open_image(source: &Source, options: &PrepareOptions, limits: &LimitSettings) -> anyhow::Result<SourceImage> {
+    open_image_at(source, options, limits, None)
+}
+
+/// Like `open_image`, but JPEGs are decoded at a reduced size when that still covers `fit`.
+/// Quick enough to redo on every move in a picker.
+pub fn open_thumbnail(source: &Source, fit: (u32, u32), options: &PrepareOptions, limits: &LimitSettings) -> anyhow::Result<SourceImage> {
+    open_image_at(source, options, limits, Some(fit))
+}
+
+fn open_image_at(source: &Source, options: &PrepareOptions, limits: &LimitSettings, fit: Option<(u32, u32)>) -> anyhow::Result<SourceImage> {
+    if source.raw().is_none() {
+        let (reader, format) = open_format(source)?;
+        match format {
+            Format::Svg => return Ok(SourceImage::Vector(Svg::parse(&source.data()?, source.name(), limits)?, options.clone())),
+            Format::Image(format) => {
+                if let Some(frames) = decode_animation(reader, source.name(), format, options, limits)? {
+                    return Ok(SourceImage::Animation(frames));
+                }
+            },
+            _ => {},
+        }
+    }
+    Ok(SourceImage::Bitmap(decode_image(source, options, limits, fit)?))
+}
+
+/// Frames of an animated image, or None when it only has one
+fn decode_animation(reader: impl BufRead + Seek, path: &Path, format: ImageFormat, options: &PrepareOptions, limits: &LimitSettings) -> anyhow::Result<Option<Vec<Frame>>> {
+    let (frames, icc): (Frames, _) = match format {
+        ImageFormat::Gif => {
+            let mut decoder = GifDecoder::new(reader)?;
+            decoder.set_limits(decoder_limits(limits))?;
+            let icc = decoder.icc_profile()?;
+            (decoder.into_frames(), icc)
+        },
+        ImageFormat::Png => {
+            let mut decoder = PngDecoder::new(reader)?;
+            if !decoder.is_apng()? {
+                return Ok(None);
+            }
+            decoder.set_limits(decoder_limits(limits))?;
+            let icc = decoder.icc_profile()?;
+            (decoder.apng()?.into_frames(), icc)
+        },
+        ImageFormat::WebP => {
+            let mut decoder = WebPDecoder::new(reader)?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decoder.set_limits(decoder_limits(limits))?;
+            let icc = decoder.icc_profile()?;
+            (decoder.into_frames(), icc)
+        },
+        _ => return Ok(None),
+    };
+
+    // The decoder limits only cover one frame at a time, all of them are kept
+    let mut total_bytes = 0;
+    let mut decoded = vec![];
+    for frame in frames {
+        let frame = frame?;
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let delay_ms = numer.checked_div(denom).unwrap_or(0);
+        let image = DynamicImage::ImageRgba8(frame.into_buffer());
+        total_bytes += image.as_bytes().len() as u64;
+        check_limits(path, image.width(), image.height(), total_bytes, limits)?;
+        let image = options.apply(convert_to_srgb(image, icc.as_deref())?);
+        decoded.push(Frame { image, delay_ms });
+    }
+    Ok((decoded.len() > 1).then_some(decoded))
+}
+
+/// Opens the source and works out its format from the first bytes
+fn open_format(source: &Source) -> anyhow::Result<(SourceReader<'_>, Format)> {
+    let mut reader = source.reader()?;
+    let mut head = vec![];
+    (&mut reader).take(512).read_to_end(&mut head)?;
+    reader.rewind()?;
+    let format = detect_format(&head, source.name())?;
+    Ok((reader, format))
+}
+
+fn open_decoder<'a>(reader: impl BufRead + Seek + 'a, format: ImageFormat) -> anyhow::Result<impl ImageDecoder + 'a> {
+    let mut reader = ImageReader::with_format(reader, format);
+    // Checked by hand once the header is read, see `limit_reduction`
+    reader.no_limits();
+    Ok(reader.into_decoder()?)
+}
+
+/// JPEG reduction needed to stay within the limits. Fails when the image is over them and
+/// can't be reduced.
+fn limit_reduction(path: &Path, format: ImageFormat, decoder: &impl ImageDecoder, limits: &LimitSettings) -> anyhow::Result<Option<u32>> {
+    let (width, height) = decoder.dimensions();
+    let Err(err) = check_limits(path, width, height, decoder.total_bytes(), limits) else {
+        return Ok(None);
+    };
+    let bytes_per_pixel = decoder.color_type().bytes_per_pixel() as u64;
+    match format {
+        ImageFormat::Jpeg => jpeg_reduction(width, height, bytes_per_pixel, limits).map(Some).ok_or(err),
+        _ => Err(err),
+    }
+}
+
+/// Largest JPEG scale-down that still covers `fit`, whichever way round the image is shown
+fn fit_reduction((width, height): (u32, u32), (fit_width, fit_height): (u32, u32)) -> Option<u32> {
+    let (short, long) = (width.min(height), width.max(height));
+    let (fit_short, fit_long) = (fit_width.min(fit_height), fit_width.max(fit_height));
+    [8, 4, 2].into_iter().find(|reduction| short / reduction >= fit_short && long / reduction >= fit_long)
+}
+
+/// Decodes the image upright, following its EXIF orientation, and applies the options.
+/// SVGs are rendered at their intrinsic size. JPEGs are reduced as far as `fit` allows.
+pub fn decode_image(source: &Source, options: &PrepareOptions, limits: &LimitSettings, fit: Option<(u32, u32)>) -> anyhow::Result<DynamicImage> {
+    let path = source.name();
+    if let Some(raw) = source.raw() {
+        return Ok(options.apply(raw.image(&source.data()?)?));
+    }
+
+    let (mut reader, format) = open_format(source)?;
+    let (img, icc) = match format {
+        Format::Image(format) => {
+            let mut decoder = open_decoder(&mut reader, format)?;
+            let orientation = decoder.orientation()?;
+            let icc = decoder.icc_profile()?;
+            let mut reduction = limit_reduction(path, format, &decoder, limits)?;
+            if let Some(fit) = fit.filter(|_| format == ImageFormat::Jpeg) {
+                reduction = reduction.max(fit_reduction(decoder.dimensions(), fit));
+            }
+            let mut img = match reduction {
+                Some(reduction) => {
+                    drop(decoder);
+                    reader.rewind()?;
+                    decode_scaled_jpeg(reader, reduction)?
+                },
+                None => {
+                    decoder.set_limits(decoder_limits(limits))?;
+                    DynamicImage::from_decoder(decoder)?
+                },
+            };
+            img.apply_orientation(orientation);
+            (img, icc)
+        },
+        Format::JpegXl => {
+            // jxl-oxide already renders the image upright
+            let mut decoder = JxlDecoder::new(reader)?;
+            let (width, height) = decoder.dimensions();
+            check_limits(path, width, height, decoder.total_bytes(), limits)?;
+            decoder.set_limits(decoder_limits(limits))?;
+            let icc = decoder.icc_profile()?;
+            (DynamicImage::from_decoder(decoder)?, icc)
+        },
+        Format::Avif => decode_avif(&source.data()?, path, limits)?,
+        Format::Svg => {
+            (Svg::parse(&source.data()?, path, limits)?.rasterize(1.0)?, None)
+        },
+    };
+    let img = convert_to_srgb(img, icc.as_deref())?;
+    Ok(options.apply(img))
+}
+
+/// Dimensions `decode_image` will return, without decoding the pixels.
+pub fn image_dimensions(source: &Source, options: &PrepareOptions, limits: &LimitSettings) -> anyhow::Result<(u32, u32)> {
+    if let Some(raw) = source.raw() {
+        return Ok(options.orient((raw.width, raw.height)));
+    }
+    let path = source.name();
+    let (mut reader, format) = open_format(source)?;
+    let dimensions = match format {
+        Format::Image(format) => {
+            let mut decoder = open_decoder(&mut reader, format)?;
+            let orientation = decoder.orientation()?;
+            let (width, height) = match limit_reduction(path, format, &decoder, limits)? {
+                Some(reduction) => {
+                    drop(decoder);
+                    reader.rewind()?;
+                    scaled_jpeg_dimensions(reader, reduction)?
+                },
+                None => decoder.dimensions(),
+            };
+            let exif_swaps = matches!(
+                orientation,
+                Orientation::Rotate90 | Orientation::Rotate270 | Orientation::Rotate90FlipH | Orientation::Rotate270FlipH
+            );
+            if exif_swaps { (height, width) } else { (width, height) }
+        },
+        Format::JpegXl => JxlDecoder::new(reader)?.dimensions(),
+        Format::Avif => avif_dimensions(&source.data()?, limits)?,
+        Format::Svg => Svg::parse(&source.data()?, path, limits)?.dimensions(),
+    };
+    Ok(options.orient(dimensions))
+}
+
+/// Smallest difference from the blurred image that gets sharpened, so flat areas keep their noise level
+const SHARPEN_THRESHOLD: i32 = 2;
+
+/// Region of the source image as `(left, top, width, height)`
+pub type Crop = (f64, f64, f64, f64);
+
+/// Cache key of a buffer prepared for a monitor with the given ICC profile. Like image files,
+/// the profile is keyed by its modification time and size too, so editing it rebuilds the
+/// buffers. A profile that can't be read is keyed by path, converting to it reports the error.
+pub fn profile_cache_key(key: &str, target_profile: Option<&Path>) -> String {
+    let Some(profile) = target_profile else {
+        return key.to_string();
+    };
+    let mut key = format!("{}#icc={}", key, profile.display());
+    if let Ok(meta) = profile.metadata() {
+        let modified = meta.modified().ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        key = format!("{}#mtime={}#size={}", key, modified.as_nanos(), meta.len());
+    }
+    key
+}
+
+pub fn build_bgra_buffer(src: &SourceImage, width: u32, height: u32, options: &PrepareOptions, target_profile: Option<&Path>, cache_path: &Path) -> anyhow::Result<()> {
+    let (img_width, img_height) = src.dimensions();
+    let (left, top, rwidth, rheight) = get_crop_params(width, height, img_width, img_height);
+    let crop = (left as f64, top as f64, rwidth as f64, rheight as f64);
+    build_cropped_bgra_buffer(src, width, height, crop, options, target_profile, cache_path)
+}
+
+/// Animations are written as an `AnimHeader` followed by every frame, Ken Burns wallpapers as
+/// a `KenBurnsHeader` followed by the larger source
+pub fn build_cropped_bgra_buffer(src: &SourceImage, width: u32, height: u32, crop: Crop, options: &PrepareOptions, target_profile: Option<&Path>, cache_path: &Path) -> anyhow::Result<()> {
+    if options.ken_burns.ken_burns {
+        let header = options.ken_burns.header(width, height);
+        let source = match src {
+            SourceImage::Animation(_) => anyhow::bail!("--ken-burns needs a still image"),
+            _ => render_source(src, header.src_width, header.src_height, crop, options, target_profile)?,
+        };
+        return write_cache_with(cache_path, |writer| {
+            header.write(writer)?;
+            writer.write_all(&source)?;
+            Ok(())
+        });
+    }
+
+    match src {
+        SourceImage::Animation(frames) if !animation_fits(frames.len(), width, height) => {
+            eprintln!(
+                "Animation of {} frames is too large to play at {}x{}, showing its first frame",
+                frames.len(), width, height
+            );
+            write_cache(cache_path, &render_source(src, width, height, crop, options, target_profile)?)
+        },
+        SourceImage::Animation(frames) => {
+            let header = AnimHeader {
+                width,
+                height,
+                delays_ms: frames.iter().map(|frame| frame.delay_ms).collect(),
+            };
+            write_cache_with(cache_path, |writer| {
+                header.write(writer)?;
+                for frame in frames {
+                    writer.write_all(&render_bgra_buffer(&frame.image, width, height, crop, options, target_profile)?)?;
+                }
+                Ok(())
+            })
+        },
+        _ => write_cache(cache_path, &render_source(src, width, height, crop, options, target_profile)?),
+    }
+}
+
+fn animation_fits(frames: usize, width: u32, height: u32) -> bool {
+    let bytes = width as u64 * height as u64 * 4 * frames as u64;
+    frames <= MAX_ANIMATION_FRAMES && bytes <= MAX_ANIMATION_BYTES
+}
+
+/// BGRA buffer of a still image, the first frame of animations
+pub fn render_source(src: &SourceImage, width: u32, height: u32, crop: Crop, options: &PrepareOptions, target_profile: Option<&Path>) -> anyhow::Result<Vec<u8>> {
+    match src {
+        SourceImage::Bitmap(img) => render_bgra_buffer(img, width, height, crop, options, target_profile),
+        SourceImage::Vector(svg, svg_options) => {
+            // Render so the cropped region comes out at the monitor resolution
+            let (left, top, rwidth, rheight) = crop;
+            let scale = (width as f64 / rwidth).max(height as f64 / rheight);
+            let rendered = svg_options.apply(svg.rasterize(scale as f32)?);
+            let (img_width, img_height) = svg_options.orient(svg.dimensions());
+            let scale_x = rendered.width() as f64 / img_width as f64;
+            let scale_y = rendered.height() as f64 / img_height as f64;
+            let crop = (left * scale_x, top * scale_y, rwidth * scale_x, rheight * scale_y);
+            render_bgra_buffer(&rendered, width, height, crop, options, target_profile)
+        },
+        SourceImage::Animation(frames) => render_bgra_buffer(&frames[0].image, width, height, crop, options, target_profile),
+    }
+}
+
+fn render_bgra_buffer(img: &DynamicImage, width: u32, height: u32, crop: Crop, options: &PrepareOptions, target_profile: Option<&Path>) -> anyhow::Result<Vec<u8>> {
+    let mut dst_image = Image::new(width, height, img.pixel_type().context("Image does not have pixel type")?);
+    let mut resizer = Resizer::new();
+    let (left, top, rwidth, rheight) = crop;
+
+    let resize_options = ResizeOptions::new()
+        .crop(left, top, rwidth, rheight)
+        .resize_alg(options.filter.algorithm());
+    resizer.resize(img, &mut dst_image, &resize_options)?;
+
+
+    let mut result_buf = BufWriter::new(Vec::new());
+    PngEncoder::new(&mut result_buf)
+        .write_image(
+            dst_image.buffer(),
+            width,
+            height,
+            img.color().into(),
+        )
+        .unwrap();
+    let png_image = result_buf.into_inner()?;
+    let png_image = Cursor::new(png_image);
+    let image = image::ImageReader::new(png_image);
+    let dimage = image.with_guessed_format()?.decode()?;
+    let mut rgba = dimage.into_rgba8();
+    if let Some(radius) = options.sharpen {
+        rgba = image::imageops::unsharpen(&rgba, radius, SHARPEN_THRESHOLD);
+    }
+    let rgba = options.effects.apply(rgba);
+    let mut image_vec = rgba.into_vec();
+    if let Some(profile) = target_profile {
+        convert_from_srgb(&mut image_vec, profile)?;
+    }
+    image_vec
+        .par_chunks_exact_mut(4)
+        .for_each(|buff| {
+            let r = buff[0];
+            let g = buff[1];
+            let b = buff[2];
+            let a = buff[3];
+            buff.copy_from_slice(&[b, g, r, a]);
+        });
+
+    Ok(image_vec)
+}
+
+pub fn get_crop_params(mon_width: u32, mon_height: u32, img_width: u32, img_height: u32) -> (u32, u32, u32, u32) {
+        let gcd = mon_width.gcd(mon_height);
+        let mon_ar_width = mon_width / gcd;
+        let mon_ar_height = mon_height / gcd;
+
+        let gcd = img_width.gcd(img_height);
+        let img_ar_width = img_width / gcd;
+        let img_ar_height = img_height / gcd;
+
+        let is_wide = img_ar_width * mon_ar_height >= mon_ar_width * img_ar_height;
+
+        let ar_equals = (mon_ar_width == img_ar_width) && (mon_ar_height == img_ar_height);
+
+        if is_wide && !ar_equals {
+            let width = (img_height * mon_ar_width) / mon_ar_height;
+            let height = img_height;
+
+            let x = img_width / 2 - width / 2;
+            let y = 0;
+            return (x, y, width, height);
+        } else if !ar_equals {
+            let width = img_width;
+            let height = (img_width * mon_ar_height) / mon_ar_width;
+
+            let x = 0;
+            let y = img_height / 2 - height / 2;
+            return (x, y, width, height)
+        }
+
+        (0, 0, img_width, img_height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewritten_profile_changes_the_cache_key() {
+        let profile = std::env::temp_dir().join(format!("wpdm-profile-{}.icc", std::process::id()));
+        std::fs::write(&profile, b"first").unwrap();
+        let first = profile_cache_key("image", Some(&profile));
+        std::fs::write(&profile, b"second profile").unwrap();
+        let second = profile_cache_key("image", Some(&profile));
+        std::fs::remove_file(&profile).unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(profile_cache_key("image", None), "image");
+    }
+
+    #[test]
+    fn fit_reduction_keeps_the_image_covering_the_fit() {
+        assert_eq!(fit_reduction((4000, 3000), (1200, 800)), Some(2));
+        assert_eq!(fit_reduction((3000, 4000), (1200, 800)), Some(2));
+        assert_eq!(fit_reduction((4000, 3000), (400, 300)), Some(8));
+        assert_eq!(fit_reduction((1000, 800), (800, 600)), None);
+    }
+}
@@ -0,0 +1,156 @@
+use std::path::PathBuf;
+use anyhow::Context;
+use image::RgbaImage;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use wpdm_common::settings::load_settings;
+use wpdm_common::{WpdmClient, WpdmMonitor, WpdmTransform};
+
+use crate::error::{print_json, CliError};
+use crate::prepare::{get_crop_params, open_image, render_source, PrepareOptions};
+use crate::preload::parse_size;
+use crate::set::{check_raw, image_jobs, open_source};
+use crate::source::{parse_raw, RawFormat};
+use crate::span::{self, Bezel};
+
+#[derive(clap::Args)]
+pub struct PreviewArgs {
+    /// Image to preview, - reads it from stdin
+    image: String,
+
+    /// PNG to write the preview to
+    #[arg(short, long, default_value = "preview.png")]
+    out: PathBuf,
+
+    /// Preview for an output of WIDTHxHEIGHT instead of the connected monitors. Can be
+    /// repeated, the outputs are laid out left to right
+    #[arg(long, value_parser = parse_size, value_name = "WxH")]
+    size: Vec<(i32, i32)>,
+
+    /// Stretch the image across every output, like set --span
+    #[arg(long)]
+    span: bool,
+
+    /// Space hidden behind the bezels when spanning, like set --bezel
+    #[arg(long, value_parser = span::parse_bezel, default_value = "0", requires = "span")]
+    bezel: Bezel,
+
+    /// Read raw pixels from stdin, like set --raw
+    #[arg(long, value_parser = parse_raw, value_name = "WxH:FORMAT")]
+    raw: Option<RawFormat>,
+
+    #[command(flatten)]
+    prepare: PrepareOptions,
+}
+
+/// Where an output ended up in the preview
+#[derive(serde::Serialize)]
+struct Placed {
+    monitor: String,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+/// Stand-ins for `--size`, named after their size
+fn sized_monitors(sizes: &[(i32, i32)]) -> Vec<WpdmMonitor> {
+    let mut x = 0;
+    sizes.iter()
+        .map(|(width, height)| {
+            let mon = WpdmMonitor {
+                name: format!("{}x{}", width, height),
+                width: *width,
+                height: *height,
+                x,
+                y: 0,
+                transform: WpdmTransform::Normal,
+            };
+            x += width;
+            mon
+        })
+        .collect()
+}
+
+/// Renders what `set` would show on every output into one PNG, placed by the logical
+/// positions of the outputs. Colour profiles are left out, the PNG is viewed as sRGB. The
+/// daemon is only asked for its monitors, and not at all with `--size`.
+pub fn run(args: PreviewArgs, json: bool) -> anyhow::Result<()> {
+    let settings = load_settings()?;
+    let mut stdin = None;
+    let source = open_source(&args.image, args.raw, &settings.limits, &mut stdin)?;
+    check_raw(args.raw, &stdin)?;
+
+    let monitors = if args.size.is_empty() {
+        WpdmClient::new()?.get_monitors()?
+    } else {
+        sized_monitors(&args.size)
+    };
+    if monitors.is_empty() {
+        return Err(CliError::NoMatch("No monitors to preview".to_string()).into());
+    }
+
+    let span = args.span.then_some(args.bezel);
+    let jobs = image_jobs(&source, monitors.clone(), span, &args.prepare, &settings)?;
+    let img = open_image(&source, &args.prepare, &settings.limits)
+        .with_context(|| CliError::Decode(source.name().display().to_string()))?;
+
+    let img = &img;
+    let prepare = &args.prepare;
+    let rendered = jobs.into_par_iter()
+        .map(|job| {
+            let (width, height) = (job.width as u32, job.height as u32);
+            let crop = job.crop.unwrap_or_else(|| {
+                let (img_width, img_height) = img.dimensions();
+                let (left, top, rwidth, rheight) = get_crop_params(width, height, img_width, img_height);
+                (left as f64, top as f64, rwidth as f64, rheight as f64)
+            });
+            let buffer = render_source(img, width, height, crop, prepare, None)?;
+            anyhow::Ok((job.monitors, width, height, buffer))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let min_x = monitors.iter().map(|mon| mon.x).min().unwrap_or(0);
+    let min_y = monitors.iter().map(|mon| mon.y).min().unwrap_or(0);
+    let mut placed = vec![];
+    let mut tiles = vec![];
+    for (names, width, height, buffer) in rendered.iter() {
+        for mon in monitors.iter().filter(|mon| names.contains(&mon.name)) {
+            placed.push(Placed {
+                monitor: mon.name.clone(),
+                x: (mon.x - min_x) as u32,
+                y: (mon.y - min_y) as u32,
+                width: *width,
+                height: *height,
+            });
+            tiles.push(buffer);
+        }
+    }
+
+    let canvas_width = placed.iter().map(|tile| tile.x + tile.width).max().unwrap_or(0);
+    let canvas_height = placed.iter().map(|tile| tile.y + tile.height).max().unwrap_or(0);
+    // Gaps between outputs stay transparent
+    let mut canvas = RgbaImage::new(canvas_width, canvas_height);
+    for (tile, buffer) in placed.iter().zip(tiles) {
+        for (idx, pixel) in buffer.chunks_exact(4).enumerate() {
+            let (x, y) = (idx as u32 % tile.width, idx as u32 / tile.width);
+            canvas.put_pixel(tile.x + x, tile.y + y, image::Rgba([pixel[2], pixel[1], pixel[0], pixel[3]]));
+        }
+    }
+    canvas.save(&args.out)
+        .with_context(|| format!("Failed to write {}", args.out.display()))?;
+
+    placed.sort_by_key(|tile| (tile.y, tile.x));
+    if json {
+        return print_json(&serde_json::json!({
+            "out": args.out,
+            "width": canvas_width,
+            "height": canvas_height,
+            "monitors": placed,
+        }));
+    }
+    for tile in placed.iter() {
+        println!("{}\t{}x{}\t+{}+{}", tile.monitor, tile.width, tile.height, tile.x, tile.y);
+    }
+    eprintln!("Wrote {} ({}x{})", args.out.display(), canvas_width, canvas_height);
+    Ok(())
+}
@@ -0,0 +1,465 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use anyhow::Context;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use wpdm_common::settings::{load_settings, LimitSettings, Settings};
+use wpdm_common::{WpdmClient, WpdmMonitor, WpdmSetWallpaper, WpdmTransition};
+
+use crate::cache::{cache_exists, cache_path, get_cache_name};
+use crate::prepare::{
+    build_bgra_buffer, build_cropped_bgra_buffer, image_dimensions, open_image, profile_cache_key, Crop,
+    PrepareOptions,
+};
+use crate::current;
+use crate::error::{print_json, CliError};
+use crate::fit;
+use crate::palette::{self, PaletteFormat};
+use crate::preload::parse_size;
+use crate::source::{parse_raw, RawFormat, Source};
+use crate::span::{self, Bezel, SpanLayout};
+use crate::watch;
+
+#[derive(clap::ValueEnum, Clone, Copy, Default)]
+pub enum Transition {
+    /// A circle growing from the centre
+    #[default]
+    Grow,
+    Fade,
+}
+
+impl From<Transition> for WpdmTransition {
+    fn from(transition: Transition) -> Self {
+        match transition {
+            Transition::Grow => WpdmTransition::GrowCircle,
+            Transition::Fade => WpdmTransition::Fade,
+        }
+    }
+}
+
+#[derive(clap::Args)]
+pub struct SetOptions {
+    /// Stretch one image across every monitor, following the output layout
+    #[arg(long)]
+    span: bool,
+
+    /// Space hidden behind the bezels when spanning, in logical pixels. Either N or
+    /// HORIZONTALxVERTICAL
+    #[arg(long, value_parser = span::parse_bezel, default_value = "0", requires = "span")]
+    bezel: Bezel,
+
+    /// Read raw pixels instead of an encoded image from stdin, e.g. 1920x1080:rgba8. Formats
+    /// are rgb8, rgba8, bgr8, bgra8 and gray8
+    #[arg(long, value_parser = parse_raw, value_name = "WxH:FORMAT")]
+    raw: Option<RawFormat>,
+
+    /// How the new wallpaper replaces the old one
+    #[arg(long, value_enum, default_value_t)]
+    transition: Transition,
+
+    /// Print the colour palette of the new wallpapers as hex, json or through a template
+    /// file, like the palette command
+    #[arg(long, value_parser = palette::parse_format, value_name = "FORMAT")]
+    palette: Option<PaletteFormat>,
+
+    /// Number of colours in the palette
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u8).range(1..=64), requires = "palette")]
+    palette_colors: u8,
+
+    #[command(flatten)]
+    pub prepare: PrepareOptions,
+}
+
+#[derive(clap::Args)]
+pub struct SetArgs {
+    /// Image to show, either PATH for every monitor or MONITOR=PATH for a single one. A PATH
+    /// of - reads the image from stdin
+    #[arg(required_unless_present = "from_dir")]
+    images: Vec<String>,
+
+    /// Give each monitor the image in this directory closest to its aspect ratio and
+    /// resolution
+    #[arg(long, conflicts_with = "images", value_name = "DIR")]
+    from_dir: Option<PathBuf>,
+
+    /// Leave out images smaller than WIDTHxHEIGHT with --from-dir, either way round
+    #[arg(long, value_parser = parse_size, value_name = "WxH", requires = "from_dir")]
+    min_resolution: Option<(i32, i32)>,
+
+    /// Keep running and set the images again whenever their files are rewritten, e.g. by a
+    /// tool that renders a weather map
+    #[arg(long, conflicts_with = "from_dir")]
+    watch: bool,
+
+    /// Only change this monitor. Can be repeated
+    #[arg(short, long)]
+    monitor: Vec<String>,
+
+    #[command(flatten)]
+    options: SetOptions,
+}
+
+/// One buffer to prepare, and the monitors that will show it
+pub struct Job {
+    pub source: Source,
+    pub cache_key: String,
+    pub width: i32,
+    pub height: i32,
+    pub crop: Option<Crop>,
+    pub target_profile: Option<PathBuf>,
+    pub monitors: Vec<String>,
+}
+
+impl SetOptions {
+    /// Bezel to span with, None when each monitor gets the whole image
+    pub fn span_bezel(&self) -> Option<Bezel> {
+        self.span.then_some(self.bezel)
+    }
+
+    /// Whether the palette of the new wallpapers gets printed
+    pub fn prints_palette(&self) -> bool {
+        self.palette.is_some()
+    }
+}
+
+/// A wallpaper that was handed to the daemon
+#[derive(serde::Serialize)]
+pub struct Applied {
+    /// Image it was made from, or the fill of a generated one
+    pub image: String,
+    pub monitors: Vec<String>,
+    pub cache: PathBuf,
+}
+
+impl Applied {
+    pub fn new(image: String, wallpaper: &WpdmSetWallpaper) -> Self {
+        Applied { image, monitors: wallpaper.monitors.clone(), cache: PathBuf::from(&wallpaper.path) }
+    }
+}
+
+/// Sets a single image on every monitor, sending each size as soon as it is ready
+pub fn set_wallpaper(image_path: &str, options: &SetOptions, json: bool) -> anyhow::Result<()> {
+    let mut client = WpdmClient::new()?;
+    let settings = load_settings()?;
+    let mut stdin = None;
+    let source = open_source(image_path, options.raw, &settings.limits, &mut stdin)?;
+    check_raw(options.raw, &stdin)?;
+
+    let monitors = client.get_monitors()?;
+    let jobs = image_jobs(&source, monitors, options.span_bezel(), &options.prepare, &settings)?;
+
+    let applied = apply_jobs(&mut client, jobs, options, &settings.limits, false)?;
+    finish(&applied, options, json)
+}
+
+pub fn run(args: SetArgs, json: bool) -> anyhow::Result<()> {
+    let mut client = WpdmClient::new()?;
+    let settings = load_settings()?;
+    let monitors = client.get_monitors()?;
+    let is_monitor = |name: &str| monitors.iter().any(|mon| mon.name == name);
+
+    if let Some(name) = args.monitor.iter().find(|name| !is_monitor(name)) {
+        return Err(CliError::UnknownMonitor(name.clone()).into());
+    }
+    let selected = |name: &str| args.monitor.is_empty() || args.monitor.iter().any(|mon| mon == name);
+
+    let mut stdin = None;
+    let mut default_image = None;
+    let mut assigned = HashMap::<String, Source>::new();
+    for image in args.images.iter() {
+        match image.split_once('=') {
+            Some((name, path)) if is_monitor(name) => {
+                if !selected(name) {
+                    anyhow::bail!("Monitor {} is not one of the --monitor filters", name);
+                }
+                assigned.insert(name.to_string(), open_source(path, args.options.raw, &settings.limits, &mut stdin)?);
+            },
+            Some((name, _)) if !Path::new(image).exists() => {
+                return Err(CliError::UnknownMonitor(name.to_string()).into());
+            },
+            _ if default_image.is_some() => {
+                anyhow::bail!("Only one image can be given without a monitor name");
+            },
+            _ => default_image = Some(open_source(image, args.options.raw, &settings.limits, &mut stdin)?),
+        }
+    }
+    check_raw(args.options.raw, &stdin)?;
+
+    if let Some(dir) = &args.from_dir {
+        if args.options.span {
+            anyhow::bail!("--span takes a single image for every monitor");
+        }
+        let candidates = fit::candidates(dir, args.min_resolution, &args.options.prepare, &settings.limits)?;
+        for mon in monitors.iter().filter(|mon| selected(&mon.name)) {
+            let best = fit::best_fit(&candidates, mon).with_context(|| CliError::NoMatch(match args.min_resolution {
+                Some((width, height)) => format!("No images of at least {}x{} in {}", width, height, dir.display()),
+                None => format!("No images in {}", dir.display()),
+            }))?;
+            eprintln!("{}: {} ({}x{})", mon.name, best.path.display(), best.width, best.height);
+            assigned.insert(mon.name.clone(), Source::Path(best.path.clone()));
+        }
+    }
+
+    let mut by_image = BTreeMap::<Source, Vec<WpdmMonitor>>::new();
+    for mon in monitors.iter().filter(|mon| selected(&mon.name)) {
+        let Some(source) = assigned.get(&mon.name).or(default_image.as_ref()) else {
+            continue;
+        };
+        by_image.entry(source.clone()).or_default().push(mon.clone());
+    }
+
+    if args.options.span && by_image.len() > 1 {
+        anyhow::bail!("--span takes a single image for every monitor");
+    }
+
+    let watched = if args.watch {
+        by_image.keys()
+            .map(|source| match source {
+                Source::Path(path) => Ok(path.clone()),
+                Source::Stdin { .. } => anyhow::bail!("--watch needs image files, stdin can't be watched"),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?
+    } else {
+        vec![]
+    };
+
+    // Jobs are worked out again on every run, a rewritten file has a new cache key
+    let mut set_images = || {
+        let mut jobs = vec![];
+        for (source, monitors) in by_image.iter() {
+            jobs.extend(image_jobs(source, monitors.clone(), args.options.span_bezel(), &args.options.prepare, &settings)?);
+        }
+        let applied = apply_jobs(&mut client, jobs, &args.options, &settings.limits, true)?;
+        finish(&applied, &args.options, json)?;
+        Ok(applied.into_iter().map(|applied| applied.cache).collect())
+    };
+
+    let cache_paths = set_images()?;
+    if args.watch {
+        watch::watch_files(&watched, cache_paths, set_images)?;
+    }
+    Ok(())
+}
+
+/// Reads the arguments of `set` for commands that set images on their behalf
+#[derive(clap::Parser)]
+#[command(name = "set")]
+struct SetCommand {
+    #[command(flatten)]
+    args: SetArgs,
+}
+
+/// Sets `images` with the default options, like `set` given only these images
+pub fn run_images(images: Vec<String>, json: bool) -> anyhow::Result<()> {
+    let args = ["set".to_string(), "--".to_string()].into_iter().chain(images);
+    run(<SetCommand as clap::Parser>::try_parse_from(args)?.args, json)
+}
+
+/// Prints the palette, in the JSON output with `--json`
+fn finish(applied: &[Applied], options: &SetOptions, json: bool) -> anyhow::Result<()> {
+    let cache_paths = applied.iter().map(|applied| applied.cache.clone()).collect::<Vec<_>>();
+    let colors = options.palette_colors as usize;
+    let palette = match &options.palette {
+        Some(format) if !json => {
+            palette::print_cache_palette(&cache_paths, colors, format)?;
+            None
+        },
+        Some(_) => Some(palette::palette_json(&palette::cache_palette(&cache_paths, colors)?)),
+        None => None,
+    };
+    report(applied, palette, json)
+}
+
+/// Remembers what was set for `current`, and prints it with `--json`
+pub fn report(applied: &[Applied], palette: Option<serde_json::Value>, json: bool) -> anyhow::Result<()> {
+    current::save_current(applied)?;
+    if json {
+        #[derive(serde::Serialize)]
+        struct Output<'a> {
+            wallpapers: &'a [Applied],
+            #[serde(skip_serializing_if = "Option::is_none")]
+            palette: Option<serde_json::Value>,
+        }
+        print_json(&Output { wallpapers: applied, palette })?;
+    }
+    Ok(())
+}
+
+/// Only used to check the options given to the daemon for its own `set` runs. The daemon
+/// picks the images and monitors itself, and waits for every run to finish, so only
+/// `SetOptions` are accepted, not `--watch`, `--from-dir` or `--monitor`.
+#[derive(clap::Parser)]
+#[command(name = "set")]
+struct SetCheck {
+    #[command(flatten)]
+    options: SetOptions,
+}
+
+/// Fails with clap's message when `args` aren't valid options for `set`
+pub fn check_set_args(args: &[String]) -> anyhow::Result<()> {
+    let check = ["set"].into_iter().chain(args.iter().map(String::as_str));
+    if let Err(err) = <SetCheck as clap::Parser>::try_parse_from(check) {
+        let err = err.to_string();
+        let reason = err.lines().next().unwrap_or_default().trim_start_matches("error: ");
+        return Err(CliError::Usage(format!("Invalid options for set after --: {}", reason)).into());
+    }
+    Ok(())
+}
+
+/// Stdin can only be read once, so every - shares the first read
+pub fn open_source(path: &str, raw: Option<RawFormat>, limits: &LimitSettings, stdin: &mut Option<Source>) -> anyhow::Result<Source> {
+    if path == "-" {
+        if stdin.is_none() {
+            *stdin = Some(Source::read_stdin(raw, limits)?);
+        }
+        return Ok(stdin.clone().unwrap());
+    }
+    let path = Path::new(path).canonicalize()
+        .with_context(|| format!("Cannot open {}", path))?;
+    Ok(Source::Path(path))
+}
+
+pub fn check_raw(raw: Option<RawFormat>, stdin: &Option<Source>) -> anyhow::Result<()> {
+    if raw.is_some() && stdin.is_none() {
+        anyhow::bail!("--raw describes the pixels read from stdin, pass - as the image");
+    }
+    Ok(())
+}
+
+pub fn image_jobs(source: &Source, monitors: Vec<WpdmMonitor>, span: Option<Bezel>, prepare: &PrepareOptions, settings: &Settings) -> anyhow::Result<Vec<Job>> {
+    match span {
+        Some(bezel) => span_jobs(source, &monitors, bezel, prepare, settings),
+        None => size_jobs(source, monitors, prepare, settings),
+    }
+}
+
+fn size_jobs(source: &Source, monitors: Vec<WpdmMonitor>, prepare: &PrepareOptions, settings: &Settings) -> anyhow::Result<Vec<Job>> {
+    let source_key = source.cache_key()?;
+    // Monitors share a buffer when both their size and colour profile match
+    let sizes = monitors.into_iter()
+        .fold(HashMap::<(i32, i32, Option<PathBuf>), Vec<String>>::new(), |mut init, nxt| {
+        let profile = settings.color.monitor_profile(&nxt.name).map(Path::to_path_buf);
+        if let Some(monitors) = init.get_mut(&(nxt.width, nxt.height, profile.clone())) {
+            monitors.push(nxt.name);
+        } else {
+            init.insert((nxt.width, nxt.height, profile), vec![nxt.name]);
+        }
+        init
+    });
+
+    let jobs = sizes.into_iter()
+        .map(|((width, height, target_profile), monitors)| Job {
+            source: source.clone(),
+            cache_key: profile_cache_key(&prepare.cache_key(&source_key), target_profile.as_deref()),
+            width,
+            height,
+            crop: None,
+            target_profile,
+            monitors,
+        })
+        .collect();
+    Ok(jobs)
+}
+
+fn span_jobs(source: &Source, monitors: &[WpdmMonitor], bezel: Bezel, prepare: &PrepareOptions, settings: &Settings) -> anyhow::Result<Vec<Job>> {
+    let source_key = source.cache_key()?;
+    let layout = SpanLayout::new(monitors, bezel);
+    let (img_width, img_height) = image_dimensions(source, prepare, &settings.limits)?;
+
+    let jobs = layout.slices.iter()
+        .map(|slice| {
+            let target_profile = settings.color.monitor_profile(&slice.monitor);
+            let cache_key = layout.cache_key(&prepare.cache_key(&source_key), slice);
+            Job {
+                source: source.clone(),
+                cache_key: profile_cache_key(&cache_key, target_profile),
+                width: slice.width,
+                height: slice.height,
+                crop: Some(layout.crop(slice, img_width, img_height)),
+                target_profile: target_profile.map(Path::to_path_buf),
+                monitors: vec![slice.monitor.clone()],
+            }
+        })
+        .collect();
+    Ok(jobs)
+}
+
+/// Builds missing cache entries and hands them to the daemon. When `atomic` is set every
+/// wallpaper goes out in a single request once all of them are ready, otherwise each one is
+/// sent as soon as its buffer is written.
+fn apply_jobs(client: &mut WpdmClient, jobs: Vec<Job>, options: &SetOptions, limits: &LimitSettings, atomic: bool) -> anyhow::Result<Vec<Applied>> {
+    let prepare = &options.prepare;
+    let transition = options.transition.into();
+    let mut applied = vec![];
+    let mut ready = vec![];
+    let mut pending = BTreeMap::<Source, Vec<(Job, PathBuf)>>::new();
+    for job in jobs {
+        let cache_name = get_cache_name(&job.cache_key, job.width, job.height)?;
+        let cache_path = cache_path(&cache_name)?;
+
+        if !cache_exists(&cache_name) {
+            pending.entry(job.source.clone()).or_default().push((job, cache_path));
+            continue;
+        }
+        let wallpaper = wallpaper(&cache_path, job.monitors, transition)?;
+        applied.push(Applied::new(job.source.name().display().to_string(), &wallpaper));
+        if atomic {
+            ready.push(wallpaper);
+        } else {
+            client.set_wallpaper(wallpaper)?;
+        }
+    }
+
+    // Decode each image once, then resize for every size in parallel
+    let (tx, rx) = mpsc::channel();
+    std::thread::scope(|scope| {
+        scope.spawn(move || {
+            pending.into_par_iter()
+                .for_each_with(tx, |tx, (source, jobs)| {
+                    let img = match open_image(&source, prepare, limits) {
+                        Ok(img) => img,
+                        Err(err) => {
+                            let _ = tx.send(Err(err.context(CliError::Decode(source.name().display().to_string()))));
+                            return;
+                        }
+                    };
+                    let img = &img;
+                    jobs.into_par_iter()
+                        .for_each_with(tx.clone(), |tx, (job, cache_path)| {
+                            let (width, height) = (job.width as u32, job.height as u32);
+                            let target_profile = job.target_profile.as_deref();
+                            let result = match job.crop {
+                                Some(crop) => build_cropped_bgra_buffer(img, width, height, crop, prepare, target_profile, &cache_path),
+                                None => build_bgra_buffer(img, width, height, prepare, target_profile, &cache_path),
+                            };
+                            let image = job.source.name().display().to_string();
+                            let _ = tx.send(result.map(|_| (image, cache_path, job.monitors)));
+                        });
+                });
+        });
+
+        for result in rx {
+            let (image, cache_path, monitors) = result?;
+            let wallpaper = wallpaper(&cache_path, monitors, transition)?;
+            applied.push(Applied::new(image, &wallpaper));
+            if atomic {
+                ready.push(wallpaper);
+            } else {
+                client.set_wallpaper(wallpaper)?;
+            }
+        }
+        anyhow::Ok(())
+    })?;
+
+    if atomic && !ready.is_empty() {
+        client.set_wallpapers(ready)?;
+    }
+    Ok(applied)
+}
+
+pub fn wallpaper(cache_path: &Path, monitors: Vec<String>, transition: WpdmTransition) -> anyhow::Result<WpdmSetWallpaper> {
+    let path = cache_path.canonicalize()?;
+
+    let path = path.to_str().context("Cannot convert path to string")?.to_string();
+    Ok(WpdmSetWallpaper { path, monitors, transition })
+}
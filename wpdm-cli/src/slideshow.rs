@@ -0,0 +1,222 @@
+use std::path::{Path, PathBuf};
+use anyhow::Context;
+use wpdm_common::slideshow::{SlideshowOrder, WpdmSlideshow, WpdmSlideshowStatus};
+use wpdm_common::{WpdmClient, WpdmMessage};
+
+use crate::error::{print_json, CliError};
+use crate::formats::{is_supported_path, supported_extensions};
+use crate::set::check_set_args;
+
+#[derive(clap::Args)]
+pub struct SlideshowArgs {
+    #[command(subcommand)]
+    command: SlideshowCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum SlideshowCommand {
+    /// Cycle through the images in a directory, replacing any running slideshow
+    Start(StartArgs),
+    /// Stop the slideshow, the current wallpaper stays up
+    Stop,
+    /// Show the next image now
+    Skip,
+    /// Print the directory, position and time until the next change
+    Status,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Default)]
+enum Order {
+    #[default]
+    Name,
+    /// Oldest first
+    Modified,
+}
+
+#[derive(clap::Args)]
+struct StartArgs {
+    /// Directory of images
+    dir: PathBuf,
+
+    /// Time between changes, e.g. 90s, 10m or 1h30m
+    #[arg(short, long, default_value = "10m", value_parser = parse_interval)]
+    interval: u64,
+
+    /// Include images in subdirectories
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Random order, every image is shown once before any repeats
+    #[arg(short, long)]
+    shuffle: bool,
+
+    /// Order of the images without --shuffle
+    #[arg(long, value_enum, default_value_t, conflicts_with = "shuffle")]
+    order: Order,
+
+    /// Reverse the order
+    #[arg(long, conflicts_with = "shuffle")]
+    reverse: bool,
+
+    /// Give each monitor its own image instead of the same one everywhere
+    #[arg(long)]
+    per_monitor: bool,
+
+    /// Options for every image, as for the set command, e.g. -- --blur 4
+    #[arg(last = true)]
+    set_args: Vec<String>,
+}
+
+fn parse_interval(s: &str) -> Result<u64, String> {
+    let err = || format!("Expected an interval such as 90s, 10m or 1h30m, got {}", s);
+    if let Ok(secs) = s.parse::<u64>() {
+        return if secs > 0 { Ok(secs) } else { Err(err()) };
+    }
+    let mut secs = 0u64;
+    let mut number = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(err()),
+        };
+        let value = std::mem::take(&mut number).parse::<u64>().map_err(|_| err())?;
+        secs = value.checked_mul(unit)
+            .and_then(|value| secs.checked_add(value))
+            .ok_or_else(err)?;
+    }
+    if !number.is_empty() || secs == 0 {
+        return Err(err());
+    }
+    Ok(secs)
+}
+
+fn format_interval(secs: u64) -> String {
+    let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+    let mut out = String::new();
+    if hours > 0 {
+        out.push_str(&format!("{}h", hours));
+    }
+    if minutes > 0 {
+        out.push_str(&format!("{}m", minutes));
+    }
+    if seconds > 0 || out.is_empty() {
+        out.push_str(&format!("{}s", seconds));
+    }
+    out
+}
+
+fn has_images(dir: &Path, recursive: bool) -> anyhow::Result<bool> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() && recursive && has_images(&path, recursive)? {
+            return Ok(true);
+        }
+        if path.is_file() && is_supported_path(&path) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+pub fn run(args: SlideshowArgs, json: bool) -> anyhow::Result<()> {
+    let mut client = WpdmClient::new()?;
+    match args.command {
+        SlideshowCommand::Start(start) => {
+            let dir = start.dir.canonicalize()
+                .with_context(|| format!("Cannot open {}", start.dir.display()))?;
+            if !has_images(&dir, start.recursive)? {
+                return Err(CliError::NoMatch(format!("No images in {}", dir.display())).into());
+            }
+            check_set_args(&start.set_args)?;
+
+            let slideshow = WpdmSlideshow {
+                dir: dir.to_str().context("Failed to get string")?.to_string(),
+                interval_secs: start.interval,
+                recursive: start.recursive,
+                shuffle: start.shuffle,
+                order: match start.order {
+                    Order::Name => SlideshowOrder::Name,
+                    Order::Modified => SlideshowOrder::Modified,
+                },
+                reverse: start.reverse,
+                per_monitor: start.per_monitor,
+                extensions: supported_extensions(),
+                set_args: start.set_args,
+            };
+            client.send(WpdmMessage::SlideshowStart(slideshow))?;
+        },
+        SlideshowCommand::Stop => client.send(WpdmMessage::SlideshowStop)?,
+        SlideshowCommand::Skip => client.send(WpdmMessage::SlideshowSkip)?,
+        SlideshowCommand::Status => {},
+    }
+
+    // Also tells whether the daemon got the message
+    let status = client.get_slideshow()?;
+    if json {
+        return print_json(&status);
+    }
+    print_status(status.as_ref());
+    Ok(())
+}
+
+fn print_status(status: Option<&WpdmSlideshowStatus>) {
+    let Some(status) = status else {
+        println!("No slideshow running");
+        return;
+    };
+    let slideshow = &status.slideshow;
+    let mut details = vec![format!("every {}", format_interval(slideshow.interval_secs))];
+    if slideshow.recursive {
+        details.push("recursive".to_string());
+    }
+    if slideshow.shuffle {
+        details.push("shuffled".to_string());
+    }
+    if slideshow.per_monitor {
+        details.push("an image per monitor".to_string());
+    }
+    println!("Slideshow of {}, {}", slideshow.dir, details.join(", "));
+    if !slideshow.set_args.is_empty() {
+        println!("Set with: {}", slideshow.set_args.join(" "));
+    }
+    if status.images > 0 {
+        println!(
+            "Image {} of {}, next change in {}",
+            status.position, status.images, format_interval(status.next_change_secs)
+        );
+    } else {
+        println!("Starting");
+    }
+    for (monitor, path) in status.current.iter() {
+        println!("{}\t{}", monitor, path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_interval;
+
+    #[test]
+    fn parses_plain_seconds_and_units() {
+        assert_eq!(parse_interval("90"), Ok(90));
+        assert_eq!(parse_interval("10m"), Ok(600));
+        assert_eq!(parse_interval("1h30m"), Ok(5400));
+        assert_eq!(parse_interval("1m30s"), Ok(90));
+    }
+
+    #[test]
+    fn rejects_zero_garbage_and_overflow() {
+        assert!(parse_interval("0").is_err());
+        assert!(parse_interval("0m").is_err());
+        assert!(parse_interval("10x").is_err());
+        assert!(parse_interval("10m5").is_err());
+        assert!(parse_interval("9999999999999999h").is_err());
+        assert!(parse_interval(&format!("{}s1s", u64::MAX)).is_err());
+    }
+}
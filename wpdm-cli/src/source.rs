@@ -0,0 +1,218 @@
+use std::borrow::Cow;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use anyhow::Context;
+use image::{DynamicImage, GrayImage, RgbImage, RgbaImage};
+use sha2::{Digest, Sha256};
+use wpdm_common::settings::LimitSettings;
+
+use crate::limits::check_limits;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PixelFormat {
+    Rgb8,
+    Rgba8,
+    Bgr8,
+    Bgra8,
+    Gray8,
+}
+
+impl PixelFormat {
+    fn channels(self) -> usize {
+        match self {
+            PixelFormat::Rgb8 | PixelFormat::Bgr8 => 3,
+            PixelFormat::Rgba8 | PixelFormat::Bgra8 => 4,
+            PixelFormat::Gray8 => 1,
+        }
+    }
+}
+
+/// Layout of raw pixels, from `--raw WIDTHxHEIGHT:FORMAT`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RawFormat {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+}
+
+pub fn parse_raw(s: &str) -> Result<RawFormat, String> {
+    let (size, format) = s.split_once(':')
+        .ok_or_else(|| format!("Expected WIDTHxHEIGHT:FORMAT, got {}", s))?;
+    let (width, height) = crate::preload::parse_size(size)?;
+    let format = match format {
+        "rgb8" => PixelFormat::Rgb8,
+        "rgba8" => PixelFormat::Rgba8,
+        "bgr8" => PixelFormat::Bgr8,
+        "bgra8" => PixelFormat::Bgra8,
+        "gray8" => PixelFormat::Gray8,
+        _ => return Err(format!("Pixel format must be rgb8, rgba8, bgr8, bgra8 or gray8, got {}", format)),
+    };
+    Ok(RawFormat { width: width as u32, height: height as u32, format })
+}
+
+impl RawFormat {
+    pub fn image(&self, data: &[u8]) -> anyhow::Result<DynamicImage> {
+        let (width, height) = (self.width, self.height);
+        let expected = width as usize * height as usize * self.format.channels();
+        if data.len() != expected {
+            anyhow::bail!(
+                "Expected {} bytes of {:?} pixels for {}x{}, got {}",
+                expected, self.format, width, height, data.len()
+            );
+        }
+        let swap_red_blue = |pixels: &[u8], channels: usize| pixels.chunks_exact(channels)
+            .flat_map(|pixel| {
+                let mut pixel = pixel.to_vec();
+                pixel.swap(0, 2);
+                pixel
+            })
+            .collect::<Vec<_>>();
+        let img = match self.format {
+            PixelFormat::Rgb8 => RgbImage::from_raw(width, height, data.to_vec()).map(DynamicImage::ImageRgb8),
+            PixelFormat::Rgba8 => RgbaImage::from_raw(width, height, data.to_vec()).map(DynamicImage::ImageRgba8),
+            PixelFormat::Bgr8 => RgbImage::from_raw(width, height, swap_red_blue(data, 3)).map(DynamicImage::ImageRgb8),
+            PixelFormat::Bgra8 => RgbaImage::from_raw(width, height, swap_red_blue(data, 4)).map(DynamicImage::ImageRgba8),
+            PixelFormat::Gray8 => GrayImage::from_raw(width, height, data.to_vec()).map(DynamicImage::ImageLuma8),
+        };
+        img.context("Raw pixel buffer has the wrong size")
+    }
+}
+
+/// Where an image comes from
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Source {
+    Path(PathBuf),
+    /// Read from stdin, encoded unless `raw` is given
+    Stdin { data: Arc<[u8]>, raw: Option<RawFormat> },
+}
+
+/// Encoded image, read from the file as the decoder goes. Only stdin is held in memory.
+pub enum SourceReader<'a> {
+    File(BufReader<File>),
+    Stdin(Cursor<&'a [u8]>),
+}
+
+impl Read for SourceReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            SourceReader::File(reader) => reader.read(buf),
+            SourceReader::Stdin(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl BufRead for SourceReader<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self {
+            SourceReader::File(reader) => reader.fill_buf(),
+            SourceReader::Stdin(reader) => reader.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match self {
+            SourceReader::File(reader) => reader.consume(amt),
+            SourceReader::Stdin(reader) => reader.consume(amt),
+        }
+    }
+}
+
+impl Seek for SourceReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            SourceReader::File(reader) => reader.seek(pos),
+            SourceReader::Stdin(reader) => reader.seek(pos),
+        }
+    }
+}
+
+impl From<PathBuf> for Source {
+    fn from(path: PathBuf) -> Self {
+        Source::Path(path)
+    }
+}
+
+impl Source {
+    /// Reads stdin up to the memory limit, so endless input fails instead of filling memory
+    pub fn read_stdin(raw: Option<RawFormat>, limits: &LimitSettings) -> anyhow::Result<Self> {
+        let name = Path::new("<stdin>");
+        if let Some(raw) = raw {
+            check_limits(name, raw.width, raw.height, raw.width as u64 * raw.height as u64 * 4, limits)?;
+        }
+        let max_bytes = limits.max_memory_bytes();
+        let mut data = vec![];
+        std::io::stdin().take(max_bytes + 1).read_to_end(&mut data)
+            .context("Failed to read the image from stdin")?;
+        if data.is_empty() {
+            anyhow::bail!("No image data on stdin");
+        }
+        check_limits(name, 0, 0, data.len() as u64, limits)?;
+        Ok(Source::Stdin { data: data.into(), raw })
+    }
+
+    /// Shown in messages, and used to guess the format from the extension
+    pub fn name(&self) -> &Path {
+        match self {
+            Source::Path(path) => path,
+            Source::Stdin { .. } => Path::new("<stdin>"),
+        }
+    }
+
+    /// Reader for decoders that stream, which covers most formats
+    pub fn reader(&self) -> anyhow::Result<SourceReader<'_>> {
+        match self {
+            Source::Path(path) => {
+                let file = File::open(path)
+                    .with_context(|| format!("Cannot open {}", path.display()))?;
+                Ok(SourceReader::File(BufReader::new(file)))
+            },
+            Source::Stdin { data, .. } => Ok(SourceReader::Stdin(Cursor::new(data))),
+        }
+    }
+
+    /// Every byte of the image, for the decoders that need it all in memory
+    pub fn data(&self) -> anyhow::Result<Cow<'_, [u8]>> {
+        match self {
+            Source::Path(path) => {
+                let data = std::fs::read(path)
+                    .with_context(|| format!("Cannot open {}", path.display()))?;
+                Ok(Cow::Owned(data))
+            },
+            Source::Stdin { data, .. } => Ok(Cow::Borrowed(data)),
+        }
+    }
+
+    pub fn raw(&self) -> Option<RawFormat> {
+        match self {
+            Source::Path(_) => None,
+            Source::Stdin { raw, .. } => *raw,
+        }
+    }
+
+    /// Files are keyed by path, modification time and size, so a file that gets rewritten
+    /// doesn't show its old cache entries. Stdin has no path, so it is keyed by a hash of the
+    /// contents and piping the same image twice still finds the cache entries.
+    pub fn cache_key(&self) -> anyhow::Result<String> {
+        match self {
+            Source::Path(path) => {
+                let meta = path.metadata()
+                    .with_context(|| format!("Cannot open {}", path.display()))?;
+                let modified = meta.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+                let path = path.to_str().context("Failed to get string")?;
+                Ok(format!("{}#mtime={}#size={}", path, modified.as_nanos(), meta.len()))
+            },
+            Source::Stdin { data, raw } => {
+                let mut key = String::from("stdin:");
+                write!(&mut key, "{:x}", Sha256::digest(data))?;
+                if let Some(raw) = raw {
+                    write!(&mut key, "#raw={}x{}:{:?}", raw.width, raw.height, raw.format)?;
+                }
+                Ok(key)
+            },
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::BTreeSet;
+use wpdm_common::WpdmMonitor;
+
+use crate::prepare::{get_crop_params, Crop};
+
+/// Space hidden behind the bezels between two neighbouring monitors, in logical pixels
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bezel {
+    pub horizontal: i32,
+    pub vertical: i32,
+}
+
+pub fn parse_bezel(s: &str) -> Result<Bezel, String> {
+    let (horizontal, vertical) = s.split_once('x').unwrap_or((s, s));
+    let horizontal = horizontal.parse::<i32>().map_err(|e| e.to_string())?;
+    let vertical = vertical.parse::<i32>().map_err(|e| e.to_string())?;
+    if horizontal < 0 || vertical < 0 {
+        return Err(format!("Bezel must not be negative, got {}", s));
+    }
+    Ok(Bezel { horizontal, vertical })
+}
+
+/// Where a monitor sits on the combined canvas, after bezel compensation
+pub struct SpanSlice {
+    pub monitor: String,
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+pub struct SpanLayout {
+    pub width: i32,
+    pub height: i32,
+    pub slices: Vec<SpanSlice>,
+}
+
+impl SpanLayout {
+    pub fn new(monitors: &[WpdmMonitor], bezel: Bezel) -> Self {
+        let min_x = monitors.iter().map(|mon| mon.x).min().unwrap_or(0);
+        let min_y = monitors.iter().map(|mon| mon.y).min().unwrap_or(0);
+        let right_edges = monitors.iter().map(|mon| mon.x + mon.width).collect::<BTreeSet<_>>();
+        let bottom_edges = monitors.iter().map(|mon| mon.y + mon.height).collect::<BTreeSet<_>>();
+
+        // Every monitor edge we cross going right (or down) is a bezel gap the image has to
+        // continue behind
+        let slices = monitors.iter()
+            .map(|mon| {
+                let columns = right_edges.range(..=mon.x).count() as i32;
+                let rows = bottom_edges.range(..=mon.y).count() as i32;
+                SpanSlice {
+                    monitor: mon.name.clone(),
+                    x: mon.x - min_x + columns * bezel.horizontal,
+                    y: mon.y - min_y + rows * bezel.vertical,
+                    width: mon.width,
+                    height: mon.height,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let width = slices.iter().map(|slice| slice.x + slice.width).max().unwrap_or(0);
+        let height = slices.iter().map(|slice| slice.y + slice.height).max().unwrap_or(0);
+
+        Self { width, height, slices }
+    }
+
+    /// Region of the image that ends up on the given slice, when the whole canvas is cropped
+    /// from the centre of the image like a single monitor would be
+    pub fn crop(&self, slice: &SpanSlice, img_width: u32, img_height: u32) -> Crop {
+        let (left, top, width, _) = get_crop_params(self.width as u32, self.height as u32, img_width, img_height);
+        let scale = width as f64 / self.width as f64;
+        (
+            left as f64 + slice.x as f64 * scale,
+            top as f64 + slice.y as f64 * scale,
+            slice.width as f64 * scale,
+            slice.height as f64 * scale,
+        )
+    }
+
+    pub fn cache_key(&self, path: &str, slice: &SpanSlice) -> String {
+        format!("{}#span={},{}:{}x{}", path, slice.x, slice.y, self.width, self.height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wpdm_common::WpdmTransform;
+
+    use super::*;
+
+    fn monitor(name: &str, x: i32, y: i32, width: i32, height: i32) -> WpdmMonitor {
+        WpdmMonitor { name: name.to_string(), width, height, x, y, transform: WpdmTransform::Normal }
+    }
+
+    fn positions(layout: &SpanLayout) -> Vec<(i32, i32)> {
+        layout.slices.iter().map(|slice| (slice.x, slice.y)).collect()
+    }
+
+    #[test]
+    fn side_by_side_monitors_leave_room_for_bezels() {
+        let monitors = [
+            monitor("DP-1", 0, 0, 1920, 1080),
+            monitor("HDMI-A-1", 1920, 0, 1280, 1024),
+            monitor("DP-2", 3200, 0, 1920, 1080),
+        ];
+        let layout = SpanLayout::new(&monitors, Bezel { horizontal: 20, vertical: 0 });
+        assert_eq!(positions(&layout), [(0, 0), (1940, 0), (3240, 0)]);
+        assert_eq!((layout.width, layout.height), (5160, 1080));
+
+        let layout = SpanLayout::new(&monitors, Bezel::default());
+        assert_eq!((layout.width, layout.height), (5120, 1080));
+    }
+
+    #[test]
+    fn stacked_monitors_are_moved_to_the_origin() {
+        let monitors = [monitor("top", -1920, -1080, 1920, 1080), monitor("bottom", -1920, 0, 1920, 1080)];
+        let layout = SpanLayout::new(&monitors, Bezel { horizontal: 0, vertical: 30 });
+        assert_eq!(positions(&layout), [(0, 0), (0, 1110)]);
+        assert_eq!((layout.width, layout.height), (1920, 2190));
+    }
+
+    #[test]
+    fn crop_scales_slices_onto_the_image() {
+        let monitors = [monitor("left", 0, 0, 1920, 1080), monitor("right", 1920, 0, 1920, 1080)];
+        let layout = SpanLayout::new(&monitors, Bezel::default());
+        assert_eq!(layout.crop(&layout.slices[1], 3840, 1080), (1920.0, 0.0, 1920.0, 1080.0));
+        assert_eq!(layout.crop(&layout.slices[1], 7680, 2160), (3840.0, 0.0, 3840.0, 2160.0));
+        // A taller image is cropped from its centre
+        assert_eq!(layout.crop(&layout.slices[0], 3840, 2080), (0.0, 500.0, 1920.0, 1080.0));
+    }
+}
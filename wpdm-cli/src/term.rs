@@ -0,0 +1,349 @@
+//! Just enough terminal handling for `pick`: raw mode, keys, and drawing RGBA images with the
+//! kitty or sixel graphics protocols, or with coloured half blocks.
+
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use base64::Engine;
+use image::RgbaImage;
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Graphics {
+    Kitty,
+    Sixel,
+    /// Two pixels per character cell, works in any terminal with 24-bit colour
+    Text,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum Key {
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Enter,
+    Quit,
+    Other,
+}
+
+/// Puts the terminal in raw mode on the alternate screen until dropped
+pub struct Terminal {
+    saved: libc::termios,
+}
+
+impl Terminal {
+    pub fn enter() -> anyhow::Result<Self> {
+        let stdin = std::io::stdin().as_raw_fd();
+        if unsafe { libc::isatty(stdin) == 0 || libc::isatty(std::io::stdout().as_raw_fd()) == 0 } {
+            anyhow::bail!("Needs a terminal");
+        }
+        let mut saved = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(stdin, &mut saved) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let mut raw = saved;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(stdin, libc::TCSAFLUSH, &raw) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let mut stdout = std::io::stdout();
+        write!(stdout, "\x1b[?1049h\x1b[?25l")?;
+        stdout.flush()?;
+        Ok(Terminal { saved })
+    }
+
+    /// Columns, rows, and the pixel size of a cell. Terminals that don't report pixels are
+    /// taken to have 8x16 cells.
+    pub fn size(&self) -> (u16, u16, u32, u32) {
+        let mut size = unsafe { std::mem::zeroed::<libc::winsize>() };
+        unsafe { libc::ioctl(std::io::stdout().as_raw_fd(), libc::TIOCGWINSZ, &mut size) };
+        let (cols, rows) = (size.ws_col.max(20), size.ws_row.max(5));
+        if size.ws_xpixel == 0 || size.ws_ypixel == 0 {
+            return (cols, rows, 8, 16);
+        }
+        (cols, rows, (size.ws_xpixel / cols).max(1) as u32, (size.ws_ypixel / rows).max(1) as u32)
+    }
+
+    /// Kitty from the environment, which it and terminals copying its protocol set. Sixel
+    /// when the terminal lists it in its reply to a device attributes query.
+    pub fn detect_graphics(&self) -> Graphics {
+        let term = std::env::var("TERM").unwrap_or_default();
+        let program = std::env::var("TERM_PROGRAM").unwrap_or_default();
+        if std::env::var_os("KITTY_WINDOW_ID").is_some()
+            || term.contains("kitty")
+            || term.contains("ghostty")
+            || program == "WezTerm"
+            || program == "ghostty"
+        {
+            return Graphics::Kitty;
+        }
+        match self.device_attributes() {
+            Some(attributes) if attributes.split(';').any(|attribute| attribute == "4") => Graphics::Sixel,
+            _ => Graphics::Text,
+        }
+    }
+
+    /// Reply to `ESC [ c`, without the framing. None when the terminal stays quiet.
+    fn device_attributes(&self) -> Option<String> {
+        let stdin = std::io::stdin().as_raw_fd();
+        let mut polling = self.saved;
+        unsafe { libc::cfmakeraw(&mut polling) };
+        // Gives up after 200 ms without a byte
+        polling.c_cc[libc::VMIN] = 0;
+        polling.c_cc[libc::VTIME] = 2;
+        unsafe { libc::tcsetattr(stdin, libc::TCSANOW, &polling) };
+
+        let mut stdout = std::io::stdout();
+        let _ = write!(stdout, "\x1b[c").and_then(|_| stdout.flush());
+        let mut reply = vec![];
+        let mut byte = [0];
+        while let Ok(1) = std::io::stdin().read(&mut byte) {
+            reply.push(byte[0]);
+            if byte[0] == b'c' {
+                break;
+            }
+        }
+
+        let mut raw = polling;
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        unsafe { libc::tcsetattr(stdin, libc::TCSANOW, &raw) };
+
+        let reply = String::from_utf8_lossy(&reply);
+        let attributes = reply.strip_prefix("\x1b[?")?.strip_suffix('c')?;
+        Some(attributes.to_string())
+    }
+
+    pub fn read_key(&self) -> anyhow::Result<Key> {
+        let mut buffer = [0; 16];
+        let len = std::io::stdin().read(&mut buffer)?;
+        Ok(parse_key(&buffer[..len]))
+    }
+}
+
+/// Key sent as one read in raw mode, escape sequences arrive whole
+fn parse_key(bytes: &[u8]) -> Key {
+    match bytes {
+        b"\x1b[A" | b"\x1bOA" | b"k" => Key::Up,
+        b"\x1b[B" | b"\x1bOB" | b"j" => Key::Down,
+        b"\x1b[5~" => Key::PageUp,
+        b"\x1b[6~" | b" " => Key::PageDown,
+        b"\x1b[H" | b"\x1bOH" | b"\x1b[1~" | b"g" => Key::Home,
+        b"\x1b[F" | b"\x1bOF" | b"\x1b[4~" | b"G" => Key::End,
+        b"\r" | b"\n" => Key::Enter,
+        // Ctrl-C doesn't interrupt in raw mode
+        b"q" | b"\x1b" | b"\x03" => Key::Quit,
+        _ => Key::Other,
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let mut stdout = std::io::stdout();
+        let _ = write!(stdout, "\x1b[?25h\x1b[?1049l");
+        let _ = stdout.flush();
+        unsafe { libc::tcsetattr(std::io::stdin().as_raw_fd(), libc::TCSAFLUSH, &self.saved) };
+    }
+}
+
+/// Clears the screen, and any kitty images on it
+pub fn clear(out: &mut impl Write, graphics: Graphics) -> std::io::Result<()> {
+    if graphics == Graphics::Kitty {
+        write!(out, "\x1b_Ga=d,q=2\x1b\\")?;
+    }
+    write!(out, "\x1b[2J")
+}
+
+/// Moves to a cell, counted from 0
+pub fn move_to(out: &mut impl Write, col: u16, row: u16) -> std::io::Result<()> {
+    write!(out, "\x1b[{};{}H", row + 1, col + 1)
+}
+
+/// Draws the image with its top left corner in the cell. For `Graphics::Text` every cell is
+/// one pixel wide and two high.
+pub fn draw_image(out: &mut impl Write, img: &RgbaImage, col: u16, row: u16, graphics: Graphics) -> std::io::Result<()> {
+    if img.width() == 0 || img.height() == 0 {
+        return Ok(());
+    }
+    move_to(out, col, row)?;
+    match graphics {
+        Graphics::Kitty => draw_kitty(out, img),
+        Graphics::Sixel => draw_sixel(out, img),
+        Graphics::Text => draw_half_blocks(out, img, col, row),
+    }
+}
+
+fn draw_kitty(out: &mut impl Write, img: &RgbaImage) -> std::io::Result<()> {
+    const CHUNK: usize = 4096;
+    let data = base64::engine::general_purpose::STANDARD.encode(img.as_raw());
+    let chunks = data.as_bytes().chunks(CHUNK).collect::<Vec<_>>();
+    for (idx, chunk) in chunks.iter().enumerate() {
+        let more = (idx + 1 < chunks.len()) as u8;
+        if idx == 0 {
+            // C=1 leaves the cursor where it was
+            write!(out, "\x1b_Ga=T,f=32,s={},v={},C=1,q=2,m={};", img.width(), img.height(), more)?;
+        } else {
+            write!(out, "\x1b_Gm={};", more)?;
+        }
+        out.write_all(chunk)?;
+        write!(out, "\x1b\\")?;
+    }
+    Ok(())
+}
+
+/// Colours are snapped to a 6x6x6 cube, plenty for a thumbnail. Transparent pixels are left
+/// unpainted.
+fn draw_sixel(out: &mut impl Write, img: &RgbaImage) -> std::io::Result<()> {
+    let level = |value: u8| (value as u32 * 5 + 127) / 255;
+    let index = |pixel: &image::Rgba<u8>| {
+        let [r, g, b, a] = pixel.0;
+        (a >= 128).then(|| (level(r) * 36 + level(g) * 6 + level(b)) as usize)
+    };
+
+    let (width, height) = img.dimensions();
+    write!(out, "\x1bP0;1;0q\"1;1;{};{}", width, height)?;
+    for idx in 0..216 {
+        let (r, g, b) = (idx / 36, idx / 6 % 6, idx % 6);
+        write!(out, "#{};2;{};{};{}", idx, r * 20, g * 20, b * 20)?;
+    }
+
+    let mut band = vec![None; width as usize * 6];
+    for top in (0..height).step_by(6) {
+        let mut used = [false; 216];
+        for (y, row) in band.chunks_exact_mut(width as usize).enumerate() {
+            for (x, cell) in row.iter_mut().enumerate() {
+                let y = top + y as u32;
+                *cell = if y < height { index(img.get_pixel(x as u32, y)) } else { None };
+                if let Some(color) = cell {
+                    used[*color] = true;
+                }
+            }
+        }
+
+        for color in (0..216).filter(|color| used[*color]) {
+            write!(out, "#{}", color)?;
+            let sixels = (0..width as usize).map(|x| {
+                let bits = (0..6).filter(|y| band[y * width as usize + x] == Some(color)).fold(0, |bits, y| bits | 1 << y);
+                (63 + bits) as u8 as char
+            });
+            write_run_length(out, sixels)?;
+            write!(out, "$")?;
+        }
+        write!(out, "-")?;
+    }
+    write!(out, "\x1b\\")
+}
+
+fn write_run_length(out: &mut impl Write, sixels: impl Iterator<Item = char>) -> std::io::Result<()> {
+    let mut run: Option<(char, usize)> = None;
+    let flush = |out: &mut dyn Write, (sixel, count): (char, usize)| match count {
+        1..=3 => write!(out, "{}", sixel.to_string().repeat(count)),
+        _ => write!(out, "!{}{}", count, sixel),
+    };
+    for sixel in sixels {
+        run = match run {
+            Some((last, count)) if last == sixel => Some((last, count + 1)),
+            Some(last) => {
+                flush(out, last)?;
+                Some((sixel, 1))
+            },
+            None => Some((sixel, 1)),
+        };
+    }
+    if let Some(last) = run {
+        flush(out, last)?;
+    }
+    Ok(())
+}
+
+/// Upper half block in the colour of the top pixel over the colour of the bottom one
+fn draw_half_blocks(out: &mut impl Write, img: &RgbaImage, col: u16, row: u16) -> std::io::Result<()> {
+    let opaque = |x: u32, y: u32| {
+        let pixel = (y < img.height()).then(|| img.get_pixel(x, y).0)?;
+        (pixel[3] >= 128).then_some(pixel)
+    };
+    for (line, y) in (0..img.height()).step_by(2).enumerate() {
+        move_to(out, col, row + line as u16)?;
+        for x in 0..img.width() {
+            match (opaque(x, y), opaque(x, y + 1)) {
+                (Some(top), Some(bottom)) => write!(
+                    out, "\x1b[38;2;{};{};{};48;2;{};{};{}m\u{2580}",
+                    top[0], top[1], top[2], bottom[0], bottom[1], bottom[2],
+                )?,
+                (Some(top), None) => write!(out, "\x1b[49;38;2;{};{};{}m\u{2580}", top[0], top[1], top[2])?,
+                (None, Some(bottom)) => write!(out, "\x1b[49;38;2;{};{};{}m\u{2584}", bottom[0], bottom[1], bottom[2])?,
+                (None, None) => write!(out, "\x1b[0m ")?,
+            }
+        }
+        write!(out, "\x1b[0m")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    fn drawn(draw: impl FnOnce(&mut Vec<u8>) -> std::io::Result<()>) -> String {
+        let mut out = vec![];
+        draw(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn parses_keys_and_escape_sequences() {
+        assert_eq!(parse_key(b"\x1b[A"), Key::Up);
+        assert_eq!(parse_key(b"\x1bOB"), Key::Down);
+        assert_eq!(parse_key(b"\x1b[5~"), Key::PageUp);
+        assert_eq!(parse_key(b" "), Key::PageDown);
+        assert_eq!(parse_key(b"\x1b[1~"), Key::Home);
+        assert_eq!(parse_key(b"G"), Key::End);
+        assert_eq!(parse_key(b"\r"), Key::Enter);
+        assert_eq!(parse_key(b"\x1b"), Key::Quit);
+        assert_eq!(parse_key(b"\x03"), Key::Quit);
+        // Two keys in one read, or an unknown sequence
+        assert_eq!(parse_key(b"jj"), Key::Other);
+        assert_eq!(parse_key(b"\x1b[2~"), Key::Other);
+    }
+
+    #[test]
+    fn run_length_only_pays_off_from_four_sixels() {
+        let encoded = drawn(|out| write_run_length(out, "@@@AAAAB".chars()));
+        assert_eq!(encoded, "@@@!4AB");
+        assert_eq!(drawn(|out| write_run_length(out, std::iter::empty())), "");
+    }
+
+    #[test]
+    fn sixels_use_the_colour_cube() {
+        // Red over a transparent pixel, then a column of blue
+        let mut img = RgbaImage::new(2, 2);
+        img.put_pixel(0, 0, Rgba([255, 0, 0, 255]));
+        img.put_pixel(1, 0, Rgba([0, 0, 255, 255]));
+        img.put_pixel(1, 1, Rgba([0, 0, 250, 255]));
+        let sixel = drawn(|out| draw_sixel(out, &img));
+
+        assert!(sixel.starts_with("\x1bP0;1;0q\"1;1;2;2#0;2;0;0;0#1;2;0;0;20"));
+        assert!(sixel.contains("#180;2;100;0;0#181;2;100;0;20"));
+        let (_, bands) = sixel.rsplit_once("#215;2;100;100;100").unwrap();
+        assert_eq!(bands, "#5?B$#180@?$-\x1b\\");
+    }
+
+    #[test]
+    fn half_blocks_pair_rows_and_leave_transparency() {
+        let mut img = RgbaImage::new(2, 3);
+        img.put_pixel(0, 0, Rgba([255, 0, 0, 255]));
+        img.put_pixel(0, 1, Rgba([0, 0, 255, 255]));
+        img.put_pixel(1, 1, Rgba([0, 255, 0, 255]));
+        img.put_pixel(0, 2, Rgba([9, 9, 9, 255]));
+        let text = drawn(|out| draw_half_blocks(out, &img, 2, 1));
+        assert_eq!(text, concat!(
+            "\x1b[2;3H\x1b[38;2;255;0;0;48;2;0;0;255m\u{2580}\x1b[49;38;2;0;255;0m\u{2584}\x1b[0m",
+            "\x1b[3;3H\x1b[49;38;2;9;9;9m\u{2580}\x1b[0m \x1b[0m",
+        ));
+    }
+}
@@ -0,0 +1,125 @@
+use std::path::PathBuf;
+use anyhow::Context;
+use chrono::{DateTime, Local};
+use wpdm_common::settings::load_settings;
+use wpdm_common::time_of_day::{load_set, WpdmTimeOfDay, WpdmTimeOfDayStatus};
+use wpdm_common::{WpdmClient, WpdmMessage};
+
+use crate::error::print_json;
+use crate::set::check_set_args;
+
+#[derive(clap::Args)]
+pub struct TimeOfDayArgs {
+    #[command(subcommand)]
+    command: TimeOfDayCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum TimeOfDayCommand {
+    /// Change the wallpaper through the day following a set file, replacing any running
+    /// slideshow
+    Start(StartArgs),
+    /// Stop following the set, the current wallpaper stays up
+    Stop,
+    /// Print today's slots and the one on screen
+    Status,
+}
+
+#[derive(clap::Args)]
+struct StartArgs {
+    /// Set file, a toml list of slots:
+    ///
+    /// [[slot]]
+    /// at = "sunset-30m"   # HH:MM, or sunrise, noon, sunset or night with an optional offset
+    /// image = "dusk.jpg"  # relative to the set file
+    ///
+    /// Sun times need latitude and longitude under [location] in config.toml
+    #[arg(verbatim_doc_comment)]
+    set: PathBuf,
+
+    /// Options for every image, as for the set command, e.g. -- --blur 4. Slots fade into
+    /// each other unless --transition is given
+    #[arg(last = true)]
+    set_args: Vec<String>,
+}
+
+pub fn run(args: TimeOfDayArgs, json: bool) -> anyhow::Result<()> {
+    let mut client = WpdmClient::new()?;
+    match args.command {
+        TimeOfDayCommand::Start(start) => {
+            let path = start.set.canonicalize()
+                .with_context(|| format!("Cannot open {}", start.set.display()))?;
+            let set = load_set(&path)?;
+            if let Some(slot) = set.slots.iter().find(|slot| !slot.image.is_file()) {
+                anyhow::bail!("Image {} of slot {} doesn't exist", slot.image.display(), slot.label);
+            }
+            // Fails early when sun times are used without a location
+            set.current(Local::now(), load_settings()?.location.as_ref())?;
+            check_set_args(&start.set_args)?;
+
+            let time_of_day = WpdmTimeOfDay {
+                path: path.to_str().context("Failed to get string")?.to_string(),
+                set_args: start.set_args,
+            };
+            client.send(WpdmMessage::TimeOfDayStart(time_of_day))?;
+        },
+        TimeOfDayCommand::Stop => client.send(WpdmMessage::TimeOfDayStop)?,
+        TimeOfDayCommand::Status => {},
+    }
+
+    // Also tells whether the daemon got the message
+    print_status(client.get_time_of_day()?, json)
+}
+
+#[derive(serde::Serialize)]
+struct ScheduledSlot {
+    start: DateTime<Local>,
+    at: String,
+    image: PathBuf,
+}
+
+#[derive(serde::Serialize)]
+struct Status {
+    #[serde(flatten)]
+    status: WpdmTimeOfDayStatus,
+    /// Today's slots in order, worked out here with the current location
+    schedule: Vec<ScheduledSlot>,
+}
+
+fn print_status(status: Option<WpdmTimeOfDayStatus>, json: bool) -> anyhow::Result<()> {
+    let Some(status) = status else {
+        if json {
+            return print_json(&None::<Status>);
+        }
+        println!("No time-of-day set running");
+        return Ok(());
+    };
+
+    let set = load_set(&PathBuf::from(&status.time_of_day.path))?;
+    let location = load_settings()?.location;
+    let schedule = set.day_schedule(Local::now().date_naive(), &Local, location.as_ref())?;
+    if json {
+        let schedule = schedule.into_iter()
+            .map(|(start, idx)| ScheduledSlot {
+                start,
+                at: set.slots[idx].label.clone(),
+                image: set.slots[idx].image.clone(),
+            })
+            .collect();
+        return print_json(&Some(Status { status, schedule }));
+    }
+
+    println!("Time-of-day set {}", status.time_of_day.path);
+    if !status.time_of_day.set_args.is_empty() {
+        println!("Set with: {}", status.time_of_day.set_args.join(" "));
+    }
+    if let Some(error) = &status.error {
+        println!("Error: {}", error);
+    }
+    for (start, idx) in schedule {
+        let slot = &set.slots[idx];
+        let marker = if status.slot == Some(idx) { "*" } else { " " };
+        println!("{} {}  {:<12} {}", marker, start.format("%H:%M"), slot.label, slot.image.display());
+    }
+    Ok(())
+}
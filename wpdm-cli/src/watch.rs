@@ -0,0 +1,91 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use anyhow::Context;
+use inotify::{EventMask, Events, Inotify, WatchDescriptor, WatchMask};
+
+/// Writes closer together than this are taken as one change
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+struct Watcher {
+    inotify: Inotify,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+    files: BTreeSet<PathBuf>,
+}
+
+impl Watcher {
+    /// Watches the directories rather than the files, since tools often write a new file and
+    /// rename it over the old one, which would end a watch on the file itself
+    fn new(files: &[PathBuf]) -> anyhow::Result<Self> {
+        let mut inotify = Inotify::init().context("Failed to start inotify")?;
+        let mut dirs = HashMap::new();
+        for dir in files.iter().filter_map(|path| path.parent()).collect::<BTreeSet<_>>() {
+            let wd = inotify.add_watch(dir, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)
+                .with_context(|| format!("Cannot watch {}", dir.display()))?;
+            dirs.insert(wd, dir.to_path_buf());
+        }
+        Ok(Watcher { inotify, dirs, files: files.iter().cloned().collect() })
+    }
+
+    fn any_watched(&self, events: Events) -> bool {
+        events
+            .filter(|event| !event.mask.contains(EventMask::ISDIR))
+            .filter_map(|event| Some(self.dirs.get(&event.wd)?.join(event.name?)))
+            .any(|path| self.files.contains(&path))
+    }
+
+    /// Blocks until a watched file changes, then until it has been left alone for `DEBOUNCE`
+    fn wait(&mut self) -> anyhow::Result<()> {
+        let mut buffer = [0; 4096];
+        loop {
+            let events = self.inotify.read_events_blocking(&mut buffer)?;
+            if self.any_watched(events) {
+                break;
+            }
+        }
+
+        let mut last_change = Instant::now();
+        while last_change.elapsed() < DEBOUNCE {
+            std::thread::sleep(DEBOUNCE / 6);
+            match self.inotify.read_events(&mut buffer) {
+                Ok(events) => {
+                    if self.any_watched(events) {
+                        last_change = Instant::now();
+                    }
+                },
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {},
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Calls `apply` every time one of the files is rewritten, until interrupted. `apply` returns
+/// the cache entries it set. The entries of the version before stay, since the daemon may still
+/// be reading them to transition away from, and those of the version before that are removed.
+pub fn watch_files(files: &[PathBuf], mut cache_paths: Vec<PathBuf>, mut apply: impl FnMut() -> anyhow::Result<Vec<PathBuf>>) -> anyhow::Result<()> {
+    let mut watcher = Watcher::new(files)?;
+    let mut previous_paths: Vec<PathBuf> = vec![];
+    loop {
+        watcher.wait()?;
+        match apply() {
+            Ok(new_paths) => {
+                for old in previous_paths.iter().filter(|old| !new_paths.contains(old) && !cache_paths.contains(old)) {
+                    let _ = std::fs::remove_file(old);
+                }
+                previous_paths = std::mem::replace(&mut cache_paths, new_paths);
+            },
+            // Usually a file that is still being written, the next write sets it
+            Err(err) => eprintln!("Failed to set {}: {:#}", display_files(files), err),
+        }
+    }
+}
+
+fn display_files(files: &[PathBuf]) -> String {
+    files.iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,175 @@
+//! Cache entry for animated wallpapers. A still wallpaper is just the BGRA pixels, an animation
+//! starts with this header, followed by every frame at the monitor size:
+//!
+//! ```text
+//! b"WPDMANIM" | width: u32 | height: u32 | frames: u32 | delay of each frame in ms: u32...
+//! ```
+//!
+//! A Ken Burns wallpaper is one source image larger than the monitor, which the daemon pans
+//! and zooms over:
+//!
+//! ```text
+//! b"WPDMKBRN" | width: u32 | height: u32 | source width: u32 | source height: u32
+//!     | duration in ms: u32 | fps: u32 | zoom from: f32 | zoom to: f32
+//!     | pan from x: f32 | pan from y: f32 | pan to x: f32 | pan to y: f32 | source pixels
+//! ```
+//!
+//! Numbers are little endian.
+
+use std::io::{self, Write};
+
+pub const MAGIC: &[u8; 8] = b"WPDMANIM";
+pub const KEN_BURNS_MAGIC: &[u8; 8] = b"WPDMKBRN";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnimHeader {
+    pub width: u32,
+    pub height: u32,
+    pub delays_ms: Vec<u32>,
+}
+
+impl AnimHeader {
+    pub fn frame_len(&self) -> usize {
+        self.width as usize * self.height as usize * 4
+    }
+
+    /// Bytes taken by the header, the first frame starts right after it
+    pub fn len(&self) -> usize {
+        MAGIC.len() + 12 + self.delays_ms.len() * 4
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.delays_ms.is_empty()
+    }
+
+    pub fn write(&self, writer: &mut (impl Write + ?Sized)) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&self.width.to_le_bytes())?;
+        writer.write_all(&self.height.to_le_bytes())?;
+        writer.write_all(&(self.delays_ms.len() as u32).to_le_bytes())?;
+        for delay in self.delays_ms.iter() {
+            writer.write_all(&delay.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Reads the header, returning None when the data isn't an animation or is truncated
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let rest = data.strip_prefix(MAGIC)?;
+        let mut numbers = rest.chunks_exact(4)
+            .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+        let width = numbers.next()?;
+        let height = numbers.next()?;
+        let count = numbers.next()? as usize;
+        let delays_ms = numbers.by_ref().take(count).collect::<Vec<_>>();
+        if delays_ms.len() != count {
+            return None;
+        }
+
+        let header = AnimHeader { width, height, delays_ms };
+        let expected = header.len() + header.frame_len() * count;
+        (data.len() == expected).then_some(header)
+    }
+}
+
+/// Motion of a Ken Burns wallpaper. Zoom 1 shows the whole source, pan positions go from 0 to 1
+/// across the part of the source that is out of view.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KenBurnsHeader {
+    pub width: u32,
+    pub height: u32,
+    pub src_width: u32,
+    pub src_height: u32,
+    pub duration_ms: u32,
+    pub fps: u32,
+    pub zoom: (f32, f32),
+    pub pan: ((f32, f32), (f32, f32)),
+}
+
+impl KenBurnsHeader {
+    pub const LEN: usize = KEN_BURNS_MAGIC.len() + 12 * 4;
+
+    pub fn src_len(&self) -> usize {
+        self.src_width as usize * self.src_height as usize * 4
+    }
+
+    pub fn write(&self, writer: &mut (impl Write + ?Sized)) -> io::Result<()> {
+        let ((from_x, from_y), (to_x, to_y)) = self.pan;
+        writer.write_all(KEN_BURNS_MAGIC)?;
+        for number in [self.width, self.height, self.src_width, self.src_height, self.duration_ms, self.fps] {
+            writer.write_all(&number.to_le_bytes())?;
+        }
+        for number in [self.zoom.0, self.zoom.1, from_x, from_y, to_x, to_y] {
+            writer.write_all(&number.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Reads the header, returning None when the data isn't a Ken Burns wallpaper, is truncated,
+    /// or describes an empty image or motion that isn't a number
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let rest = data.strip_prefix(KEN_BURNS_MAGIC)?;
+        let mut words = rest.chunks_exact(4)
+            .map(|bytes| [bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let mut int = || words.next().map(u32::from_le_bytes);
+        let (width, height) = (int()?, int()?);
+        let (src_width, src_height) = (int()?, int()?);
+        let (duration_ms, fps) = (int()?, int()?);
+        let mut float = || int().map(f32::from_bits);
+        let zoom = (float()?, float()?);
+        let pan = ((float()?, float()?), (float()?, float()?));
+
+        if width == 0 || height == 0 || src_width == 0 || src_height == 0 {
+            return None;
+        }
+        let ((from_x, from_y), (to_x, to_y)) = pan;
+        if ![zoom.0, zoom.1, from_x, from_y, to_x, to_y].iter().all(|number| number.is_finite()) {
+            return None;
+        }
+
+        let src_len = (src_width as usize).checked_mul(src_height as usize)?.checked_mul(4)?;
+        let header = KenBurnsHeader { width, height, src_width, src_height, duration_ms, fps, zoom, pan };
+        (data.len() == Self::LEN.checked_add(src_len)?).then_some(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ken_burns(src_width: u32, src_height: u32) -> KenBurnsHeader {
+        KenBurnsHeader {
+            width: 2,
+            height: 2,
+            src_width,
+            src_height,
+            duration_ms: 1000,
+            fps: 30,
+            zoom: (1.0, 1.5),
+            pan: ((0.0, 0.0), (1.0, 1.0)),
+        }
+    }
+
+    fn encode(header: &KenBurnsHeader, src_len: usize) -> Vec<u8> {
+        let mut data = vec![];
+        header.write(&mut data).unwrap();
+        data.resize(data.len() + src_len, 0);
+        data
+    }
+
+    #[test]
+    fn ken_burns_header_round_trips() {
+        let header = ken_burns(4, 3);
+        assert_eq!(KenBurnsHeader::parse(&encode(&header, 4 * 3 * 4)), Some(header));
+    }
+
+    #[test]
+    fn corrupt_ken_burns_headers_are_rejected() {
+        assert_eq!(KenBurnsHeader::parse(&encode(&ken_burns(0, 3), 0)), None);
+        assert_eq!(KenBurnsHeader::parse(&encode(&ken_burns(4, 0), 0)), None);
+        assert_eq!(KenBurnsHeader::parse(&encode(&ken_burns(4, 3), 4 * 3 * 4 - 1)), None);
+        assert_eq!(KenBurnsHeader::parse(&encode(&ken_burns(u32::MAX, u32::MAX), 0)), None);
+        let header = KenBurnsHeader { zoom: (f32::NAN, 1.0), ..ken_burns(4, 3) };
+        assert_eq!(KenBurnsHeader::parse(&encode(&header, 4 * 3 * 4)), None);
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::{fs::OpenOptions, path::PathBuf};
 use std::io::Write;
 
@@ -12,13 +13,45 @@ pub fn config_dir() -> Option<PathBuf> {
     config_path()?.parent().map(|p| p.to_path_buf())
 }
 
-pub fn save_wp_path(path: &str) -> std::io::Result<()> {
+/// Current wallpaper buffer of each monitor. The file holds one `monitor<TAB>path` line per
+/// monitor. A line without a monitor name is the older single wallpaper format and is kept
+/// under the empty key, which applies to every monitor without its own entry.
+pub fn load_wp_paths() -> std::io::Result<BTreeMap<String, String>> {
+    let Some(conf_path) = config_path() else {
+        return Ok(BTreeMap::new())
+    };
+    if !std::fs::exists(&conf_path)? {
+        return Ok(BTreeMap::new());
+    }
+    let contents = std::fs::read_to_string(conf_path)?;
+    let paths = contents.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| match line.split_once('\t') {
+            Some((monitor, path)) => (monitor.to_string(), path.trim().to_string()),
+            None => (String::new(), line.trim().to_string()),
+        })
+        .collect();
+    Ok(paths)
+}
+
+pub fn get_wp_path(paths: &BTreeMap<String, String>, monitor: &str) -> Option<String> {
+    paths.get(monitor)
+        .or_else(|| paths.get(""))
+        .cloned()
+}
+
+pub fn save_wp_path(monitors: &[String], path: &str) -> std::io::Result<()> {
     let Some(dir) = config_dir() else {
         return Ok(())
     };
     let Some(conf_path) = config_path() else {
         return Ok(())
     };
+    let mut paths = load_wp_paths()?;
+    for monitor in monitors {
+        paths.insert(monitor.clone(), path.to_string());
+    }
+
     std::fs::create_dir_all(dir)?;
     let mut save = OpenOptions::new()
         .write(true)
@@ -26,7 +59,12 @@ pub fn save_wp_path(path: &str) -> std::io::Result<()> {
         .truncate(true)
         .open(conf_path)?;
 
-    writeln!(save, "{}", path)?;
+    for (monitor, path) in paths {
+        if monitor.is_empty() {
+            writeln!(save, "{}", path)?;
+        } else {
+            writeln!(save, "{}\t{}", monitor, path)?;
+        }
+    }
     Ok(())
 }
-
@@ -1,21 +1,58 @@
 pub mod serde_udp;
 pub mod config;
+pub mod settings;
+pub mod anim;
+pub mod slideshow;
+pub mod time_of_day;
 
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 
 use crate::serde_udp::SerdeUdp;
+use crate::slideshow::{WpdmSlideshow, WpdmSlideshowStatus};
+use crate::time_of_day::{WpdmTimeOfDay, WpdmTimeOfDayStatus};
+
+/// A query got no reply in time, the daemon is most likely not running
+#[derive(thiserror::Error, Debug)]
+#[error("wpdm daemon did not respond")]
+pub struct DaemonUnreachable;
+
+/// How the old wallpaper makes way for the new one
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WpdmTransition {
+    #[default]
+    GrowCircle,
+    Fade,
+}
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct WpdmSetWallpaper {
     pub path: String,
-    pub monitors: Vec<String>
+    pub monitors: Vec<String>,
+    pub transition: WpdmTransition,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WpdmTransform {
+    #[default]
+    Normal,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    Flipped,
+    Flipped90,
+    Flipped180,
+    Flipped270,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct WpdmMonitor {
     pub name: String,
     pub height: i32,
-    pub width: i32
+    pub width: i32,
+    /// Logical position of the monitor in the compositor's output layout
+    pub x: i32,
+    pub y: i32,
+    pub transform: WpdmTransform,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
@@ -26,14 +63,25 @@ pub struct WpdmMonitors {
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub enum WpdmMessage {
     SetWallpaper(WpdmSetWallpaper),
+    /// Several wallpapers that have to start transitioning in the same frame
+    SetWallpapers(Vec<WpdmSetWallpaper>),
     QueryMonitor,
-    Monitors(WpdmMonitors)
-}
-
-impl WpdmMessage {
-    pub fn set_wallpaper(path: String, monitors: Vec<String>) -> Self {
-        Self::SetWallpaper(WpdmSetWallpaper { path, monitors })
-    }
+    Monitors(WpdmMonitors),
+    SlideshowStart(WpdmSlideshow),
+    SlideshowStop,
+    /// Moves on to the next image now, the timer starts over
+    SlideshowSkip,
+    SlideshowQuery,
+    /// None when no slideshow is running
+    SlideshowStatus(Option<WpdmSlideshowStatus>),
+    TimeOfDayStart(WpdmTimeOfDay),
+    TimeOfDayStop,
+    TimeOfDayQuery,
+    /// None when no time-of-day set is running
+    TimeOfDayStatus(Option<WpdmTimeOfDayStatus>),
+    Ping,
+    /// Reply to a ping, with the version of the daemon
+    Pong(String),
 }
 
 pub struct WpdmClient {
@@ -45,7 +93,7 @@ pub struct WpdmClient {
 // 2. How do we tell the client about the monitor size?
 // 3. Need bidirectional communication. (Server also needs to be able to send message to client)
 // 4. Means server has to manage connection to clients?
-//      No, server can just use broadcast, will assume that there is only a single client.
+//      No, the server replies to whichever client sent the last message.
 
 impl WpdmClient {
     pub fn new() -> anyhow::Result<Self> {
@@ -53,20 +101,26 @@ impl WpdmClient {
         Ok(Self { stream })
     }
 
-    pub fn set_wallpaper(&mut self, path: String, monitors: Vec<String>) -> anyhow::Result<()> {
-        let message = WpdmMessage::set_wallpaper(path, monitors);
-
-        self.stream.send(message)
+    pub fn set_wallpaper(&mut self, wallpaper: WpdmSetWallpaper) -> anyhow::Result<()> {
+        self.stream.send(WpdmMessage::SetWallpaper(wallpaper))
             .inspect_err(|err| tracing::error!("Failed to send set wallpaper: {}", err))?;
 
         Ok(())
     }
 
+    pub fn set_wallpapers(&mut self, wallpapers: Vec<WpdmSetWallpaper>) -> anyhow::Result<()> {
+        self.stream.send(WpdmMessage::SetWallpapers(wallpapers))
+            .inspect_err(|err| tracing::error!("Failed to send set wallpapers: {}", err))?;
+
+        Ok(())
+    }
+
     pub fn get_monitors(&mut self) -> anyhow::Result<Vec<WpdmMonitor>> {
         self.stream.send(WpdmMessage::QueryMonitor)
             .inspect_err(|err| tracing::error!("Failed to send set wallpaper: {}", err))?;
 
-        let message = self.stream.recv()?;
+        let message = self.stream.recv()
+            .context(DaemonUnreachable)?;
 
         let WpdmMessage::Monitors(WpdmMonitors { monitors }) = message else {
             return Err(anyhow!("Server didn't return correct response"));
@@ -74,6 +128,52 @@ impl WpdmClient {
 
         Ok(monitors)
     }
+
+    pub fn send(&mut self, message: WpdmMessage) -> anyhow::Result<()> {
+        self.stream.send(message)
+            .inspect_err(|err| tracing::error!("Failed to send message: {}", err))?;
+        Ok(())
+    }
+
+    pub fn get_slideshow(&mut self) -> anyhow::Result<Option<WpdmSlideshowStatus>> {
+        self.send(WpdmMessage::SlideshowQuery)?;
+
+        let message = self.stream.recv()
+            .context(DaemonUnreachable)?;
+
+        let WpdmMessage::SlideshowStatus(status) = message else {
+            return Err(anyhow!("Server didn't return correct response"));
+        };
+
+        Ok(status)
+    }
+
+    pub fn get_time_of_day(&mut self) -> anyhow::Result<Option<WpdmTimeOfDayStatus>> {
+        self.send(WpdmMessage::TimeOfDayQuery)?;
+
+        let message = self.stream.recv()
+            .context(DaemonUnreachable)?;
+
+        let WpdmMessage::TimeOfDayStatus(status) = message else {
+            return Err(anyhow!("Server didn't return correct response"));
+        };
+
+        Ok(status)
+    }
+
+    /// Version of the daemon, fails when it doesn't answer
+    pub fn ping(&mut self) -> anyhow::Result<String> {
+        self.send(WpdmMessage::Ping)?;
+
+        let message = self.stream.recv()
+            .context(DaemonUnreachable)?;
+
+        let WpdmMessage::Pong(version) = message else {
+            return Err(anyhow!("Server didn't return correct response"));
+        };
+
+        Ok(version)
+    }
 }
 
 pub struct WpdmListener {
@@ -92,6 +192,21 @@ impl WpdmListener {
         Ok(())
     }
 
+    pub fn slideshow_status(&mut self, status: Option<WpdmSlideshowStatus>) -> anyhow::Result<()> {
+        self.listener.send(WpdmMessage::SlideshowStatus(status))?;
+        Ok(())
+    }
+
+    pub fn time_of_day_status(&mut self, status: Option<WpdmTimeOfDayStatus>) -> anyhow::Result<()> {
+        self.listener.send(WpdmMessage::TimeOfDayStatus(status))?;
+        Ok(())
+    }
+
+    pub fn pong(&mut self, version: String) -> anyhow::Result<()> {
+        self.listener.send(WpdmMessage::Pong(version))?;
+        Ok(())
+    }
+
     pub fn poll(&mut self) -> anyhow::Result<WpdmMessage> {
         Ok(self.listener.recv()?)
     }
@@ -1,13 +1,20 @@
-use std::{marker::PhantomData, net::UdpSocket};
+use std::{marker::PhantomData, net::{SocketAddr, UdpSocket}, time::Duration};
 
 use serde::{de::DeserializeOwned, Serialize};
 
 
 const SERVER_ADDR: &str = "127.0.0.1:50100";
-const CLIENT_ADDR: &str = "127.0.0.1:50101";
+// Clients bind any free port, so several can talk to the daemon at once
+const CLIENT_ADDR: &str = "127.0.0.1:0";
 
-pub struct SerdeUdp<T, const B: usize = 1024> {
+// How long a client waits for a reply before assuming the daemon isn't running
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct SerdeUdp<T, const B: usize = 8192> {
     socket: UdpSocket,
+    /// Where sent messages go: the server until a message arrives, then whoever sent it, so
+    /// the server replies to the client that asked
+    peer: Option<SocketAddr>,
     marker: PhantomData<T>,
     buffer: [u8; B]
 }
@@ -26,44 +33,34 @@ impl<T, const B: usize> SerdeUdp<T, B> where T: Serialize + DeserializeOwned {
     pub fn server() -> std::io::Result<Self> {
         Ok(Self {
             socket: UdpSocket::bind(SERVER_ADDR)?,
+            peer: None,
             marker: PhantomData,
             buffer: [0; B]
         })
     }
 
     pub fn client() -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(CLIENT_ADDR)?;
+        socket.set_read_timeout(Some(CLIENT_TIMEOUT))?;
         Ok(Self {
-            socket: UdpSocket::bind(CLIENT_ADDR)?,
+            socket,
+            peer: SERVER_ADDR.parse().ok(),
             marker: PhantomData,
             buffer: [0; B]
         })
     }
 
-    pub fn find_peers(&self) -> Vec<String> {
-        let Ok(local_sock) = self.socket.local_addr() else {
-            return vec![];
-        };
-
-        let local_sock = local_sock.to_string();
-
-        vec![SERVER_ADDR, CLIENT_ADDR]
-            .into_iter()
-            .filter(|ss| **ss != local_sock)
-            .map(|s| s.to_string())
-            .collect()
-    }
-
     pub fn send(&mut self, data: T) -> Result<(), SerdeUdpErr> {
-        let peers = self.find_peers();
         let buff = postcard::to_slice::<T>(&data, &mut self.buffer)?;
-        for peer in peers {
-            let _ = self.socket.send_to(buff, &peer)?;
+        if let Some(peer) = self.peer {
+            self.socket.send_to(buff, peer)?;
         }
         Ok(())
     }
 
     pub fn recv(&mut self) -> Result<T, SerdeUdpErr> {
-        let (size, _) = self.socket.recv_from(&mut self.buffer)?;
+        let (size, sender) = self.socket.recv_from(&mut self.buffer)?;
+        self.peer = Some(sender);
         let out = postcard::from_bytes::<T>(&self.buffer[..size])?;
         Ok(out)
     }
@@ -0,0 +1,105 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+/// User settings, read from `$XDG_CONFIG_HOME/wpdm/config.toml`. Every section is optional.
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Settings {
+    pub color: ColorSettings,
+    pub limits: LimitSettings,
+    pub location: Option<LocationSettings>,
+}
+
+/// ```toml
+/// [color]
+/// profile = "/usr/share/color/icc/monitor.icc"
+///
+/// [color.monitors]
+/// DP-1 = "/usr/share/color/icc/dp1.icc"
+/// ```
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ColorSettings {
+    /// ICC profile of monitors without their own entry. Wallpapers are prepared for sRGB
+    /// monitors when unset.
+    pub profile: Option<PathBuf>,
+    pub monitors: BTreeMap<String, PathBuf>,
+}
+
+impl ColorSettings {
+    pub fn monitor_profile(&self, monitor: &str) -> Option<&Path> {
+        self.monitors.get(monitor)
+            .or(self.profile.as_ref())
+            .map(|path| path.as_path())
+    }
+}
+
+/// Images over these limits are refused before decoding, so a huge file can't take all the
+/// memory. JPEGs are decoded at a reduced size instead when that brings them within the limits.
+///
+/// ```toml
+/// [limits]
+/// max_width = 32768
+/// max_height = 32768
+/// # MiB of decoded pixels
+/// max_memory = 1024
+/// ```
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LimitSettings {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_memory: u64,
+}
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        LimitSettings {
+            max_width: 32768,
+            max_height: 32768,
+            max_memory: 1024,
+        }
+    }
+}
+
+impl LimitSettings {
+    pub fn max_memory_bytes(&self) -> u64 {
+        self.max_memory.saturating_mul(1024 * 1024)
+    }
+}
+
+/// Where sunrise and sunset are worked out for time-of-day sets, in degrees. North and east
+/// are positive.
+///
+/// ```toml
+/// [location]
+/// latitude = 52.37
+/// longitude = 4.90
+/// ```
+#[derive(serde::Deserialize, Debug, Clone, Copy)]
+pub struct LocationSettings {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+pub fn settings_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| Some(std::env::home_dir()?.join(".config")))?;
+    Some(config_home.join("wpdm/config.toml"))
+}
+
+pub fn load_settings() -> anyhow::Result<Settings> {
+    let Some(path) = settings_path() else {
+        return Ok(Settings::default());
+    };
+    if !std::fs::exists(&path)? {
+        return Ok(Settings::default());
+    }
+    let contents = std::fs::read_to_string(&path)?;
+    toml::from_str(&contents)
+        .with_context(|| format!("Failed to parse {}", path.display()))
+}
@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SlideshowOrder {
+    #[default]
+    Name,
+    Modified,
+}
+
+/// Slideshow settings, sent by `wpdm-cli slideshow start`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct WpdmSlideshow {
+    pub dir: String,
+    pub interval_secs: u64,
+    pub recursive: bool,
+    /// Every image is shown once before any repeats
+    pub shuffle: bool,
+    /// Order without shuffle
+    pub order: SlideshowOrder,
+    pub reverse: bool,
+    /// Each monitor shows the next image in the list instead of all showing the same one
+    pub per_monitor: bool,
+    /// File extensions to pick up, the formats wpdm-cli can decode
+    pub extensions: Vec<String>,
+    /// Passed to `wpdm-cli set` for every image, e.g. effects
+    pub set_args: Vec<String>,
+}
+
+/// Reply to a slideshow status query
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct WpdmSlideshowStatus {
+    pub slideshow: WpdmSlideshow,
+    /// Images shown so far in this pass, out of `images`
+    pub position: usize,
+    pub images: usize,
+    /// Image of each monitor
+    pub current: Vec<(String, String)>,
+    pub next_change_secs: u64,
+}
+
+/// Running slideshow, saved after every change so it carries on after a restart
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct SlideshowState {
+    pub slideshow: WpdmSlideshow,
+    /// Images of the current pass, in the order they are shown
+    pub queue: Vec<PathBuf>,
+    pub position: usize,
+    pub current: BTreeMap<String, PathBuf>,
+    /// Unix time in seconds. Wall clock time, so suspend and restarts don't push it back.
+    pub next_change: u64,
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl SlideshowState {
+    /// Starts with a change straight away
+    pub fn new(slideshow: WpdmSlideshow) -> Self {
+        SlideshowState {
+            slideshow,
+            queue: vec![],
+            position: 0,
+            current: BTreeMap::new(),
+            next_change: unix_now(),
+        }
+    }
+
+    pub fn status(&self) -> WpdmSlideshowStatus {
+        WpdmSlideshowStatus {
+            slideshow: self.slideshow.clone(),
+            position: self.position,
+            images: self.queue.len(),
+            current: self.current.iter()
+                .map(|(monitor, path)| (monitor.clone(), path.display().to_string()))
+                .collect(),
+            next_change_secs: self.next_change.saturating_sub(unix_now()),
+        }
+    }
+
+    /// Picks the images for the next change, one per monitor, and schedules the one after.
+    /// The directory is scanned again at the start of every pass, so new images show up.
+    pub fn advance(&mut self, monitors: &[String]) -> anyhow::Result<BTreeMap<String, PathBuf>> {
+        let wanted = if self.slideshow.per_monitor { monitors.len().max(1) } else { 1 };
+        let mut picks = vec![];
+        while picks.len() < wanted {
+            if self.position >= self.queue.len() {
+                self.new_pass()?;
+            }
+            if self.queue.is_empty() {
+                anyhow::bail!("No images in {}", self.slideshow.dir);
+            }
+            picks.push(self.queue[self.position].clone());
+            self.position += 1;
+        }
+
+        let chosen = monitors.iter()
+            .enumerate()
+            .map(|(idx, monitor)| (monitor.clone(), picks[idx % picks.len()].clone()))
+            .collect::<BTreeMap<_, _>>();
+        self.current = chosen.clone();
+        self.next_change = unix_now().saturating_add(self.slideshow.interval_secs);
+        Ok(chosen)
+    }
+
+    fn new_pass(&mut self) -> anyhow::Result<()> {
+        let mut images = vec![];
+        scan_dir(Path::new(&self.slideshow.dir), &self.slideshow, &mut images)?;
+        match self.slideshow.order {
+            SlideshowOrder::Name => images.sort(),
+            SlideshowOrder::Modified => {
+                let modified = |path: &PathBuf| path.metadata()
+                    .and_then(|meta| meta.modified())
+                    .unwrap_or(UNIX_EPOCH);
+                images.sort_by_cached_key(|path| (modified(path), path.clone()));
+            },
+        }
+        if self.slideshow.reverse {
+            images.reverse();
+        }
+        if self.slideshow.shuffle {
+            fastrand::shuffle(&mut images);
+            // Don't start the new pass with an image that is on screen
+            let shown = |path: &PathBuf| self.current.values().any(|current| current == path);
+            if images.first().is_some_and(shown)
+                && let Some(idx) = images.iter().position(|path| !shown(path))
+            {
+                images.swap(0, idx);
+            }
+        }
+        self.queue = images;
+        self.position = 0;
+        Ok(())
+    }
+}
+
+fn scan_dir(dir: &Path, slideshow: &WpdmSlideshow, images: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() && slideshow.recursive {
+            scan_dir(&path, slideshow, images)?;
+            continue;
+        }
+        let supported = path.extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| slideshow.extensions.iter().any(|known| known.eq_ignore_ascii_case(ext)));
+        if path.is_file() && supported {
+            images.push(path);
+        }
+    }
+    Ok(())
+}
+
+pub fn state_path() -> Option<PathBuf> {
+    Some(config::config_dir()?.join("slideshow.toml"))
+}
+
+pub fn load_state() -> anyhow::Result<Option<SlideshowState>> {
+    let Some(path) = state_path() else {
+        return Ok(None);
+    };
+    if !std::fs::exists(&path)? {
+        return Ok(None);
+    }
+    let contents = std::fs::read_to_string(&path)?;
+    Ok(Some(toml::from_str(&contents)?))
+}
+
+/// Saves the state, or removes the file once the slideshow is stopped
+pub fn save_state(state: Option<&SlideshowState>) -> anyhow::Result<()> {
+    let Some(path) = state_path() else {
+        return Ok(());
+    };
+    match state {
+        Some(state) => {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(&path, toml::to_string(state)?)?;
+        },
+        None => {
+            if std::fs::exists(&path)? {
+                std::fs::remove_file(&path)?;
+            }
+        },
+    }
+    Ok(())
+}
@@ -0,0 +1,386 @@
+//! Wallpaper sets that change with the time of day. A set is a toml file of slots, each
+//! starting at a clock time or at a sun event worked out offline from `[location]`:
+//!
+//! ```toml
+//! [[slot]]
+//! at = "sunrise"
+//! image = "dawn.jpg"
+//!
+//! [[slot]]
+//! at = "12:00"
+//! image = "day.jpg"
+//!
+//! [[slot]]
+//! at = "sunset-30m"
+//! image = "dusk.jpg"
+//!
+//! [[slot]]
+//! at = "night"
+//! image = "night.jpg"
+//! ```
+//!
+//! Images are relative to the set file.
+
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::Context;
+use chrono::{DateTime, Days, NaiveDate, NaiveTime, TimeDelta, TimeZone, Utc};
+
+use crate::config;
+use crate::settings::{settings_path, LocationSettings};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SunEvent {
+    Sunrise,
+    /// Sun at its highest
+    Noon,
+    Sunset,
+    /// End of civil twilight, the sun 6° below the horizon
+    Night,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlotTime {
+    Clock(NaiveTime),
+    /// Sun event, moved by an offset in minutes
+    Sun(SunEvent, i64),
+}
+
+/// Furthest a slot can be moved from its sun event
+const MAX_OFFSET_MINUTES: i64 = 24 * 60;
+
+fn parse_offset(s: &str) -> Option<i64> {
+    let (sign, rest) = match s.chars().next()? {
+        '+' => (1, &s[1..]),
+        '-' => (-1, &s[1..]),
+        _ => return None,
+    };
+    let mut minutes = 0i64;
+    let mut number = String::new();
+    for c in rest.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            'h' => 60,
+            'm' => 1,
+            _ => return None,
+        };
+        let value = std::mem::take(&mut number).parse::<i64>().ok()?;
+        minutes = minutes.checked_add(value.checked_mul(unit)?)?;
+    }
+    (number.is_empty() && !rest.is_empty() && minutes <= MAX_OFFSET_MINUTES).then_some(sign * minutes)
+}
+
+impl FromStr for SlotTime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Expected HH:MM, or sunrise, noon, sunset or night with an optional offset of at most 24h such as -30m, got {}", s);
+        if let Ok(time) = NaiveTime::parse_from_str(s, "%H:%M") {
+            return Ok(SlotTime::Clock(time));
+        }
+        let split = s.find(['+', '-']).unwrap_or(s.len());
+        let (event, offset) = s.split_at(split);
+        let event = match event {
+            "sunrise" => SunEvent::Sunrise,
+            "noon" => SunEvent::Noon,
+            "sunset" => SunEvent::Sunset,
+            "night" => SunEvent::Night,
+            _ => return Err(err()),
+        };
+        let offset = if offset.is_empty() { 0 } else { parse_offset(offset).ok_or_else(err)? };
+        Ok(SlotTime::Sun(event, offset))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Slot {
+    /// As written in the set file
+    pub label: String,
+    pub at: SlotTime,
+    pub image: PathBuf,
+}
+
+#[derive(Clone, Debug)]
+pub struct TimeOfDaySet {
+    pub slots: Vec<Slot>,
+}
+
+#[derive(serde::Deserialize)]
+struct SetFile {
+    slot: Vec<SlotEntry>,
+}
+
+#[derive(serde::Deserialize)]
+struct SlotEntry {
+    at: String,
+    image: PathBuf,
+}
+
+pub fn load_set(path: &Path) -> anyhow::Result<TimeOfDaySet> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Cannot open {}", path.display()))?;
+    let file = toml::from_str::<SetFile>(&contents)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let slots = file.slot.into_iter()
+        .map(|entry| {
+            let at = entry.at.parse::<SlotTime>()
+                .map_err(|err| anyhow::anyhow!("{} in {}", err, path.display()))?;
+            Ok(Slot { label: entry.at, at, image: dir.join(entry.image) })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if slots.is_empty() {
+        anyhow::bail!("{} has no slots", path.display());
+    }
+    Ok(TimeOfDaySet { slots })
+}
+
+/// Unix time of a sun event on the date, by the sunrise equation. None when the sun doesn't
+/// get there that day, like sunset in polar summer.
+pub fn sun_event(event: SunEvent, date: NaiveDate, location: &LocationSettings) -> Option<DateTime<Utc>> {
+    let rad = PI / 180.0;
+    let j2000 = NaiveDate::from_ymd_opt(2000, 1, 1)?;
+    let days = (date - j2000).num_days() as f64 + 0.0008;
+
+    let mean_solar = days - location.longitude / 360.0;
+    let anomaly = (357.5291 + 0.98560028 * mean_solar).rem_euclid(360.0);
+    let center = 1.9148 * (anomaly * rad).sin() + 0.02 * (2.0 * anomaly * rad).sin() + 0.0003 * (3.0 * anomaly * rad).sin();
+    let longitude = (anomaly + center + 180.0 + 102.9372).rem_euclid(360.0);
+    let transit = 2451545.0 + mean_solar + 0.0053 * (anomaly * rad).sin() - 0.0069 * (2.0 * longitude * rad).sin();
+    let declination = ((longitude * rad).sin() * (23.4397 * rad).sin()).asin();
+
+    let julian = match event {
+        SunEvent::Noon => transit,
+        SunEvent::Sunrise | SunEvent::Sunset | SunEvent::Night => {
+            let altitude: f64 = if event == SunEvent::Night { -6.0 } else { -0.833 };
+            let latitude = location.latitude * rad;
+            let cos_hour = ((altitude * rad).sin() - latitude.sin() * declination.sin())
+                / (latitude.cos() * declination.cos());
+            if !(-1.0..=1.0).contains(&cos_hour) {
+                return None;
+            }
+            let hour = cos_hour.acos() / rad / 360.0;
+            if event == SunEvent::Sunrise { transit - hour } else { transit + hour }
+        },
+    };
+    let secs = ((julian - 2440587.5) * 86400.0).round() as i64;
+    DateTime::from_timestamp(secs, 0)
+}
+
+impl TimeOfDaySet {
+    pub fn uses_sun(&self) -> bool {
+        self.slots.iter().any(|slot| matches!(slot.at, SlotTime::Sun(..)))
+    }
+
+    /// Start of each slot on the date in `tz`, sorted. Slots at sun events that don't happen
+    /// that day are left out.
+    pub fn day_schedule<Tz: TimeZone>(&self, date: NaiveDate, tz: &Tz, location: Option<&LocationSettings>) -> anyhow::Result<Vec<(DateTime<Tz>, usize)>> {
+        let mut schedule = vec![];
+        for (idx, slot) in self.slots.iter().enumerate() {
+            let start = match slot.at {
+                SlotTime::Clock(time) => {
+                    let local = date.and_time(time);
+                    // A time skipped by a DST change starts at the end of the gap instead
+                    tz.from_local_datetime(&local).earliest()
+                        .or_else(|| tz.from_local_datetime(&(local + chrono::Duration::hours(1))).earliest())
+                },
+                SlotTime::Sun(event, offset) => {
+                    let location = location.with_context(|| format!(
+                        "Sun times need latitude and longitude under [location] in {}",
+                        settings_path().map(|path| path.display().to_string()).unwrap_or_else(|| "config.toml".to_string())
+                    ))?;
+                    sun_event(event, date, location)
+                        .and_then(|time| time.with_timezone(tz).checked_add_signed(TimeDelta::try_minutes(offset)?))
+                },
+            };
+            if let Some(start) = start {
+                schedule.push((start, idx));
+            }
+        }
+        schedule.sort();
+        Ok(schedule)
+    }
+
+    /// Slot showing at `now`, and when the next slot starts
+    pub fn current<Tz: TimeZone>(&self, now: DateTime<Tz>, location: Option<&LocationSettings>) -> anyhow::Result<(usize, Option<DateTime<Tz>>)> {
+        let today = now.date_naive();
+        let mut schedule = vec![];
+        for date in [today - Days::new(1), today, today + Days::new(1)] {
+            schedule.extend(self.day_schedule(date, &now.timezone(), location)?);
+        }
+        schedule.sort();
+        let slot = schedule.iter()
+            .rev()
+            .find(|(start, _)| *start <= now)
+            .map(|(_, idx)| *idx)
+            // No slot started in the last day, which only happens with sun events in polar
+            // day or night
+            .unwrap_or(0);
+        let next = schedule.into_iter()
+            .find(|(start, _)| *start > now)
+            .map(|(start, _)| start);
+        Ok((slot, next))
+    }
+}
+
+/// Sent by `wpdm-cli time-of-day start`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct WpdmTimeOfDay {
+    pub path: String,
+    /// Passed to `wpdm-cli set` for every image, e.g. effects
+    pub set_args: Vec<String>,
+}
+
+/// Reply to a time-of-day status query
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct WpdmTimeOfDayStatus {
+    pub time_of_day: WpdmTimeOfDay,
+    /// Slot on screen, None until the first one is set
+    pub slot: Option<usize>,
+    /// Set file errors, such as a missing location
+    pub error: Option<String>,
+}
+
+/// Running time-of-day set, saved so it carries on after a restart
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct TimeOfDayState {
+    pub time_of_day: WpdmTimeOfDay,
+    pub slot: Option<usize>,
+}
+
+pub fn state_path() -> Option<PathBuf> {
+    Some(config::config_dir()?.join("time-of-day.toml"))
+}
+
+pub fn load_state() -> anyhow::Result<Option<TimeOfDayState>> {
+    let Some(path) = state_path() else {
+        return Ok(None);
+    };
+    if !std::fs::exists(&path)? {
+        return Ok(None);
+    }
+    let contents = std::fs::read_to_string(&path)?;
+    Ok(Some(toml::from_str(&contents)?))
+}
+
+/// Saves the state, or removes the file once the set is stopped
+pub fn save_state(state: Option<&TimeOfDayState>) -> anyhow::Result<()> {
+    let Some(path) = state_path() else {
+        return Ok(());
+    };
+    match state {
+        Some(state) => {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(&path, toml::to_string(state)?)?;
+        },
+        None => {
+            if std::fs::exists(&path)? {
+                std::fs::remove_file(&path)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono_tz::Europe::Berlin;
+
+    use super::*;
+
+    const BERLIN: LocationSettings = LocationSettings { latitude: 52.52, longitude: 13.405 };
+    const LONDON: LocationSettings = LocationSettings { latitude: 51.5074, longitude: -0.1278 };
+    const TROMSO: LocationSettings = LocationSettings { latitude: 69.6496, longitude: 18.956 };
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    /// Checks the event against a published time, to within two minutes
+    fn assert_sun(event: SunEvent, date: NaiveDate, location: &LocationSettings, expected: (u32, u32)) {
+        let time = sun_event(event, date, location).unwrap();
+        let expected = date.and_hms_opt(expected.0, expected.1, 0).unwrap().and_utc();
+        assert!((time - expected).num_seconds().abs() <= 120, "{:?} at {}, expected {}", event, time, expected);
+    }
+
+    fn set(times: &[&str]) -> TimeOfDaySet {
+        let slots = times.iter()
+            .map(|at| Slot { label: at.to_string(), at: at.parse().unwrap(), image: PathBuf::from(at) })
+            .collect();
+        TimeOfDaySet { slots }
+    }
+
+    #[test]
+    fn sun_events_match_published_times() {
+        assert_sun(SunEvent::Sunrise, date(2024, 6, 21), &BERLIN, (2, 43));
+        assert_sun(SunEvent::Sunset, date(2024, 6, 21), &BERLIN, (19, 33));
+        assert_sun(SunEvent::Sunrise, date(2024, 12, 21), &LONDON, (8, 4));
+        assert_sun(SunEvent::Sunset, date(2024, 12, 21), &LONDON, (15, 53));
+    }
+
+    #[test]
+    fn sun_events_missing_in_polar_day_and_night() {
+        assert_eq!(sun_event(SunEvent::Sunset, date(2024, 6, 21), &TROMSO), None);
+        assert_eq!(sun_event(SunEvent::Sunrise, date(2024, 12, 21), &TROMSO), None);
+        assert!(sun_event(SunEvent::Noon, date(2024, 12, 21), &TROMSO).is_some());
+    }
+
+    #[test]
+    fn parses_clock_times_and_sun_events() {
+        assert_eq!("07:30".parse(), Ok(SlotTime::Clock(NaiveTime::from_hms_opt(7, 30, 0).unwrap())));
+        assert_eq!("noon".parse(), Ok(SlotTime::Sun(SunEvent::Noon, 0)));
+        assert_eq!("sunset-30m".parse(), Ok(SlotTime::Sun(SunEvent::Sunset, -30)));
+        assert_eq!("sunrise+1h30m".parse(), Ok(SlotTime::Sun(SunEvent::Sunrise, 90)));
+        assert_eq!("night+24h".parse(), Ok(SlotTime::Sun(SunEvent::Night, 24 * 60)));
+    }
+
+    #[test]
+    fn rejects_bad_slot_times() {
+        for s in ["25:00", "dusk", "sunset-", "sunset-30", "sunset-30s", "sunset-25h", "sunrise+99999999999999999999h", "sunrise+1h+1h"] {
+            assert!(s.parse::<SlotTime>().is_err(), "{} parsed", s);
+        }
+    }
+
+    #[test]
+    fn current_slot_carries_over_midnight() {
+        let set = set(&["22:00", "06:00"]);
+        let late = Berlin.with_ymd_and_hms(2024, 1, 10, 23, 30, 0).unwrap();
+        let early = Berlin.with_ymd_and_hms(2024, 1, 11, 1, 0, 0).unwrap();
+        let morning = Berlin.with_ymd_and_hms(2024, 1, 11, 6, 0, 0).unwrap();
+        assert_eq!(set.current(late, None).unwrap(), (0, Some(morning)));
+        assert_eq!(set.current(early, None).unwrap(), (0, Some(morning)));
+        assert_eq!(set.current(morning, None).unwrap(), (1, Some(Berlin.with_ymd_and_hms(2024, 1, 11, 22, 0, 0).unwrap())));
+    }
+
+    #[test]
+    fn current_slot_across_dst_changes() {
+        let set = set(&["22:00", "02:30", "12:00"]);
+
+        // 02:30 is skipped when the clocks go forward, the slot starts at 03:30 instead
+        let gap_end = Berlin.with_ymd_and_hms(2024, 3, 31, 3, 30, 0).unwrap();
+        let before = Berlin.with_ymd_and_hms(2024, 3, 31, 3, 15, 0).unwrap();
+        assert_eq!(set.current(before, None).unwrap(), (0, Some(gap_end)));
+        assert_eq!(set.current(gap_end, None).unwrap(), (1, Some(Berlin.with_ymd_and_hms(2024, 3, 31, 12, 0, 0).unwrap())));
+
+        // 02:30 happens twice when the clocks go back, the slot starts at the first
+        let repeated = Berlin.with_ymd_and_hms(2024, 10, 27, 2, 45, 0).latest().unwrap();
+        let noon = Berlin.with_ymd_and_hms(2024, 10, 27, 12, 0, 0).unwrap();
+        assert_eq!(set.current(repeated, None).unwrap(), (1, Some(noon)));
+    }
+
+    #[test]
+    fn sun_slots_need_a_location() {
+        let set = set(&["sunrise", "sunset"]);
+        assert!(set.current(Berlin.with_ymd_and_hms(2024, 6, 21, 12, 0, 0).unwrap(), None).is_err());
+        let (slot, next) = set.current(Berlin.with_ymd_and_hms(2024, 6, 21, 12, 0, 0).unwrap(), Some(&BERLIN)).unwrap();
+        assert_eq!(slot, 0);
+        assert_eq!(next, sun_event(SunEvent::Sunset, date(2024, 6, 21), &BERLIN).map(|time| time.with_timezone(&Berlin)));
+    }
+}
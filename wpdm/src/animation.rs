@@ -0,0 +1,112 @@
+use std::{sync::Arc, time::{Duration, Instant}};
+
+use crate::loader::Wallpaper;
+
+/// GIFs often ask for no delay at all, browsers slow delays up to 10ms down to 10 fps
+const MIN_DELAY: Duration = Duration::from_millis(10);
+const DEFAULT_DELAY: Duration = Duration::from_millis(100);
+
+/// Animated or Ken Burns wallpaper playing on monitors of the same size
+struct Animation {
+    monitors: Vec<String>,
+    /// Frame each monitor has on screen
+    shown: Vec<Option<usize>>,
+    wallpaper: Arc<Wallpaper>,
+    frame: usize,
+    next_frame_at: Instant,
+}
+
+impl Animation {
+    fn delay(&self) -> Duration {
+        let delay = self.wallpaper.delay(self.frame);
+        if delay <= MIN_DELAY { DEFAULT_DELAY } else { delay }
+    }
+}
+
+pub struct AnimationManager {
+    animations: Vec<Animation>,
+}
+
+impl AnimationManager {
+    pub fn new() -> Self {
+        Self { animations: vec![] }
+    }
+
+    /// Plays the wallpaper on the monitors, replacing whatever they were playing
+    pub fn start(&mut self, monitors: Vec<String>, wallpaper: Arc<Wallpaper>) {
+        self.stop(&monitors);
+        let mut animation = Animation {
+            shown: vec![None; monitors.len()],
+            monitors,
+            wallpaper,
+            frame: 0,
+            next_frame_at: Instant::now(),
+        };
+        animation.next_frame_at += animation.delay();
+        self.animations.push(animation);
+    }
+
+    pub fn stop(&mut self, monitors: &[String]) {
+        for animation in self.animations.iter_mut() {
+            while let Some(idx) = animation.monitors.iter().position(|mon| monitors.contains(mon)) {
+                animation.monitors.remove(idx);
+                animation.shown.remove(idx);
+            }
+        }
+        self.animations.retain(|animation| !animation.monitors.is_empty());
+    }
+
+    /// Moves every animation to the frame that should be on screen now. Frames that were due
+    /// while nothing could be drawn are skipped, so playback keeps its speed.
+    pub fn advance(&mut self, now: Instant) {
+        for animation in self.animations.iter_mut() {
+            // After a suspend, start again from now instead of catching up on every frame
+            if now.saturating_duration_since(animation.next_frame_at) > Duration::from_secs(1) {
+                animation.next_frame_at = now;
+            }
+            while animation.next_frame_at <= now {
+                animation.frame = (animation.frame + 1) % animation.wallpaper.frame_count();
+                animation.next_frame_at += animation.delay();
+            }
+        }
+    }
+
+    pub fn next_frame_at(&self) -> Option<Instant> {
+        self.animations.iter()
+            .map(|animation| animation.next_frame_at)
+            .min()
+    }
+
+    /// Whether the monitor is showing an older frame than the current one
+    pub fn has_update(&self, monitor: &str) -> bool {
+        self.animations.iter().any(|animation| {
+            animation.monitors.iter()
+                .position(|mon| mon == monitor)
+                .is_some_and(|idx| animation.shown[idx] != Some(animation.frame))
+        })
+    }
+
+    /// Copy of the frame the monitor has on screen, None when it isn't playing anything
+    pub fn shown_frame(&self, monitor: &str) -> Option<Vec<u8>> {
+        self.animations.iter().find_map(|animation| {
+            let idx = animation.monitors.iter().position(|mon| mon == monitor)?;
+            let frame = animation.shown[idx]?;
+            let mut buffer = vec![0; animation.wallpaper.first_frame().len()];
+            animation.wallpaper.draw_frame(frame, &mut buffer);
+            Some(buffer)
+        })
+    }
+
+    /// Copies the current frame of the monitor's animation, returns false when it has none
+    pub fn render(&mut self, monitor: &str, buffer: &mut [u8]) -> bool {
+        for animation in self.animations.iter_mut() {
+            let Some(idx) = animation.monitors.iter().position(|mon| mon == monitor) else {
+                continue;
+            };
+            animation.wallpaper.draw_frame(animation.frame, buffer);
+            animation.shown[idx] = Some(animation.frame);
+            return true;
+        }
+        false
+    }
+}
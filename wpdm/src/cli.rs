@@ -0,0 +1,51 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Mutex;
+
+use anyhow::Context;
+
+/// Installed next to the daemon, otherwise found on PATH
+fn cli_path() -> PathBuf {
+    std::env::current_exe().ok()
+        .map(|exe| exe.with_file_name("wpdm-cli"))
+        .filter(|path| path.exists())
+        .unwrap_or_else(|| PathBuf::from("wpdm-cli"))
+}
+
+/// Held while `wpdm-cli` runs, so the slideshow, the time-of-day set and restores take turns
+static RUNNING: Mutex<()> = Mutex::new(());
+
+/// Runs `wpdm-cli set`, which prepares the images and hands them back to the daemon like any
+/// other wallpaper. The listener has to keep running meanwhile. Runs are one at a time, and
+/// `wanted` is asked once it is this run's turn, so a set that was stopped while waiting for
+/// another run doesn't change the wallpaper after all.
+pub fn run_set(args: Vec<OsString>, wanted: impl FnOnce() -> bool) -> anyhow::Result<()> {
+    run("set", args, wanted)
+}
+
+/// Prepares the image recorded for `monitor` again once it has changed size, since its cache
+/// entry no longer fits. Runs on its own thread, so the render loop keeps going meanwhile.
+pub fn restore_resized(monitor: String) {
+    std::thread::spawn(move || {
+        let args = vec!["--monitor".into(), monitor.into(), "--prepare".into()];
+        let _ = run("restore", args, || true)
+            .inspect_err(|err| tracing::error!("Failed to restore the resized monitor: {}", err));
+    });
+}
+
+fn run(command: &str, args: Vec<OsString>, wanted: impl FnOnce() -> bool) -> anyhow::Result<()> {
+    let _running = RUNNING.lock().unwrap();
+    if !wanted() {
+        return Ok(());
+    }
+    let output = Command::new(cli_path())
+        .arg(command)
+        .args(args)
+        .output()
+        .context("Failed to run wpdm-cli")?;
+    if !output.status.success() {
+        anyhow::bail!("wpdm-cli {} failed: {}", command, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(())
+}
@@ -99,8 +99,10 @@ impl OutputHandler for WallpaperLayer {
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _output: wl_output::WlOutput,
+        output: wl_output::WlOutput,
     ) {
+        let _ = self.update_monitor_meta(&output)
+            .inspect_err(|err| tracing::error!("Failed to update monitor: {}", err));
     }
 
     fn output_destroyed(
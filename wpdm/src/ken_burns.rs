@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use rayon::{iter::{IndexedParallelIterator, ParallelIterator}, slice::ParallelSliceMut};
+use wpdm_common::anim::KenBurnsHeader;
+
+/// Pans and zooms over a source larger than the monitor. Goes there and back, so looping
+/// never jumps.
+pub struct KenBurns {
+    header: KenBurnsHeader,
+}
+
+impl KenBurns {
+    pub fn new(header: KenBurnsHeader) -> Self {
+        Self { header }
+    }
+
+    fn pass_frames(&self) -> usize {
+        (self.header.duration_ms as usize * self.header.fps as usize / 1000).max(1)
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.pass_frames() * 2
+    }
+
+    pub fn delay(&self) -> Duration {
+        Duration::from_millis(1000 / self.header.fps.max(1) as u64)
+    }
+
+    /// How far along the pass the frame is, eased in and out
+    fn progress(&self, frame: usize) -> f32 {
+        let pass = self.pass_frames();
+        let frame = if frame > pass { 2 * pass - frame } else { frame };
+        let t = frame as f32 / pass as f32;
+        t * t * (3.0 - 2.0 * t)
+    }
+
+    /// Draws nothing when the buffers don't match the header, `KenBurnsHeader::parse` has
+    /// already checked the source
+    pub fn render(&self, frame: usize, source: &[u8], result: &mut [u8]) {
+        let header = &self.header;
+        if source.len() != header.src_len() || result.len() != header.width as usize * header.height as usize * 4 {
+            return;
+        }
+
+        let t = self.progress(frame);
+        let lerp = |from: f32, to: f32| from + (to - from) * t;
+        let zoom = lerp(header.zoom.0, header.zoom.1).max(1.0);
+        let (src_width, src_height) = (header.src_width as f32, header.src_height as f32);
+        let (view_width, view_height) = (src_width / zoom, src_height / zoom);
+        let ((from_x, from_y), (to_x, to_y)) = header.pan;
+        let left = lerp(from_x, to_x).clamp(0.0, 1.0) * (src_width - view_width);
+        let top = lerp(from_y, to_y).clamp(0.0, 1.0) * (src_height - view_height);
+        let scale_x = view_width / header.width as f32;
+        let scale_y = view_height / header.height as f32;
+
+        let stride = header.src_width as usize * 4;
+        let (last_x, last_y) = (header.src_width as usize - 1, header.src_height as usize - 1);
+        result
+            .par_chunks_mut(header.width as usize * 4)
+            .enumerate()
+            .for_each(|(y, row)| {
+                // Bilinear sample around the centre of each output pixel
+                let sy = (top + (y as f32 + 0.5) * scale_y - 0.5).clamp(0.0, last_y as f32);
+                let y0 = sy as usize;
+                let y1 = (y0 + 1).min(last_y);
+                let wy = sy - y0 as f32;
+                for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                    let sx = (left + (x as f32 + 0.5) * scale_x - 0.5).clamp(0.0, last_x as f32);
+                    let x0 = sx as usize;
+                    let x1 = (x0 + 1).min(last_x);
+                    let wx = sx - x0 as f32;
+                    for (c, value) in pixel.iter_mut().enumerate() {
+                        let at = |x: usize, y: usize| source[y * stride + x * 4 + c] as f32;
+                        let upper = at(x0, y0) + (at(x1, y0) - at(x0, y0)) * wx;
+                        let lower = at(x0, y1) + (at(x1, y1) - at(x0, y1)) * wx;
+                        *value = (upper + (lower - upper) * wy).round() as u8;
+                    }
+                }
+            });
+    }
+}
@@ -1,9 +1,15 @@
 extern crate libc;
 
-use std::{collections::BTreeMap, path::PathBuf, sync::{Arc, RwLock}};
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Write},
+    os::{fd::{AsRawFd, BorrowedFd}, unix::net::UnixStream},
+    path::PathBuf,
+    sync::{mpsc, Arc, RwLock},
+    time::{Duration, Instant},
+};
 
 use anyhow::Context;
-use memmap2::Mmap;
 use smithay_client_toolkit::{
     compositor::CompositorState,
     output::OutputState,
@@ -17,6 +23,7 @@ use smithay_client_toolkit::{
     shm::{slot::{Buffer, SlotPool}, Shm},
 };
 use wayland_client::{
+    backend::WaylandError,
     Connection, EventQueue, QueueHandle,
     globals::registry_queue_init,
     protocol::{
@@ -25,15 +32,23 @@ use wayland_client::{
     },
 };
 
-use std::sync::mpsc::Receiver;
+use wpdm_common::{WpdmTransform, WpdmTransition};
 
-use crate::{loader::{load_argb_buffer, mmap_buffer}, transitions::grow_circ::GrowCircleTransition};
+use crate::{
+    animation::AnimationManager,
+    cli,
+    loader::Wallpaper,
+    transitions::TransitionEffect,
+};
 
 #[derive(Clone, Debug)]
 pub struct MonitorMeta {
     pub name: String,
     pub width: i32,
     pub height: i32,
+    pub x: i32,
+    pub y: i32,
+    pub transform: WpdmTransform,
 }
 
 
@@ -44,24 +59,91 @@ pub struct Monitor {
     pub width: i32,
     pub height: i32,
     pub configured: bool,
+    /// A frame callback was requested and the compositor hasn't sent it yet
+    pub frame_pending: bool,
 }
 
 pub struct Transition {
     monitors: Vec<String>,
     frames: Vec<u32>,
-    from_buffer: Mmap,
-    to_buffer: Mmap,
-    transition: GrowCircleTransition
+    from: Arc<Wallpaper>,
+    /// Frame an animated wallpaper had on screen when the transition started, shown instead
+    /// of the first frame of `from`
+    from_shown: Option<Vec<u8>>,
+    to: Arc<Wallpaper>,
+    transition: TransitionEffect
+}
+
+pub struct TransitionRequest {
+    pub monitors: Vec<String>,
+    pub src_argb_buff_path: PathBuf,
+    pub dest_argb_buff_path: PathBuf,
+    pub transition: WpdmTransition,
 }
 
 pub enum RenderCommand {
-    Transition {
-        monitors: Vec<String>,
-        src_argb_buff_path: PathBuf,
-        dest_argb_buff_path: PathBuf
+    Transition(Vec<TransitionRequest>)
+}
+
+/// Sends render commands, and wakes the render loop up to handle them
+pub struct CommandSender {
+    producer: mpsc::SyncSender<RenderCommand>,
+    wake: UnixStream,
+}
+
+impl CommandSender {
+    pub fn send(&self, command: RenderCommand) -> anyhow::Result<()> {
+        self.producer.send(command)?;
+        (&self.wake).write_all(&[1])?;
+        Ok(())
+    }
+}
+
+pub struct CommandReceiver {
+    cons: mpsc::Receiver<RenderCommand>,
+    wake: UnixStream,
+}
+
+impl CommandReceiver {
+    /// Sleeps until `fd` is readable, a command is sent or the timeout passes. Returns whether
+    /// `fd` is readable.
+    fn wait(&self, fd: BorrowedFd, timeout: Option<Duration>) -> io::Result<bool> {
+        let mut fds = [
+            libc::pollfd { fd: fd.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: self.wake.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        ];
+        // Rounded up, waking before the deadline would only mean polling again
+        let timeout = timeout
+            .map(|timeout| timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32)
+            .unwrap_or(-1);
+        let result = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if result < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(err);
+        }
+        Ok(fds[0].revents != 0)
+    }
+
+    fn try_recv(&self) -> Option<RenderCommand> {
+        let mut buf = [0; 64];
+        while (&self.wake).read(&mut buf).is_ok_and(|n| n > 0) {}
+        self.cons.try_recv().ok()
     }
 }
 
+pub fn command_channel() -> io::Result<(CommandSender, CommandReceiver)> {
+    let (prod, cons) = mpsc::sync_channel(1);
+    let (wake_sender, wake_receiver) = UnixStream::pair()?;
+    wake_receiver.set_nonblocking(true)?;
+    Ok((
+        CommandSender { producer: prod, wake: wake_sender },
+        CommandReceiver { cons, wake: wake_receiver },
+    ))
+}
+
 pub struct TransitionManager {
     pub transitions: Vec<Transition>
 }
@@ -81,8 +163,8 @@ impl TransitionManager {
 
         let ret = tr.transition.render(
             *curr_frame, 
-            &tr.from_buffer, 
-            &tr.to_buffer, 
+            tr.from_shown.as_deref().unwrap_or(tr.from.first_frame()),
+            tr.to.first_frame(),
             buffer
         );
         if !ret {
@@ -100,10 +182,26 @@ impl TransitionManager {
         Some(())
     }
 
+    /// Drops the monitors from their transitions, the ones left with no monitors are removed
+    fn stop(&mut self, monitors: &[String]) {
+        for tr in self.transitions.iter_mut() {
+            while let Some(idx) = tr.monitors.iter().position(|mon| monitors.contains(mon)) {
+                tr.monitors.remove(idx);
+                tr.frames.remove(idx);
+            }
+        }
+        self.transitions.retain(|tr| !tr.monitors.is_empty());
+    }
+
     fn has_transitions(&self) -> bool {
         !self.transitions.is_empty()
     }
 
+    fn has_transition(&self, monitor: &str) -> bool {
+        self.transitions.iter()
+            .any(|tr| tr.monitors.iter().any(|mon| mon == monitor))
+    }
+
 }
 
 
@@ -118,14 +216,15 @@ pub struct WallpaperLayer {
     pub pool: SlotPool,
     pub shm: Shm,
 
-    cons: Receiver<RenderCommand>,
+    commands: CommandReceiver,
     monitor_meta: SharedMonitorMeta,
     monitors: Vec<Monitor>,
-    transition_manager: Option<TransitionManager>
+    transition_manager: TransitionManager,
+    animation_manager: AnimationManager,
 }
 
 impl WallpaperLayer {
-    pub fn new(cons: Receiver<RenderCommand>) -> anyhow::Result<Self> {
+    pub fn new(commands: CommandReceiver) -> anyhow::Result<Self> {
         let conn = Connection::connect_to_env()?;
         let (globals, event_queue) = registry_queue_init::<Self>(&conn)?;
         let qh = event_queue.handle();
@@ -148,10 +247,11 @@ impl WallpaperLayer {
             pool,
             shm,
 
-            cons,
+            commands,
             monitor_meta: Arc::new(RwLock::new(vec![])),
             monitors,
-            transition_manager: Some(TransitionManager::new()),
+            transition_manager: TransitionManager::new(),
+            animation_manager: AnimationManager::new(),
         })
     }
 
@@ -174,6 +274,7 @@ impl WallpaperLayer {
             height: monitor_meta.height,
             layer,
             configured: false,
+            frame_pending: false,
         };
         self.monitors.push(monitor);
         let mut mons = self.monitor_meta.write().unwrap();
@@ -181,15 +282,15 @@ impl WallpaperLayer {
         Ok(())
     }
 
+    /// Called when the surface is configured, or when the compositor is ready for the next
+    /// frame. Nothing is drawn unless the monitor has something new to show, so a still
+    /// wallpaper costs nothing once it is up.
     pub fn render(
         &mut self,
         qh: &QueueHandle<Self>,
         surface: &WlSurface,
         configure: bool,
     ) -> anyhow::Result<()> {
-        // 1. Poll for any new frames
-        // 2. Render new frame
-        // tracing::info!("RENDERING FRAME");
         let monitor = self
             .get_monitor(surface, configure)
             .context("Monitor not found")?;
@@ -197,91 +298,130 @@ impl WallpaperLayer {
             return Ok(());
         }
 
-        let mut transition_manager = self.transition_manager.take()
-            .context("Missing transition manager")?;
+        self.set_frame_pending(&monitor.name, false);
+        if self.has_update(&monitor.name) {
+            self.draw(qh, &monitor)?;
+        }
+        Ok(())
+    }
+
+    fn has_update(&self, monitor: &str) -> bool {
+        self.transition_manager.has_transition(monitor) || self.animation_manager.has_update(monitor)
+    }
 
-        let (buffer, canvas) = self.create_buffer(&monitor)?;
-        transition_manager.render_transition(&monitor.name, canvas);
+    fn draw(&mut self, qh: &QueueHandle<Self>, monitor: &Monitor) -> anyhow::Result<()> {
+        let had_transitions = self.transition_manager.has_transitions();
 
-        let has_transitions = transition_manager.has_transitions();
-        self.transition_manager.replace(transition_manager);
-        self.flush_buffer(&buffer, &monitor)?;
-        self.request_render(qh, &monitor);
+        let (buffer, canvas) = Self::create_buffer(&mut self.pool, monitor)?;
+        let drawn = self.transition_manager.render_transition(&monitor.name, canvas).is_some()
+            || self.animation_manager.render(&monitor.name, canvas);
+        if !drawn {
+            return Ok(());
+        }
+        self.flush_buffer(&buffer, monitor)?;
+        self.request_render(qh, monitor);
+        self.set_frame_pending(&monitor.name, true);
 
-        if !has_transitions {
+        if had_transitions && !self.transition_manager.has_transitions() {
             let result = unsafe { libc::malloc_trim(0) };
             if result == 1 {
                 tracing::info!("Memory was released back to the system.");
             } else {
                 tracing::info!("No memory could be released, or the function is not available on this platform.");
             }
-
-            self.wait_for_commands();
         }
+        Ok(())
+    }
 
-
+    /// Draws the monitors that have something new to show and aren't waiting on a frame
+    /// callback. The rest are drawn when their callback arrives.
+    fn draw_idle_monitors(&mut self, qh: &QueueHandle<Self>) -> anyhow::Result<()> {
+        let idle = self.monitors.iter()
+            .filter(|mon| mon.configured && !mon.frame_pending && self.has_update(&mon.name))
+            .cloned()
+            .collect::<Vec<_>>();
+        for monitor in idle {
+            self.draw(qh, &monitor)?;
+        }
         Ok(())
     }
 
-    fn wait_for_commands(&mut self) {
-        let Ok(command) = self.cons.recv() else {
-            return;
-        };
+    fn set_frame_pending(&mut self, monitor: &str, pending: bool) {
+        if let Some(mon) = self.monitors.iter_mut().find(|mon| mon.name == monitor) {
+            mon.frame_pending = pending;
+        }
+    }
 
-        // Possible to cater for more complicated transition types
-        match command {
-            RenderCommand::Transition {
-                monitors,
-                src_argb_buff_path,
-                dest_argb_buff_path
-            } => {
-                let mut map = BTreeMap::<(u32, u32), Vec<String>>::new();
-                for mon in monitors {
-                    let Some((width, height)) = self.get_monitor_size(&mon) else {
-                        continue;
-                    };
-                    if let Some(mons) = map.get_mut(&(width, height)) {
-                        mons.push(mon);
-                    } else {
-                        map.insert((width, height), vec![mon]);
+    fn handle_commands(&mut self) {
+        while let Some(command) = self.commands.try_recv() {
+            // Possible to cater for more complicated transition types
+            match command {
+                RenderCommand::Transition(requests) => {
+                    // Every request is queued before the next frame, so all of them start together
+                    for request in requests {
+                        self.push_transition(request);
                     }
                 }
+            };
+        }
+    }
 
-                // Only expected to loop once, since message from upstream, must be one message,
-                // per monitor size
-                for ((width, height), monitors) in map {
-                    let Ok(from_buffer) = mmap_buffer(src_argb_buff_path.clone()) else {
-                        return;
-                    };
-                    let Ok(to_buffer) = mmap_buffer(dest_argb_buff_path.clone()) else {
-                        return;
-                    };
-                    let expected_buffer_len = (width * height * 4) as usize;
-                    if from_buffer.len() != expected_buffer_len {
-                        tracing::error!("Failed to create transition, since from buffer len is unexpected size: {}", from_buffer.len());
-                        continue;
-                    }
+    fn push_transition(&mut self, request: TransitionRequest) {
+        let TransitionRequest { monitors, src_argb_buff_path, dest_argb_buff_path, transition } = request;
+        let mut map = BTreeMap::<(u32, u32), Vec<String>>::new();
+        for mon in monitors {
+            let Some((width, height)) = self.get_monitor_size(&mon) else {
+                continue;
+            };
+            if let Some(mons) = map.get_mut(&(width, height)) {
+                mons.push(mon);
+            } else {
+                map.insert((width, height), vec![mon]);
+            }
+        }
 
-                    if to_buffer.len() != expected_buffer_len {
-                        tracing::error!("Failed to create transition, since to buffer len is unexpected size: {}", to_buffer.len());
-                        continue;
-                    }
+        // Only expected to loop once, since message from upstream, must be one message,
+        // per monitor size
+        for ((width, height), monitors) in map {
+            let to = match Wallpaper::open(&dest_argb_buff_path, width, height) {
+                Ok(to) => Arc::new(to),
+                Err(err) => {
+                    tracing::error!("Failed to create transition: {}", err);
+                    continue;
+                }
+            };
+            // The old wallpaper was prepared for another size when the monitor was resized,
+            // the new one then shows up without a transition
+            let from = match Wallpaper::open(&src_argb_buff_path, width, height) {
+                Ok(from) => Arc::new(from),
+                Err(err) => {
+                    tracing::warn!("Not transitioning from the old wallpaper: {}", err);
+                    to.clone()
+                }
+            };
 
-                    let tr = Transition {
-                        frames: vec![0; monitors.len()],
-                        monitors,
-                        transition: GrowCircleTransition::new(width, height),
-                        from_buffer,
-                        to_buffer
-                    };
+            let frame_len = (width * height * 4) as usize;
+            let from_shown = monitors.iter()
+                .find_map(|mon| self.animation_manager.shown_frame(mon))
+                .filter(|frame| frame.len() == frame_len);
 
-                    if let Some(trm) = self.transition_manager.as_mut() {
-                        trm.transitions.push(tr);
-                    }
-                }
+            // Animations start straight away and show up once the transition is done
+            if to.is_animated() {
+                self.animation_manager.start(monitors.clone(), to.clone());
+            } else {
+                self.animation_manager.stop(&monitors);
             }
-        };
 
+            let tr = Transition {
+                frames: vec![0; monitors.len()],
+                monitors,
+                transition: TransitionEffect::new(transition, width, height),
+                from,
+                from_shown,
+                to,
+            };
+            self.transition_manager.transitions.push(tr);
+        }
     }
 
     fn get_monitor_size(&self, monitor: &str) -> Option<(u32, u32)> {
@@ -325,8 +465,8 @@ impl WallpaperLayer {
         Ok(())
     }
 
-    fn create_buffer(&mut self, monitor: &Monitor) -> anyhow::Result<(Buffer, &mut [u8])> {
-        let (buffer, canvas) = self.pool.create_buffer(
+    fn create_buffer<'a>(pool: &'a mut SlotPool, monitor: &Monitor) -> anyhow::Result<(Buffer, &'a mut [u8])> {
+        let (buffer, canvas) = pool.create_buffer(
             monitor.width,
             monitor.height,
             monitor.width * 4,
@@ -352,8 +492,45 @@ impl WallpaperLayer {
         let (width, height) = output_info
             .logical_size
             .context("Failed to get monitor width and height")?;
+        let (x, y) = output_info.logical_position.unwrap_or((0, 0));
+        let transform = to_wpdm_transform(output_info.transform);
 
-        Ok(MonitorMeta { name: monitor_name, width, height })
+        Ok(MonitorMeta { name: monitor_name, width, height, x, y, transform })
+    }
+
+    /// Outputs can be moved, rotated or change mode while running, keep the layout we report
+    /// to clients up to date. A new size resizes the layer, stops what was playing at the old
+    /// size and has the CLI prepare the wallpaper again at the new one.
+    pub fn update_monitor_meta(&mut self, output: &wl_output::WlOutput) -> anyhow::Result<()> {
+        let monitor_meta = self.create_monitor_meta(output)?;
+        let monitor = self.monitors.iter_mut()
+            .find(|mon| mon.name == monitor_meta.name)
+            .filter(|mon| (mon.width, mon.height) != (monitor_meta.width, monitor_meta.height));
+        let resized = monitor.is_some();
+        if let Some(monitor) = monitor {
+            monitor.width = monitor_meta.width;
+            monitor.height = monitor_meta.height;
+            monitor.layer.set_size(monitor_meta.width as u32, monitor_meta.height as u32);
+            monitor.layer.commit();
+            let monitors = [monitor_meta.name.clone()];
+            self.transition_manager.stop(&monitors);
+            self.animation_manager.stop(&monitors);
+        }
+        {
+            let mut mons = self.monitor_meta.write().unwrap();
+            if let Some(meta) = mons.iter_mut().find(|meta| meta.name == monitor_meta.name) {
+                meta.x = monitor_meta.x;
+                meta.y = monitor_meta.y;
+                meta.width = monitor_meta.width;
+                meta.height = monitor_meta.height;
+                meta.transform = monitor_meta.transform;
+            }
+        }
+        // The CLI asks the daemon for the monitor sizes, so only once the new one is stored
+        if resized {
+            cli::restore_resized(monitor_meta.name);
+        }
+        Ok(())
     }
 
     fn create_layer_shell(
@@ -383,12 +560,45 @@ impl WallpaperLayer {
             return Ok(());
         };
         tracing::info!("Running Layer");
+        let qh = evt_queue.handle();
 
         evt_queue.roundtrip(self)?;
 
         loop {
-            evt_queue.blocking_dispatch(self)?;
+            evt_queue.dispatch_pending(self)?;
+            evt_queue.flush()?;
+            if let Some(guard) = evt_queue.prepare_read() {
+                // Sleeps until the compositor sends events, a command comes in or the next
+                // animation frame is due. Without animations this blocks until something happens.
+                let timeout = self.animation_manager.next_frame_at()
+                    .map(|at| at.saturating_duration_since(Instant::now()));
+                if self.commands.wait(guard.connection_fd(), timeout)? {
+                    match guard.read() {
+                        Ok(_) => {},
+                        Err(WaylandError::Io(err)) if err.kind() == io::ErrorKind::WouldBlock => {},
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+            }
+            evt_queue.dispatch_pending(self)?;
+
+            self.handle_commands();
+            self.animation_manager.advance(Instant::now());
+            self.draw_idle_monitors(&qh)?;
         }
     }
 
 }
+
+fn to_wpdm_transform(transform: wl_output::Transform) -> WpdmTransform {
+    match transform {
+        wl_output::Transform::_90 => WpdmTransform::Rotate90,
+        wl_output::Transform::_180 => WpdmTransform::Rotate180,
+        wl_output::Transform::_270 => WpdmTransform::Rotate270,
+        wl_output::Transform::Flipped => WpdmTransform::Flipped,
+        wl_output::Transform::Flipped90 => WpdmTransform::Flipped90,
+        wl_output::Transform::Flipped180 => WpdmTransform::Flipped180,
+        wl_output::Transform::Flipped270 => WpdmTransform::Flipped270,
+        _ => WpdmTransform::Normal,
+    }
+}
@@ -1,30 +1,38 @@
-use std::path::Path;
-use std::sync::mpsc;
-use std::{fs::OpenOptions, io::Read, thread::JoinHandle};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
 
 use anyhow::Context;
 use wpdm_common::config::save_wp_path;
-use wpdm_common::{config, WpdmListener, WpdmMonitor, WpdmSetWallpaper};
+use wpdm_common::{config, WpdmListener, WpdmMonitor, WpdmSetWallpaper, WpdmTransition};
 
-use crate::layer::RenderCommand;
+use crate::layer::{CommandSender, RenderCommand, TransitionRequest};
+use crate::slideshow::Slideshow;
+use crate::time_of_day::TimeOfDay;
 use crate::{layer::SharedMonitorMeta};
 
 pub struct WpdmServer {
     // Needs to know dimensions of the buffer to send
     listener: WpdmListener,
-    producer: mpsc::SyncSender<RenderCommand>,
+    producer: CommandSender,
     monitor_meta: SharedMonitorMeta,
+    slideshow: Slideshow,
+    time_of_day: TimeOfDay,
 }
 
 impl WpdmServer {
     pub fn new(
-        producer: mpsc::SyncSender<RenderCommand>,
+        producer: CommandSender,
         monitor_meta: SharedMonitorMeta,
+        slideshow: Slideshow,
+        time_of_day: TimeOfDay,
     ) -> anyhow::Result<Self> {
         Ok(Self {
             listener: WpdmListener::new()?,
             producer,
             monitor_meta,
+            slideshow,
+            time_of_day,
         })
     }
 
@@ -35,45 +43,70 @@ impl WpdmServer {
     }
 
     pub fn handle_change_wallpaper(&mut self, sw: WpdmSetWallpaper) -> anyhow::Result<()> {
+        self.handle_change_wallpapers(vec![sw])
+    }
+
+    pub fn handle_change_wallpapers(&mut self, sws: Vec<WpdmSetWallpaper>) -> anyhow::Result<()> {
         // 1. Generate all frames for wallpaper change
         // 2. Fetch current wallpaper (need wallpaper image loader, since we don't store current
         //    wallpaper in memory)
         // 3. Generate frame transitions between set_wallpaper
-        let src_argb_buff_path = Path::new(&self.get_curr_wp_path()?).to_owned();
-        let dest_argb_buff_path = Path::new(&sw.path).to_owned();
-        let monitors = sw.monitors;
-
-        self.producer.send(RenderCommand::Transition {
-            monitors,
-            src_argb_buff_path,
-            dest_argb_buff_path
-        })
-        .inspect_err(|e| tracing::error!("Failed sending buffer: {}", e))?;
+        let curr_paths = config::load_wp_paths()?;
+        let mut requests = vec![];
+
+        for sw in sws.iter() {
+            let dest_argb_buff_path = Path::new(&sw.path).to_owned();
+
+            // Monitors of different sizes are showing different buffers, so each group of
+            // monitors transitions from its own current buffer
+            let mut by_src = BTreeMap::<PathBuf, Vec<String>>::new();
+            for monitor in sw.monitors.iter() {
+                let src_argb_buff_path = config::get_wp_path(&curr_paths, monitor)
+                    .map(PathBuf::from)
+                    .filter(|path| path.exists())
+                    .unwrap_or_else(|| dest_argb_buff_path.clone());
+                by_src.entry(src_argb_buff_path).or_default().push(monitor.clone());
+            }
+
+            requests.extend(by_src.into_iter()
+                .map(|(src_argb_buff_path, monitors)| TransitionRequest {
+                    monitors,
+                    src_argb_buff_path,
+                    dest_argb_buff_path: dest_argb_buff_path.clone(),
+                    transition: sw.transition,
+                }));
+        }
+
+        self.producer.send(RenderCommand::Transition(requests))
+            .inspect_err(|e| tracing::error!("Failed sending buffer: {}", e))?;
 
         // TODO: Save path needs to run on wpdm-cli
-        save_wp_path(&sw.path)?;
+        for sw in sws {
+            save_wp_path(&sw.monitors, &sw.path)?;
+        }
 
         Ok(())
     }
 
-    pub fn get_curr_wp_path(&self) -> anyhow::Result<String> {
-        let path = config::config_path().context("Failed to get config path")?;
-        let mut data_file = OpenOptions::new().read(true).open(path)?;
-        let mut path = String::new();
-        let _ = data_file.read_to_string(&mut path)?;
-        let path = path.trim().to_string();
-        Ok(path)
-    }
-
     pub fn on_start(&mut self) -> anyhow::Result<()> {
-        let path = self.get_curr_wp_path()?;
+        let curr_paths = config::load_wp_paths()?;
         self.wait_for_monitors();
-        tracing::info!("Finished waiting for monitors, {:?}", &path);
-        let monitors = { 
+        tracing::info!("Finished waiting for monitors, {:?}", &curr_paths);
+        let monitors = {
             let metas = self.monitor_meta.read().unwrap();
-            metas.iter().map(|mm| mm.name.clone()).collect()
+            metas.iter().map(|mm| mm.name.clone()).collect::<Vec<_>>()
         };
-        self.handle_change_wallpaper(WpdmSetWallpaper { path, monitors })?;
+
+        let mut by_path = BTreeMap::<String, Vec<String>>::new();
+        for monitor in monitors {
+            if let Some(path) = config::get_wp_path(&curr_paths, &monitor) {
+                by_path.entry(path).or_default().push(monitor);
+            }
+        }
+        let sws = by_path.into_iter()
+            .map(|(path, monitors)| WpdmSetWallpaper { path, monitors, transition: WpdmTransition::default() })
+            .collect();
+        self.handle_change_wallpapers(sws)?;
         Ok(())
     }
 
@@ -92,17 +125,56 @@ impl WpdmServer {
                         tracing::error!("Error during change wallpaper: {}", err);
                     }
                 },
+                wpdm_common::WpdmMessage::SetWallpapers(set_wallpapers) => {
+                    if let Err(err) = self.handle_change_wallpapers(set_wallpapers) {
+                        tracing::error!("Error during change wallpapers: {}", err);
+                    }
+                },
                 wpdm_common::WpdmMessage::QueryMonitor => {
                     let monitor_metas = self.monitor_meta.read().unwrap();
                     let monitors = monitor_metas.iter()
-                        .map(|mm| WpdmMonitor { name: mm.name.clone(), height: mm.height, width: mm.width })
+                        .map(|mm| WpdmMonitor {
+                            name: mm.name.clone(),
+                            height: mm.height,
+                            width: mm.width,
+                            x: mm.x,
+                            y: mm.y,
+                            transform: mm.transform,
+                        })
                         .collect::<Vec<_>>();
                     let _ = self.listener.monitors(monitors)
                         .inspect_err(|err| tracing::error!("Failed to send monitors: {}", err));
                 },
+                // Only one of them can be in charge of the wallpaper
+                wpdm_common::WpdmMessage::SlideshowStart(slideshow) => {
+                    self.time_of_day.stop();
+                    self.slideshow.start(slideshow);
+                },
+                wpdm_common::WpdmMessage::SlideshowStop => self.slideshow.stop(),
+                wpdm_common::WpdmMessage::SlideshowSkip => self.slideshow.skip(),
+                wpdm_common::WpdmMessage::SlideshowQuery => {
+                    let _ = self.listener.slideshow_status(self.slideshow.status())
+                        .inspect_err(|err| tracing::error!("Failed to send slideshow status: {}", err));
+                },
+                wpdm_common::WpdmMessage::TimeOfDayStart(time_of_day) => {
+                    self.slideshow.stop();
+                    self.time_of_day.start(time_of_day);
+                },
+                wpdm_common::WpdmMessage::TimeOfDayStop => self.time_of_day.stop(),
+                wpdm_common::WpdmMessage::TimeOfDayQuery => {
+                    let _ = self.listener.time_of_day_status(self.time_of_day.status())
+                        .inspect_err(|err| tracing::error!("Failed to send time-of-day status: {}", err));
+                },
+                wpdm_common::WpdmMessage::Ping => {
+                    let _ = self.listener.pong(env!("CARGO_PKG_VERSION").to_string())
+                        .inspect_err(|err| tracing::error!("Failed to send pong: {}", err));
+                },
 
                 // Client side messages
                 wpdm_common::WpdmMessage::Monitors(_) => { }
+                wpdm_common::WpdmMessage::SlideshowStatus(_) => { }
+                wpdm_common::WpdmMessage::TimeOfDayStatus(_) => { }
+                wpdm_common::WpdmMessage::Pong(_) => { }
             };
         }
     }
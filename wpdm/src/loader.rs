@@ -1,13 +1,10 @@
-use std::{fs::{File, OpenOptions}, io::Read, path::PathBuf};
+use std::{fs::OpenOptions, path::{Path, PathBuf}, time::Duration};
 
+use anyhow::Context;
 use memmap2::Mmap;
+use wpdm_common::anim::{AnimHeader, KenBurnsHeader};
 
-pub fn load_argb_buffer(path: PathBuf) -> anyhow::Result<Vec<u8>> {
-    let mut file = File::open(path)?;
-    let mut contents = Vec::new();
-    let _ = file.read_to_end(&mut contents)?;
-    Ok(contents)
-}
+use crate::ken_burns::KenBurns;
 
 pub fn mmap_buffer(path: PathBuf) -> anyhow::Result<memmap2::Mmap> {
     let file = OpenOptions::new()
@@ -18,3 +15,97 @@ pub fn mmap_buffer(path: PathBuf) -> anyhow::Result<memmap2::Mmap> {
 
     Ok(mmap)
 }
+
+enum Content {
+    Still,
+    Frames { start: usize, delays: Vec<Duration> },
+    /// The first frame is rendered up front for transitions
+    KenBurns { start: usize, ken_burns: KenBurns, first: Vec<u8> },
+}
+
+/// Mapped cache entry, a single BGRA buffer, the frames of an animation or the source of a
+/// Ken Burns wallpaper
+pub struct Wallpaper {
+    mmap: Mmap,
+    frame_len: usize,
+    content: Content,
+}
+
+impl Wallpaper {
+    pub fn open(path: &Path, width: u32, height: u32) -> anyhow::Result<Self> {
+        let mmap = mmap_buffer(path.to_path_buf())
+            .with_context(|| format!("Failed to map {}", path.display()))?;
+        let frame_len = (width * height * 4) as usize;
+
+        if mmap.len() == frame_len {
+            return Ok(Self { mmap, frame_len, content: Content::Still });
+        }
+
+        if let Some(header) = KenBurnsHeader::parse(&mmap) {
+            if (header.width, header.height) != (width, height) {
+                anyhow::bail!(
+                    "Ken Burns wallpaper {} is {}x{}, expected {}x{}",
+                    path.display(), header.width, header.height, width, height
+                );
+            }
+            let ken_burns = KenBurns::new(header);
+            let mut first = vec![0; frame_len];
+            ken_burns.render(0, &mmap[KenBurnsHeader::LEN..], &mut first);
+            let content = Content::KenBurns { start: KenBurnsHeader::LEN, ken_burns, first };
+            return Ok(Self { mmap, frame_len, content });
+        }
+
+        let header = AnimHeader::parse(&mmap)
+            .with_context(|| format!("Buffer {} is an unexpected size: {}", path.display(), mmap.len()))?;
+        if (header.width, header.height) != (width, height) || header.is_empty() {
+            anyhow::bail!(
+                "Animation {} is {}x{}, expected {}x{}",
+                path.display(), header.width, header.height, width, height
+            );
+        }
+        let delays = header.delays_ms.iter()
+            .map(|delay| Duration::from_millis(*delay as u64))
+            .collect();
+        let content = Content::Frames { start: header.len(), delays };
+        Ok(Self { mmap, frame_len, content })
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.frame_count() > 1
+    }
+
+    pub fn frame_count(&self) -> usize {
+        match &self.content {
+            Content::Still => 1,
+            Content::Frames { delays, .. } => delays.len(),
+            Content::KenBurns { ken_burns, .. } => ken_burns.frame_count(),
+        }
+    }
+
+    pub fn delay(&self, frame: usize) -> Duration {
+        match &self.content {
+            Content::Still => Duration::ZERO,
+            Content::Frames { delays, .. } => delays.get(frame).copied().unwrap_or_default(),
+            Content::KenBurns { ken_burns, .. } => ken_burns.delay(),
+        }
+    }
+
+    pub fn first_frame(&self) -> &[u8] {
+        match &self.content {
+            Content::Still => &self.mmap,
+            Content::Frames { start, .. } => &self.mmap[*start..*start + self.frame_len],
+            Content::KenBurns { first, .. } => first,
+        }
+    }
+
+    pub fn draw_frame(&self, frame: usize, buffer: &mut [u8]) {
+        match &self.content {
+            Content::Still => buffer.copy_from_slice(&self.mmap),
+            Content::Frames { start, .. } => {
+                let start = start + frame * self.frame_len;
+                buffer.copy_from_slice(&self.mmap[start..start + self.frame_len]);
+            },
+            Content::KenBurns { start, ken_burns, .. } => ken_burns.render(frame, &self.mmap[*start..], buffer),
+        }
+    }
+}
@@ -1,24 +1,34 @@
 //! wpdm - A wallpaper daemon for wayland
 
+mod animation;
+mod cli;
+mod ken_burns;
 mod layer;
 mod listener;
 mod loader;
 mod transitions;
 mod renderer;
+mod slideshow;
+mod time_of_day;
 mod util;
 mod handler;
 
-use std::sync::mpsc;
-
-use crate::{layer::WallpaperLayer, listener::WpdmServer};
+use crate::{
+    layer::{command_channel, WallpaperLayer},
+    listener::WpdmServer,
+    slideshow::Slideshow,
+    time_of_day::TimeOfDay,
+};
 
 fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt().init();
 
-    let (prod, cons) = mpsc::sync_channel(1);
+    let (commands, receiver) = command_channel()?;
 
-    let mut layer = WallpaperLayer::new(cons)?;
-    let server = WpdmServer::new(prod, layer.get_monitor_meta())?;
+    let mut layer = WallpaperLayer::new(receiver)?;
+    let slideshow = Slideshow::spawn(layer.get_monitor_meta());
+    let time_of_day = TimeOfDay::spawn(layer.get_monitor_meta());
+    let server = WpdmServer::new(commands, layer.get_monitor_meta(), slideshow, time_of_day)?;
 
     let handle = server.run();
 
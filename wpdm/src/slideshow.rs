@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use wpdm_common::slideshow::{load_state, save_state, unix_now, SlideshowState, WpdmSlideshow, WpdmSlideshowStatus};
+
+use crate::cli::run_set;
+use crate::layer::SharedMonitorMeta;
+
+/// How soon a failed change is tried again, unless the next change is due sooner anyway
+const RETRY_SECS: u64 = 30;
+
+#[derive(Default)]
+struct Shared {
+    state: Option<SlideshowState>,
+    skip: bool,
+}
+
+/// Cycles through a directory on a timer
+#[derive(Clone)]
+pub struct Slideshow {
+    shared: Arc<(Mutex<Shared>, Condvar)>,
+}
+
+impl Slideshow {
+    /// Carries on with the slideshow saved by the last run, if there was one
+    pub fn spawn(monitor_meta: SharedMonitorMeta) -> Self {
+        let state = load_state()
+            .inspect_err(|err| tracing::error!("Failed to load slideshow: {}", err))
+            .ok()
+            .flatten();
+        let slideshow = Self {
+            shared: Arc::new((Mutex::new(Shared { state, skip: false }), Condvar::new())),
+        };
+        let runner = slideshow.clone();
+        std::thread::spawn(move || runner.run(monitor_meta));
+        slideshow
+    }
+
+    pub fn start(&self, slideshow: WpdmSlideshow) {
+        tracing::info!("Starting slideshow of {}", slideshow.dir);
+        self.update(|shared| shared.state = Some(SlideshowState::new(slideshow)));
+    }
+
+    pub fn stop(&self) {
+        self.update(|shared| shared.state = None);
+    }
+
+    pub fn skip(&self) {
+        self.update(|shared| shared.skip = true);
+    }
+
+    pub fn status(&self) -> Option<WpdmSlideshowStatus> {
+        let (lock, _) = &*self.shared;
+        lock.lock().unwrap().state.as_ref().map(SlideshowState::status)
+    }
+
+    fn update(&self, update: impl FnOnce(&mut Shared)) {
+        let (lock, cvar) = &*self.shared;
+        let mut shared = lock.lock().unwrap();
+        update(&mut shared);
+        if let Err(err) = save_state(shared.state.as_ref()) {
+            tracing::error!("Failed to save slideshow: {}", err);
+        }
+        cvar.notify_one();
+    }
+
+    fn run(&self, monitor_meta: SharedMonitorMeta) {
+        let (lock, cvar) = &*self.shared;
+        loop {
+            let mut shared = lock.lock().unwrap();
+            // Sleeps until the next change is due, or the slideshow is started, stopped or skipped
+            loop {
+                let next_change = shared.state.as_ref().map(|state| state.next_change);
+                match next_change {
+                    None => shared = cvar.wait(shared).unwrap(),
+                    Some(_) if shared.skip => break,
+                    Some(at) => {
+                        let now = unix_now();
+                        if at <= now {
+                            break;
+                        }
+                        shared = cvar.wait_timeout(shared, Duration::from_secs(at - now)).unwrap().0;
+                    },
+                }
+            }
+            shared.skip = false;
+
+            let monitors = monitor_meta.read().unwrap().iter()
+                .map(|meta| meta.name.clone())
+                .collect::<Vec<_>>();
+            if monitors.is_empty() {
+                // Outputs aren't known yet after a restart
+                drop(shared);
+                std::thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+
+            let Some(state) = shared.state.as_mut() else {
+                continue;
+            };
+            let picks = state.advance(&monitors);
+            if picks.is_err() {
+                state.next_change = unix_now().saturating_add(RETRY_SECS.min(state.slideshow.interval_secs));
+            }
+            if let Err(err) = save_state(Some(state)) {
+                tracing::error!("Failed to save slideshow: {}", err);
+            }
+            let slideshow = state.slideshow.clone();
+            let scheduled = state.next_change;
+            drop(shared);
+
+            let wanted = || self.is_scheduled(scheduled);
+            if let Err(err) = picks.and_then(|picks| set_images(&picks, &slideshow, wanted)) {
+                tracing::error!("Slideshow failed to change wallpaper: {}", err);
+                self.retry_soon(scheduled);
+            }
+        }
+    }
+
+    /// Whether the slideshow is still the one that scheduled `scheduled`, rather than started
+    /// again or stopped
+    fn is_scheduled(&self, scheduled: u64) -> bool {
+        let (lock, _) = &*self.shared;
+        lock.lock().unwrap().state.as_ref().is_some_and(|state| state.next_change == scheduled)
+    }
+
+    /// Brings the next change forward after a failed one, unless the slideshow was started or
+    /// stopped meanwhile
+    fn retry_soon(&self, scheduled: u64) {
+        let (lock, _) = &*self.shared;
+        let mut shared = lock.lock().unwrap();
+        let Some(state) = shared.state.as_mut().filter(|state| state.next_change == scheduled) else {
+            return;
+        };
+        state.next_change = scheduled.min(unix_now().saturating_add(RETRY_SECS));
+        if let Err(err) = save_state(Some(state)) {
+            tracing::error!("Failed to save slideshow: {}", err);
+        }
+    }
+}
+
+fn set_images(picks: &BTreeMap<String, PathBuf>, slideshow: &WpdmSlideshow, wanted: impl FnOnce() -> bool) -> anyhow::Result<()> {
+    let mut args = vec![];
+    if slideshow.per_monitor {
+        for (monitor, path) in picks {
+            args.push(OsString::from(format!("{}={}", monitor, path.display())));
+        }
+    } else if let Some(path) = picks.values().next() {
+        args.push(path.into());
+    }
+    args.extend(slideshow.set_args.iter().map(OsString::from));
+    run_set(args, wanted)
+}
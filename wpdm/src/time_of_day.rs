@@ -0,0 +1,153 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use chrono::Local;
+use wpdm_common::settings::load_settings;
+use wpdm_common::time_of_day::{load_set, load_state, save_state, TimeOfDayState, WpdmTimeOfDay, WpdmTimeOfDayStatus};
+
+use crate::cli::run_set;
+use crate::layer::SharedMonitorMeta;
+
+/// The slot is worked out again at least this often. Timers don't count time spent suspended
+/// and don't follow clock changes, so this brings the right slot back within a minute.
+const RECHECK: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct Shared {
+    state: Option<TimeOfDayState>,
+    error: Option<String>,
+    /// Bumped on every start and stop, so a change made while a slot was being set isn't missed
+    generation: u64,
+}
+
+/// Switches between the slots of a time-of-day set, fading from one to the next
+#[derive(Clone)]
+pub struct TimeOfDay {
+    shared: Arc<(Mutex<Shared>, Condvar)>,
+}
+
+impl TimeOfDay {
+    /// Carries on with the set saved by the last run, if there was one
+    pub fn spawn(monitor_meta: SharedMonitorMeta) -> Self {
+        let state = load_state()
+            .inspect_err(|err| tracing::error!("Failed to load time-of-day set: {}", err))
+            .ok()
+            .flatten();
+        let time_of_day = Self {
+            shared: Arc::new((Mutex::new(Shared { state, ..Default::default() }), Condvar::new())),
+        };
+        let runner = time_of_day.clone();
+        std::thread::spawn(move || runner.run(monitor_meta));
+        time_of_day
+    }
+
+    pub fn start(&self, time_of_day: WpdmTimeOfDay) {
+        tracing::info!("Starting time-of-day set {}", time_of_day.path);
+        self.update(Some(TimeOfDayState { time_of_day, slot: None }));
+    }
+
+    pub fn stop(&self) {
+        self.update(None);
+    }
+
+    pub fn status(&self) -> Option<WpdmTimeOfDayStatus> {
+        let (lock, _) = &*self.shared;
+        let shared = lock.lock().unwrap();
+        shared.state.as_ref().map(|state| WpdmTimeOfDayStatus {
+            time_of_day: state.time_of_day.clone(),
+            slot: state.slot,
+            error: shared.error.clone(),
+        })
+    }
+
+    fn update(&self, state: Option<TimeOfDayState>) {
+        let (lock, cvar) = &*self.shared;
+        let mut shared = lock.lock().unwrap();
+        shared.state = state;
+        shared.error = None;
+        shared.generation += 1;
+        if let Err(err) = save_state(shared.state.as_ref()) {
+            tracing::error!("Failed to save time-of-day set: {}", err);
+        }
+        cvar.notify_one();
+    }
+
+    fn run(&self, monitor_meta: SharedMonitorMeta) {
+        let (lock, cvar) = &*self.shared;
+        loop {
+            let mut shared = lock.lock().unwrap();
+            while shared.state.is_none() {
+                shared = cvar.wait(shared).unwrap();
+            }
+            let generation = shared.generation;
+            let state = shared.state.clone().unwrap();
+            drop(shared);
+
+            let wait = if monitor_meta.read().unwrap().is_empty() {
+                // Outputs aren't known yet after a restart
+                Duration::from_secs(1)
+            } else {
+                let result = self.show_current(&state, generation);
+                let mut shared = lock.lock().unwrap();
+                if shared.generation == generation {
+                    shared.error = result.as_ref().err().map(|err| err.to_string());
+                }
+                result.unwrap_or_else(|err| {
+                    tracing::error!("Time-of-day set failed: {}", err);
+                    RECHECK
+                })
+            };
+
+            let shared = lock.lock().unwrap();
+            let _ = cvar.wait_timeout_while(shared, wait, |shared| shared.generation == generation);
+        }
+    }
+
+    fn is_generation(&self, generation: u64) -> bool {
+        let (lock, _) = &*self.shared;
+        lock.lock().unwrap().generation == generation
+    }
+
+    /// Sets the slot that should be on screen if it isn't already, and returns how long until
+    /// the next check. The set file and location are read every time, so edits apply without
+    /// starting the set again.
+    fn show_current(&self, state: &TimeOfDayState, generation: u64) -> anyhow::Result<Duration> {
+        let set = load_set(Path::new(&state.time_of_day.path))?;
+        let settings = load_settings()?;
+        let now = Local::now();
+        let (slot, next) = set.current(now, settings.location.as_ref())?;
+
+        if state.slot != Some(slot) {
+            tracing::info!("Time-of-day slot {} ({})", slot, set.slots[slot].label);
+            set_slot(&set.slots[slot].image, &state.time_of_day.set_args, || self.is_generation(generation))?;
+
+            let (lock, _) = &*self.shared;
+            let mut shared = lock.lock().unwrap();
+            if shared.generation == generation
+                && let Some(state) = shared.state.as_mut()
+            {
+                state.slot = Some(slot);
+                save_state(Some(state))?;
+            }
+        }
+
+        // A second late, so the next slot has surely started
+        let until_next = next
+            .and_then(|next| (next - now).to_std().ok())
+            .map(|until| until + Duration::from_secs(1))
+            .unwrap_or(RECHECK);
+        Ok(until_next.min(RECHECK))
+    }
+}
+
+/// Fades to the slot, unless the set was started with its own `--transition`
+fn set_slot(image: &Path, set_args: &[String], wanted: impl FnOnce() -> bool) -> anyhow::Result<()> {
+    let mut args = vec![PathBuf::from(image).into_os_string()];
+    if !set_args.iter().any(|arg| arg == "--transition" || arg.starts_with("--transition=")) {
+        args.extend(["--transition".into(), "fade".into()]);
+    }
+    args.extend(set_args.iter().map(OsString::from));
+    run_set(args, wanted)
+}
@@ -0,0 +1,44 @@
+use rayon::{iter::{IndexedParallelIterator, ParallelIterator}, slice::ParallelSliceMut};
+
+use crate::util::argb_buffer_size;
+
+pub struct FadeTransition {
+    n_frames: u32,
+    width: u32,
+    height: u32,
+}
+
+impl FadeTransition {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self::new_with_frames(width, height, 60)
+    }
+
+    pub fn new_with_frames(width: u32, height: u32, n_frames: u32) -> Self {
+        FadeTransition { n_frames, width, height }
+    }
+
+    pub fn render(&self, frame: u32, from: &[u8], to: &[u8], result: &mut [u8]) -> bool {
+        if frame > self.n_frames {
+            return true;
+        }
+        assert_eq!(from.len(), argb_buffer_size(self.width, self.height) as usize);
+        assert_eq!(to.len(), argb_buffer_size(self.width, self.height) as usize);
+        assert_eq!(result.len(), argb_buffer_size(self.width, self.height) as usize);
+
+        // Fixed point weight of the new wallpaper, out of 256
+        let weight = (frame * 256 / self.n_frames) as u16;
+        let row_len = self.width as usize * 4;
+        result
+            .par_chunks_mut(row_len)
+            .enumerate()
+            .for_each(|(y, row)| {
+                let start = y * row_len;
+                let from = &from[start..start + row_len];
+                let to = &to[start..start + row_len];
+                for ((out, from), to) in row.iter_mut().zip(from).zip(to) {
+                    *out = ((*from as u16 * (256 - weight) + *to as u16 * weight) >> 8) as u8;
+                }
+            });
+        false
+    }
+}
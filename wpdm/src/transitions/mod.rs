@@ -1 +1,29 @@
+pub mod fade;
 pub mod grow_circ;
+
+use wpdm_common::WpdmTransition;
+
+use self::{fade::FadeTransition, grow_circ::GrowCircleTransition};
+
+/// Transition picked by the client for a wallpaper change
+pub enum TransitionEffect {
+    GrowCircle(GrowCircleTransition),
+    Fade(FadeTransition),
+}
+
+impl TransitionEffect {
+    pub fn new(transition: WpdmTransition, width: u32, height: u32) -> Self {
+        match transition {
+            WpdmTransition::GrowCircle => TransitionEffect::GrowCircle(GrowCircleTransition::new(width, height)),
+            WpdmTransition::Fade => TransitionEffect::Fade(FadeTransition::new(width, height)),
+        }
+    }
+
+    /// Draws the frame into `result`, returns true once the transition is over
+    pub fn render(&self, frame: u32, from: &[u8], to: &[u8], result: &mut [u8]) -> bool {
+        match self {
+            TransitionEffect::GrowCircle(transition) => transition.render(frame, from, to, result),
+            TransitionEffect::Fade(transition) => transition.render(frame, from, to, result),
+        }
+    }
+}